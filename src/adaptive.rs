@@ -44,12 +44,16 @@ pub fn process_subdivision(
                     .chunks
                     .get(&cpu_chunk)
                     .unwrap()
-                    .get_node_mask(tnipt.pointer as usize);
+                    .get_node_mask(tnipt.pointer as usize, world.mip_filter);
                 octree.subdivide(node_index, mask, voxel_depth + 1);
             } else if tnipt.pointer > CHUNK_OFFSET {
                 let chunk_id = tnipt.pointer - CHUNK_OFFSET;
                 if world.chunks.contains_key(&chunk_id) {
-                    let mask = world.chunks.get(&chunk_id).unwrap().get_node_mask(0);
+                    let mask = world
+                        .chunks
+                        .get(&chunk_id)
+                        .unwrap()
+                        .get_node_mask(0, world.mip_filter);
                     octree.subdivide(node_index, mask, voxel_depth + 1);
                 } else {
                     println!("Loading chunk {}", chunk_id);
@@ -99,21 +103,15 @@ pub fn process_unsubdivision(
             let (cpu_chunk, cpu_index, _, _) = world.find_voxel(pos, Some(voxel_depth));
 
             let tnipt = world.chunks.get(&cpu_chunk).unwrap().nodes[cpu_index];
-            let value = if tnipt.pointer < CHUNK_OFFSET {
-                tnipt.value
-            } else if tnipt.pointer > CHUNK_OFFSET {
+            if tnipt.pointer > CHUNK_OFFSET {
                 let chunk = tnipt.pointer - CHUNK_OFFSET;
                 if chunk >= CHUNK_OFFSET / 2 {
                     println!("Destroyed chunk {}", chunk);
                     world.chunks.remove(&chunk);
                 }
+            }
 
-                tnipt.value
-            } else {
-                tnipt.value
-            };
-
-            octree.nodes[node_index] = value.to_value();
+            octree.nodes[node_index] = tnipt.to_value(world.mip_filter);
 
             result[i] = 0;
         }
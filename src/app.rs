@@ -1,6 +1,11 @@
 use super::*;
+use std::path::PathBuf;
 use winit::window::Window;
 
+/// The ray for what's being looked at is cast every frame, so it gives up long before
+/// `MAX_RAY_STEPS`
+const UI_RAY_STEPS: u32 = 1000;
+
 pub struct App {
     pub octree: Octree,
     pub world: World,
@@ -8,7 +13,7 @@ pub struct App {
     pub gpu: Gpu,
     pub render: Render,
     pub compute: Compute,
-    pub procedural: Procedural,
+    pub gen_job: Option<GenJob>,
     pub input: Input,
     pub character: Character,
     pub settings: Settings,
@@ -16,24 +21,32 @@ pub struct App {
 }
 
 impl App {
-    pub async fn new(window: &Window) -> Self {
+    /// Opens `path` or the default model if there isn't one
+    pub async fn new(window: &Window, path: Option<PathBuf>, octree_depth: u32) -> Self {
         let input = Input::new();
         let character = Character::new();
 
         let settings = Settings {
-            octree_depth: 12,
+            octree_depth,
+            hollow: false,
             fov: 90.0,
             sensitivity: 0.00005,
         };
 
         let gpu = Gpu::new(window).await;
-        let procedural = Procedural::new(&gpu);
 
-        // let world = World::load_world("worlds/defualt").unwrap();
-        let mut world = World::new(String::new());
-        let chunk = CpuOctree::load_file("files/statuette.rsvo".to_string(), 10).unwrap();
-        world.chunks.insert(0, chunk);
-        world.generate_mip_tree(0);
+        let path = path.unwrap_or_else(|| PathBuf::from("files/defualt.vox"));
+        let mut ui = Ui::default();
+        let world = match World::open(&path, octree_depth) {
+            Ok(world) => world,
+            Err(e) => {
+                ui.error_string = format!("{}: {}", path.display(), e);
+                let mut world = World::new(String::new());
+                world.chunks.insert(0, CpuOctree::new(0));
+                world.generate_mip_tree(0).unwrap();
+                world
+            }
+        };
 
         let gen_settings = GenSettings::default();
         // let cpu_octree = generate_world(&gen_settings, &blocks).unwrap();
@@ -44,7 +57,11 @@ impl App {
         // };
 
         // let octree = Octree::new([Voxel::new(255, 255, 255); 8]);
-        let mask = world.chunks.get(&0).unwrap().get_node_mask(0);
+        let mask = world
+            .chunks
+            .get(&0)
+            .unwrap()
+            .get_node_mask(0, world.mip_filter);
         let octree = Octree::new(mask);
 
         let render = Render::new(&gpu, window, &octree).await;
@@ -57,11 +74,11 @@ impl App {
             gpu,
             render,
             compute,
-            procedural,
+            gen_job: None,
             input,
             character,
             settings,
-            ui: Default::default(),
+            ui,
         };
 
         app
@@ -129,6 +146,34 @@ impl App {
             0.0
         };
 
+        if let Some(result) = self.gen_job.as_mut().and_then(GenJob::poll) {
+            let path = self.gen_job.take().unwrap().path;
+            match result.and_then(|_| World::load_world(path)) {
+                Ok(world) => {
+                    self.world = world;
+
+                    // Reset octree
+                    let mask = self
+                        .world
+                        .chunks
+                        .get(&0)
+                        .unwrap()
+                        .get_node_mask(0, self.world.mip_filter);
+                    self.octree = Octree::new(mask);
+
+                    let nodes = self.octree.raw_data();
+                    self.gpu.queue.write_buffer(
+                        &self.render.node_buffer,
+                        0,
+                        bytemuck::cast_slice(&nodes),
+                    );
+
+                    self.ui.error_string = "".to_string();
+                }
+                Err(e) => self.ui.error_string = e,
+            }
+        }
+
         let hole_percentage =
             100.0 * (8.0 * self.octree.hole_stack.len() as f32) / self.octree.nodes.len() as f32;
 
@@ -142,6 +187,10 @@ impl App {
                             let path = native_dialog::FileDialog::new()
                                 .add_filter("Magica Voxel RSVO File", &["rsvo"])
                                 .add_filter("Magica Voxel Vox File", &["vox"])
+                                .add_filter("Mesh", &["obj", "stl"])
+                                .add_filter("Point Cloud", &["ply", "xyz"])
+                                .add_filter("Minecraft", &["schem", "schematic", "mca"])
+                                .add_filter("NRRD Volume", &["nrrd", "nhdr"])
                                 .show_open_single_file()
                                 .unwrap();
 
@@ -150,14 +199,24 @@ impl App {
                                     path.into_os_string().into_string().unwrap(),
                                     self.settings.octree_depth,
                                 ) {
-                                    Ok(chunk) => {
+                                    Ok(mut chunk) => {
+                                        if self.settings.hollow {
+                                            let (before, after) = chunk.hollow(&|_| false);
+                                            println!("Hollowed from {} to {} nodes", before, after);
+                                        }
+
                                         self.world.chunks.remove(&0);
                                         self.world.chunks.insert(0, chunk);
-                                        self.world.generate_mip_tree(0);
+                                        // A file can reference chunks that aren't there
+                                        let mips = self.world.generate_mip_tree(0);
 
                                         // Reset octree
-                                        let mask =
-                                            self.world.chunks.get(&0).unwrap().get_node_mask(0);
+                                        let mask = self
+                                            .world
+                                            .chunks
+                                            .get(&0)
+                                            .unwrap()
+                                            .get_node_mask(0, self.world.mip_filter);
                                         self.octree = Octree::new(mask);
 
                                         let nodes = self.octree.raw_data();
@@ -167,7 +226,7 @@ impl App {
                                             bytemuck::cast_slice(&nodes),
                                         );
 
-                                        self.ui.error_string = "".to_string();
+                                        self.ui.error_string = mips.err().unwrap_or_default();
                                     }
                                     Err(e) => {
                                         self.ui.error_string = e;
@@ -188,7 +247,12 @@ impl App {
                                     self.world = World::load_world(path.parent().unwrap()).unwrap();
 
                                     // Reset octree
-                                    let mask = self.world.chunks.get(&0).unwrap().get_node_mask(0);
+                                    let mask = self
+                                        .world
+                                        .chunks
+                                        .get(&0)
+                                        .unwrap()
+                                        .get_node_mask(0, self.world.mip_filter);
                                     self.octree = Octree::new(mask);
 
                                     let nodes = self.octree.raw_data();
@@ -218,7 +282,11 @@ impl App {
                         //     }
                         // }
 
-                        if ui.button("Regenerate").clicked() {
+                        let generating = self.gen_job.is_some();
+                        if ui
+                            .add_enabled(!generating, egui::Button::new("Regenerate"))
+                            .clicked()
+                        {
                             let path = native_dialog::FileDialog::new()
                                 .show_save_single_file()
                                 .unwrap();
@@ -227,29 +295,56 @@ impl App {
 
                             match path {
                                 Some(path) => {
-                                    World::generate_world(&path, &mut self.procedural, &self.gpu)
-                                        .unwrap();
-
-                                    self.world = World::load_world(path).unwrap();
-
-                                    // Reset octree
-                                    let mask = self.world.chunks.get(&0).unwrap().get_node_mask(0);
-                                    self.octree = Octree::new(mask);
-
-                                    let nodes = self.octree.raw_data();
-                                    self.gpu.queue.write_buffer(
-                                        &self.render.node_buffer,
-                                        0,
-                                        bytemuck::cast_slice(&nodes),
-                                    );
-
+                                    self.gen_job =
+                                        Some(GenJob::start(path, self.gen_settings.clone()));
                                     self.ui.error_string = "".to_string();
                                 }
                                 None => self.ui.error_string = "No file selected".to_string(),
                             }
                         }
+
+                        if ui
+                            .add_enabled(!generating, egui::Button::new("Resume"))
+                            .clicked()
+                        {
+                            let path = native_dialog::FileDialog::new()
+                                .show_open_single_dir()
+                                .unwrap();
+
+                            match path {
+                                Some(path) => match Manifest::load(&path) {
+                                    Ok(manifest) => {
+                                        // Resuming needs the settings the world was started with
+                                        self.gen_settings = manifest.gen_settings;
+                                        self.gen_job =
+                                            Some(GenJob::start(path, self.gen_settings.clone()));
+                                        self.ui.error_string = "".to_string();
+                                    }
+                                    Err(e) => self.ui.error_string = e,
+                                },
+                                None => self.ui.error_string = "No folder selected".to_string(),
+                            }
+                        }
                     });
 
+                    if let Some(job) = &self.gen_job {
+                        let (done, total) = job.progress.get();
+                        let eta = match job.progress.eta() {
+                            Some(eta) => format!("{}s left", eta.as_secs()),
+                            None => "estimating".to_string(),
+                        };
+
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::ProgressBar::new(done as f32 / total.max(1) as f32)
+                                    .text(format!("{}/{} chunks, {}", done, total, eta)),
+                            );
+                            if ui.button("Cancel").clicked() {
+                                job.progress.cancel();
+                            }
+                        });
+                    }
+
                     if self.ui.error_string != "" {
                         ui.colored_label(egui::Color32::RED, &self.ui.error_string);
                     }
@@ -258,6 +353,35 @@ impl App {
                         egui::Slider::new(&mut self.settings.octree_depth, 0..=20)
                             .text("Octree depth"),
                     );
+                    ui.checkbox(&mut self.settings.hollow, "Hollow opened files");
+                    if self.world.path.is_empty() {
+                        let mut filter = self.world.mip_filter;
+                        mip_filter_ui(ui, "LOD filter", &mut filter);
+                        if filter != self.world.mip_filter {
+                            let mips = self.world.set_mip_filter(filter);
+
+                            // Reset octree
+                            let mask = self
+                                .world
+                                .chunks
+                                .get(&0)
+                                .unwrap()
+                                .get_node_mask(0, self.world.mip_filter);
+                            self.octree = Octree::new(mask);
+
+                            let nodes = self.octree.raw_data();
+                            self.gpu.queue.write_buffer(
+                                &self.render.node_buffer,
+                                0,
+                                bytemuck::cast_slice(nodes),
+                            );
+
+                            self.ui.error_string = mips.err().unwrap_or_default();
+                        }
+                    } else {
+                        // The chunk files were built with the filter in the manifest
+                        ui.label(format!("LOD filter: {}", self.world.mip_filter.name()));
+                    }
 
                     ui.horizontal(|ui| {
                         ui.add(
@@ -288,11 +412,17 @@ impl App {
                     );
                     ui.checkbox(&mut self.render.uniforms.misc_bool, "Misc");
 
-                    ui.label(format!(
-                        "Nodes: {:.2} million ({:.0}% holes)",
-                        self.octree.nodes.len() as f32 / 1000000.0,
-                        hole_percentage,
-                    ));
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "Nodes: {:.2} million ({:.0}% holes)",
+                            self.octree.nodes.len() as f32 / 1000000.0,
+                            hole_percentage,
+                        ));
+                        if ui.button("Print stats").clicked() {
+                            println!("Gpu octree:\n{}", self.octree.stats());
+                            println!("Loaded chunks:\n{}", self.world.stats());
+                        }
+                    });
                 });
 
             egui::CollapsingHeader::new("Character")
@@ -308,11 +438,131 @@ impl App {
                             .prefix("Sensitivity")
                             .logarithmic(true),
                     );
+
+                    let hit = self.world.raycast_steps(
+                        self.character.pos.to_vec(),
+                        self.character.look,
+                        4.0,
+                        None,
+                        UI_RAY_STEPS,
+                    );
+                    ui.label(match hit {
+                        Ok(Some(hit)) => format!(
+                            "Looking at: chunk {}, depth {}, colour {:?}, {:.4} away",
+                            hit.chunk, hit.depth, hit.node.value, hit.dist
+                        ),
+                        Ok(None) => "Looking at: nothing".to_string(),
+                        Err(e) => format!("Looking at: {}", e),
+                    });
+                });
+
+            egui::CollapsingHeader::new("World gen")
+                .default_open(false)
+                .show(ui, |ui| {
+                    let gen_settings = &mut self.gen_settings;
+                    ui.add(egui::DragValue::new(&mut gen_settings.seed).prefix("Seed: "));
+                    ui.add(
+                        egui::Slider::new(&mut gen_settings.world_depth, 1..=6).text("World depth"),
+                    );
+                    ui.add(
+                        egui::Slider::new(&mut gen_settings.chunk_depth, 4..=9).text("Chunk depth"),
+                    );
+                    ui.add(
+                        egui::Slider::new(&mut gen_settings.sdf_slope, 1.0..=8.0).text("Sdf slope"),
+                    );
+                    ui.checkbox(&mut gen_settings.hollow, "Hollow");
+                    mip_filter_ui(ui, "Generated LOD filter", &mut gen_settings.mip_filter);
+                    ui.add(
+                        egui::Slider::new(&mut gen_settings.biome_scale, 0.1..=10.0)
+                            .text("Biome scale")
+                            .logarithmic(true),
+                    );
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut gen_settings.deep_block)
+                                .clamp_range(1..=8)
+                                .prefix("Deep block: "),
+                        );
+                        ui.add(
+                            egui::DragValue::new(&mut gen_settings.deep_depth)
+                                .speed(1.0)
+                                .clamp_range(0.0..=1000.0)
+                                .prefix("Deep depth: "),
+                        );
+                    });
+
+                    for biome in &mut gen_settings.biomes {
+                        ui.label(&biome.name);
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::DragValue::new(&mut biome.surface)
+                                    .clamp_range(1..=8)
+                                    .prefix("Surface: "),
+                            );
+                            ui.add(
+                                egui::DragValue::new(&mut biome.subsurface)
+                                    .clamp_range(1..=8)
+                                    .prefix("Subsurface: "),
+                            );
+                            ui.add(
+                                egui::DragValue::new(&mut biome.subsurface_depth)
+                                    .clamp_range(0..=16)
+                                    .prefix("Depth: "),
+                            );
+                            ui.add(
+                                egui::DragValue::new(&mut biome.base)
+                                    .clamp_range(1..=8)
+                                    .prefix("Base: "),
+                            );
+                        });
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut gen_settings.cave_floor_block)
+                                .clamp_range(1..=8)
+                                .prefix("Cave floor: "),
+                        );
+                        ui.add(
+                            egui::DragValue::new(&mut gen_settings.cave_ceiling_block)
+                                .clamp_range(1..=8)
+                                .prefix("Cave ceiling: "),
+                        );
+                    });
+
+                    for cave in &mut gen_settings.caves {
+                        ui.label(cave.name());
+                        ui.horizontal(|ui| match cave {
+                            Cave::Worms { scale, radius } => {
+                                ui.add(egui::DragValue::new(scale).speed(0.05).prefix("Scale: "));
+                                ui.add(
+                                    egui::DragValue::new(radius).speed(0.005).prefix("Radius: "),
+                                );
+                            }
+                            Cave::Caverns { scale, threshold } => {
+                                ui.add(egui::DragValue::new(scale).speed(0.05).prefix("Scale: "));
+                                ui.add(
+                                    egui::DragValue::new(threshold)
+                                        .speed(0.01)
+                                        .prefix("Threshold: "),
+                                );
+                            }
+                            Cave::Ravines {
+                                scale,
+                                width,
+                                floor,
+                            } => {
+                                ui.add(egui::DragValue::new(scale).speed(0.05).prefix("Scale: "));
+                                ui.add(egui::DragValue::new(width).speed(0.005).prefix("Width: "));
+                                ui.add(egui::DragValue::new(floor).speed(0.01).prefix("Floor: "));
+                            }
+                        });
+                    }
                 });
 
             // fn update_world_gen(app: &mut App) {
             //     app.world = World::generate_world(&app.procedural, &app.gpu, &app.blocks);;\
-            //     app.octree = Octree::new(app.world.get_node_mask(0));
+            //     app.octree = Octree::new(app.world.get_node_mask(0, self.world.mip_filter));
             // }
 
             // egui::CollapsingHeader::new("World gen")
@@ -418,3 +668,20 @@ impl App {
 struct Ui {
     error_string: String,
 }
+
+fn mip_filter_ui(ui: &mut egui::Ui, label: &str, filter: &mut MipFilter) {
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_label(label)
+            .selected_text(filter.name())
+            .show_ui(ui, |ui| {
+                ui.selectable_value(filter, MipFilter::Mean, "mean");
+                ui.selectable_value(filter, MipFilter::Majority, "majority");
+                ui.selectable_value(filter, MipFilter::Luminance, "luminance");
+                ui.selectable_value(filter, MipFilter::Threshold(128), "threshold");
+            });
+
+        if let MipFilter::Threshold(threshold) = filter {
+            ui.add(egui::DragValue::new(threshold).prefix("Min coverage: "));
+        }
+    });
+}
@@ -0,0 +1,658 @@
+use super::*;
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Opens the viewer if left out
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Opens a .vox, .rsvo, .obj, .stl, .ply, .xyz, Minecraft, .nrrd or chunk .bin file or a world
+    /// folder in the viewer
+    View {
+        path: Option<PathBuf>,
+        /// Levels of .rsvo files to load
+        #[arg(long, default_value_t = 12)]
+        depth: u32,
+    },
+    /// Prints node and leaf counts per level, memory use, colours and chunk references of a
+    /// file or world folder
+    Info {
+        path: PathBuf,
+        #[arg(long, default_value_t = 12)]
+        depth: u32,
+    },
+    /// Converts between .vox, .rsvo and chunk .bin files, picked by the extensions. Meshes and
+    /// point clouds are voxelized at `depth`.
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+        #[arg(long, default_value_t = 12)]
+        depth: u32,
+    },
+    /// Turns an .obj or .stl mesh into voxels and saves them as .vox, .rsvo or chunk .bin
+    Voxelize {
+        input: PathBuf,
+        output: PathBuf,
+        /// The longest side of the mesh is 2^depth voxels
+        #[arg(long, default_value_t = 8)]
+        depth: u32,
+        /// Fill the inside of watertight meshes
+        #[arg(long)]
+        fill: bool,
+    },
+    /// Turns a Minecraft .schem, .schematic or .mca region file into a chunk that references
+    /// the blocks
+    ImportMinecraft {
+        input: PathBuf,
+        output: PathBuf,
+        /// Lines of "name id" tried before the default table, like "oak_planks 4" or "*_wool 0"
+        #[arg(long)]
+        block_table: Option<PathBuf>,
+    },
+    /// Turns a .nrrd volume, or a .raw one with --size and --type, into voxels through a transfer
+    /// function and saves them as .vox, .rsvo or chunk .bin
+    ImportVolume {
+        input: PathBuf,
+        output: PathBuf,
+        /// Lines of "density r g b opacity", densities at least half opaque are solid
+        #[arg(long)]
+        transfer: Option<PathBuf>,
+        /// Samples along each side of a .raw volume, as x,y,z
+        #[arg(long)]
+        size: Option<String>,
+        /// Type of each sample of a .raw volume: u8, i8, u16, i16, u32, i32 or f32
+        #[arg(long, default_value = "u8")]
+        r#type: String,
+        /// Bytes before the first sample of a .raw volume
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        #[arg(long)]
+        big_endian: bool,
+    },
+    /// Builds a world folder from a 16 bit heightmap in a .png or .pgm, with the same chunks as
+    /// `generate`
+    ImportHeightmap {
+        heightmap: PathBuf,
+        output: PathBuf,
+        /// Image that colours the surface
+        #[arg(long)]
+        colours: Option<PathBuf>,
+        /// Grey image where each value picks a biome for the layers under the surface
+        #[arg(long)]
+        materials: Option<PathBuf>,
+        /// Height of a white pixel as a fraction of the world height
+        #[arg(long, default_value_t = 0.5)]
+        vertical_scale: f32,
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..=MAX_WORLD_DEPTH as i64))]
+        world_depth: Option<u32>,
+        #[arg(long)]
+        chunk_depth: Option<u32>,
+        #[arg(long)]
+        hollow: bool,
+        /// mean, majority, luminance or "threshold <coverage>"
+        #[arg(long)]
+        mip_filter: Option<String>,
+    },
+    /// Saves the surface of a file or world folder as an .obj or .ply mesh with vertex colours
+    ExportMesh {
+        input: PathBuf,
+        output: PathBuf,
+        /// Depth of the voxels to mesh, deeper nodes are drawn with their mip colour
+        #[arg(long, default_value_t = 8)]
+        lod: u32,
+        /// Surface nets instead of cubes
+        #[arg(long)]
+        smooth: bool,
+        /// Corner of the region to mesh in the -1 to 1 space, as x,y,z
+        #[arg(long, requires = "max", allow_hyphen_values = true)]
+        min: Option<String>,
+        #[arg(long, requires = "min", allow_hyphen_values = true)]
+        max: Option<String>,
+        /// Levels of .rsvo files to load
+        #[arg(long, default_value_t = 12)]
+        depth: u32,
+    },
+    /// Saves a cross section of a file or world folder as a .png or .ppm image, empty voxels
+    /// are black
+    Slice {
+        input: PathBuf,
+        output: PathBuf,
+        /// x, y or z
+        #[arg(long, default_value = "z")]
+        axis: String,
+        /// Where the plane crosses the axis in the -1 to 1 space
+        #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+        at: f32,
+        /// Depth of the voxels, the image is 2^lod pixels wide
+        #[arg(long, default_value_t = 8)]
+        lod: u32,
+        /// Save every layer along the axis, numbered after the output name
+        #[arg(long, conflicts_with = "at")]
+        stack: bool,
+        /// Levels of .rsvo files to load
+        #[arg(long, default_value_t = 12)]
+        depth: u32,
+    },
+    /// Bakes the mips into leaves at a shallower depth, or splits leaves into deeper levels
+    Resample {
+        input: PathBuf,
+        output: PathBuf,
+        /// Cut the octree off at this depth
+        #[arg(long, conflicts_with = "levels")]
+        to_depth: Option<u32>,
+        /// Split leaves this many levels deeper
+        #[arg(long, required_unless_present = "to_depth")]
+        levels: Option<u32>,
+        /// Round off the steps between voxels when splitting
+        #[arg(long)]
+        smooth: bool,
+        /// Filter for the baked colours: mean, majority, luminance or "threshold <coverage>"
+        #[arg(long)]
+        mip_filter: Option<String>,
+        /// Levels of .rsvo files to load
+        #[arg(long, default_value_t = 12)]
+        depth: u32,
+    },
+    /// Generates a world into a folder without opening a window, resumes it if it was
+    /// interrupted
+    Generate {
+        output: PathBuf,
+        #[arg(long)]
+        seed: Option<u32>,
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..=MAX_WORLD_DEPTH as i64))]
+        world_depth: Option<u32>,
+        #[arg(long)]
+        chunk_depth: Option<u32>,
+        #[arg(long)]
+        hollow: bool,
+        /// mean, majority, luminance or "threshold <coverage>"
+        #[arg(long)]
+        mip_filter: Option<String>,
+    },
+    /// Checks a file or every chunk in a world folder for broken pointers and references
+    #[command(alias = "fsck")]
+    Validate {
+        path: PathBuf,
+        /// Prune whatever is broken and save the result
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Regenerates some chunks of a world and compares them to the manifest
+    Verify {
+        world: PathBuf,
+        #[arg(default_value_t = 4)]
+        samples: usize,
+    },
+}
+
+impl Command {
+    /// Runs every subcommand except `view`
+    pub fn run(self) -> Result<(), String> {
+        match self {
+            Command::View { .. } => unreachable!("The viewer is opened by main"),
+            Command::Info { path, depth } => info(&path, depth),
+            Command::Convert {
+                input,
+                output,
+                depth,
+            } => convert(&input, &output, depth),
+            Command::Voxelize {
+                input,
+                output,
+                depth,
+                fill,
+            } => voxelize(&input, &output, depth, fill),
+            Command::ImportMinecraft {
+                input,
+                output,
+                block_table,
+            } => import_minecraft(&input, &output, block_table.as_deref()),
+            Command::ImportVolume {
+                input,
+                output,
+                transfer,
+                size,
+                r#type,
+                offset,
+                big_endian,
+            } => {
+                let volume = match size {
+                    Some(size) => {
+                        let size = match size.split(',').collect::<Vec<_>>().as_slice() {
+                            [x, y, z] => Vector3::new(parse(x)?, parse(y)?, parse(z)?),
+                            _ => return Err(format!("Expected x,y,z but got '{}'", size)),
+                        };
+                        Volume {
+                            path: input.clone(),
+                            offset,
+                            size,
+                            sample_type: SampleType::from_name(&r#type)?,
+                            big_endian,
+                        }
+                    }
+                    None => Volume::load_nrrd(&input)?,
+                };
+                import_volume(&volume, &output, transfer.as_deref())
+            }
+            Command::ImportHeightmap {
+                heightmap,
+                output,
+                colours,
+                materials,
+                vertical_scale,
+                world_depth,
+                chunk_depth,
+                hollow,
+                mip_filter,
+            } => {
+                let mut gen_settings = GenSettings {
+                    hollow,
+                    ..Default::default()
+                };
+                if let Some(world_depth) = world_depth {
+                    gen_settings.world_depth = world_depth;
+                }
+                if let Some(chunk_depth) = chunk_depth {
+                    gen_settings.chunk_depth = chunk_depth;
+                }
+                if let Some(mip_filter) = mip_filter {
+                    let words: Vec<&str> = mip_filter.split_whitespace().collect();
+                    gen_settings.mip_filter = MipFilter::from_words(&words)?;
+                }
+
+                let heightmap = Heightmap::load(
+                    &heightmap,
+                    colours.as_deref(),
+                    materials.as_deref(),
+                    vertical_scale,
+                )?;
+                World::import_heightmap(&output, &heightmap, &gen_settings)?;
+                println!("Imported heightmap to {}", output.display());
+                Ok(())
+            }
+            Command::ExportMesh {
+                input,
+                output,
+                lod,
+                smooth,
+                min,
+                max,
+                depth,
+            } => {
+                let region = match min.zip(max) {
+                    Some((min, max)) => Some((parse_corner(&min)?, parse_corner(&max)?)),
+                    None => None,
+                };
+                let style = if smooth {
+                    MeshStyle::Smooth
+                } else {
+                    MeshStyle::Cubes
+                };
+                export_mesh(&input, &output, depth, lod, region, style)
+            }
+            Command::Slice {
+                input,
+                output,
+                axis,
+                at,
+                lod,
+                stack,
+                depth,
+            } => {
+                let axis = match axis.as_str() {
+                    "x" => 0,
+                    "y" => 1,
+                    "z" => 2,
+                    _ => return Err(format!("Axis has to be x, y or z, not '{}'", axis)),
+                };
+                let layers = if stack {
+                    0..1 << lod
+                } else {
+                    let layer = slice_layer(at, lod);
+                    layer..layer + 1
+                };
+                export_slices(&input, &output, depth, axis, layers, lod, stack)
+            }
+            Command::Resample {
+                input,
+                output,
+                to_depth,
+                levels,
+                smooth,
+                mip_filter,
+                depth,
+            } => {
+                let filter = match mip_filter {
+                    Some(mip_filter) => {
+                        let words: Vec<&str> = mip_filter.split_whitespace().collect();
+                        MipFilter::from_words(&words)?
+                    }
+                    None => MipFilter::default(),
+                };
+                resample(&input, &output, depth, to_depth, levels, smooth, filter)
+            }
+            Command::Generate {
+                output,
+                seed,
+                world_depth,
+                chunk_depth,
+                hollow,
+                mip_filter,
+            } => {
+                // Resuming needs the settings the world was started with
+                let mut gen_settings = match Manifest::load(&output) {
+                    Ok(manifest) => manifest.gen_settings,
+                    Err(_) => GenSettings::default(),
+                };
+                if let Some(seed) = seed {
+                    gen_settings.seed = seed;
+                }
+                if let Some(world_depth) = world_depth {
+                    gen_settings.world_depth = world_depth;
+                }
+                if let Some(chunk_depth) = chunk_depth {
+                    gen_settings.chunk_depth = chunk_depth;
+                }
+                gen_settings.hollow |= hollow;
+                if let Some(mip_filter) = mip_filter {
+                    let words: Vec<&str> = mip_filter.split_whitespace().collect();
+                    gen_settings.mip_filter = MipFilter::from_words(&words)?;
+                }
+
+                generate(output, gen_settings)
+            }
+            Command::Validate { path, repair } => validate(&path, repair),
+            Command::Verify { world, samples } => verify(&world, samples),
+        }
+    }
+}
+
+fn info(path: &Path, depth: u32) -> Result<(), String> {
+    let world = World::open(path, depth)?;
+    print!("{}", world.chunks.get(&0).unwrap().stats());
+
+    if path.is_dir() {
+        println!();
+        println!("Whole world:");
+        print!("{}", world.folder_stats()?);
+
+        match Manifest::load(path) {
+            Ok(manifest) => println!(
+                "Seed: {}, world depth: {}, chunk depth: {}, {}",
+                manifest.gen_settings.seed,
+                manifest.gen_settings.world_depth,
+                manifest.gen_settings.chunk_depth,
+                if manifest.root_hash.is_some() {
+                    "finished"
+                } else {
+                    "unfinished"
+                }
+            ),
+            Err(_) => println!("No manifest"),
+        }
+    }
+
+    Ok(())
+}
+
+fn convert(input: &Path, output: &Path, depth: u32) -> Result<(), String> {
+    // Fills in the mips so chunk files can be viewed and references get a colour
+    let world = World::open(input, depth)?;
+    let chunk = world.chunks.get(&0).unwrap();
+    chunk.save_file(output)?;
+
+    println!(
+        "Converted {} nodes from {} to {}",
+        chunk.nodes.len(),
+        input.display(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn voxelize(input: &Path, output: &Path, depth: u32, fill: bool) -> Result<(), String> {
+    let mesh = Mesh::load_file(input)?;
+    let mut octree = mesh.voxelize(depth, fill)?;
+    octree.generate_mips(&|_| Voxel::new(0, 0, 0), MipFilter::default());
+    octree.save_file(output)?;
+
+    for missing in &mesh.missing_materials {
+        println!("Couldn't read material library {}", missing);
+    }
+    println!(
+        "Voxelized {} triangles into {} nodes",
+        mesh.triangles.len(),
+        octree.nodes.len()
+    );
+    Ok(())
+}
+
+fn import_minecraft(input: &Path, output: &Path, block_table: Option<&Path>) -> Result<(), String> {
+    let table = match block_table {
+        Some(path) => std::fs::read_to_string(path).map_err(|e| e.to_string())?,
+        None => String::new(),
+    };
+    let mut table = BlockTable::new(&table)?;
+
+    let data = std::fs::read(input).map_err(|e| e.to_string())?;
+    let extension = input.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let chunk = CpuOctree::load_minecraft(&data, extension, &mut table)?;
+
+    // The blocks are needed for the mips
+    let mut world = World::new(String::new());
+    world.chunks.insert(0, chunk);
+    world.generate_mip_tree(0)?;
+    let chunk = world.chunks.get(&0).unwrap();
+    chunk.save_file(output)?;
+
+    if !table.unmapped.is_empty() {
+        let unmapped: Vec<&str> = table.unmapped.iter().map(String::as_str).collect();
+        println!("Used stone for {}", unmapped.join(", "));
+    }
+    println!(
+        "Imported {} nodes to {}",
+        chunk.nodes.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn import_volume(volume: &Volume, output: &Path, transfer: Option<&Path>) -> Result<(), String> {
+    let transfer = match transfer {
+        Some(path) => {
+            TransferFunction::new(&std::fs::read_to_string(path).map_err(|e| e.to_string())?)?
+        }
+        None => TransferFunction::default_for(volume.sample_type),
+    };
+    let mut octree = CpuOctree::load_volume(volume, &transfer)?;
+    octree.generate_mips(&|_| Voxel::new(0, 0, 0), MipFilter::default());
+    octree.save_file(output)?;
+
+    println!(
+        "Imported {}x{}x{} samples into {} nodes",
+        volume.size.x,
+        volume.size.y,
+        volume.size.z,
+        octree.nodes.len()
+    );
+    Ok(())
+}
+
+/// "x,y,z"
+fn parse_corner(text: &str) -> Result<Vector3<f32>, String> {
+    match text.split(',').collect::<Vec<_>>().as_slice() {
+        [x, y, z] => Ok(Vector3::new(parse(x)?, parse(y)?, parse(z)?)),
+        _ => Err(format!("Expected x,y,z but got '{}'", text)),
+    }
+}
+
+fn export_mesh(
+    input: &Path,
+    output: &Path,
+    depth: u32,
+    lod: u32,
+    region: Option<(Vector3<f32>, Vector3<f32>)>,
+    style: MeshStyle,
+) -> Result<(), String> {
+    let world = World::open(input, depth)?;
+    let mesh = if input.is_dir() {
+        ChunkFiles::new(&world)?.to_mesh(lod, region, style)?
+    } else {
+        world.to_mesh(lod, region, style)?
+    };
+    mesh.save_file(output)?;
+
+    println!(
+        "Saved {} triangles to {}",
+        mesh.triangles.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+fn export_slices(
+    input: &Path,
+    output: &Path,
+    depth: u32,
+    axis: usize,
+    layers: std::ops::Range<u32>,
+    lod: u32,
+    stack: bool,
+) -> Result<(), String> {
+    let world = World::open(input, depth)?;
+    let files = if input.is_dir() {
+        Some(ChunkFiles::new(&world)?)
+    } else {
+        None
+    };
+
+    for layer in layers.clone() {
+        let path = if stack {
+            stack_path(output, layer, &layers)
+        } else {
+            output.to_path_buf()
+        };
+        let image = match &files {
+            Some(files) => files.slice(axis, layer, lod)?,
+            None => world.slice(axis, layer, lod)?,
+        };
+        image.save(&path)?;
+    }
+
+    if stack {
+        println!("Saved {} slices next to {}", layers.len(), output.display());
+    } else {
+        println!("Saved layer {} to {}", layers.start, output.display());
+    }
+    Ok(())
+}
+
+fn resample(
+    input: &Path,
+    output: &Path,
+    depth: u32,
+    to_depth: Option<u32>,
+    levels: Option<u32>,
+    smooth: bool,
+    filter: MipFilter,
+) -> Result<(), String> {
+    let world = World::open(input, depth)?;
+    let chunk = world.chunks.get(&0).unwrap();
+    let resampled = match (to_depth, levels) {
+        (Some(to_depth), _) => chunk.downsample(to_depth, filter),
+        (None, Some(levels)) => chunk.upsample(levels, smooth),
+        (None, None) => unreachable!("clap requires one of them"),
+    };
+    resampled.save_file(output)?;
+
+    println!(
+        "Resampled {} levels and {} nodes to {} levels and {} nodes",
+        chunk.depth(),
+        chunk.nodes.len(),
+        resampled.depth(),
+        resampled.nodes.len()
+    );
+    Ok(())
+}
+
+fn generate(path: PathBuf, gen_settings: GenSettings) -> Result<(), String> {
+    let mut job = GenJob::start(path, gen_settings);
+
+    let bar = indicatif::ProgressBar::new(0);
+    bar.set_style(
+        indicatif::ProgressStyle::default_bar().template("{bar:40} {pos}/{len} chunks, {eta} left"),
+    );
+    loop {
+        if let Some(result) = job.poll() {
+            bar.finish();
+            return result;
+        }
+
+        let (done, total) = job.progress.get();
+        bar.set_length(total);
+        bar.set_position(done);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+fn validate(path: &Path, repair: bool) -> Result<(), String> {
+    let problems = if path.is_dir() {
+        World::fsck(path, repair)?
+    } else {
+        let mut chunk = CpuOctree::load_file(path.to_string_lossy().to_string(), u32::MAX)?;
+
+        // References can be to blocks or to chunk files next to this one
+        let world = World::new(String::new());
+        let chunk_exists = |id: u32| {
+            world.chunks.contains_key(&id) || path.with_file_name(format!("{}.bin", id)).exists()
+        };
+
+        let problems = chunk.validate(&chunk_exists);
+        if repair && !problems.is_empty() {
+            chunk.repair(&problems, MipFilter::default());
+            chunk.save_file(path)?;
+        }
+
+        problems.into_iter().map(|problem| (0, problem)).collect()
+    };
+
+    for (id, problem) in &problems {
+        println!("Chunk {}: {}", id, problem);
+    }
+
+    if problems.is_empty() {
+        println!("No problems found");
+        Ok(())
+    } else if repair {
+        let pruned = problems
+            .iter()
+            .filter(|(_, problem)| !matches!(problem.kind, ProblemKind::Truncated))
+            .count();
+        println!("Pruned {} broken nodes", pruned);
+        Ok(())
+    } else {
+        Err(format!("{} problems found", problems.len()))
+    }
+}
+
+fn verify(path: &Path, samples: usize) -> Result<(), String> {
+    let gpu = pollster::block_on(Gpu::headless())?;
+    let mut procedural = Procedural::new(&gpu);
+
+    let results = World::verify_world(path, &mut procedural, &gpu, samples)?;
+    for (id, matches) in &results {
+        println!("Chunk {}: {}", id, if *matches { "ok" } else { "MISMATCH" });
+    }
+
+    if results.iter().all(|(_, matches)| *matches) {
+        Ok(())
+    } else {
+        Err("Generation is not reproducible".to_string())
+    }
+}
@@ -0,0 +1,362 @@
+use super::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+impl CpuOctree {
+    /// Saves as .vox, .rsvo or a chunk .bin depending on the extension
+    pub fn save_file(&self, path: &Path) -> Result<(), String> {
+        use std::ffi::OsStr;
+        let data = match path.extension().and_then(OsStr::to_str) {
+            Some("vox") => self.save_vox()?,
+            Some("rsvo") => self.save_octree(),
+            Some("bin") => unsafe { self.bin() }.to_vec(),
+            _ => return Err("Unknown file type".to_string()),
+        };
+
+        std::fs::write(path, data).map_err(|e| e.to_string())
+    }
+
+    /// Depth of the deepest leaf
+    pub fn depth(&self) -> u32 {
+        self.walk(WalkSettings::default())
+            .map(|visit| visit.depth)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Leaves above the deepest level are filled in, references use their mip colour
+    fn save_vox(&self) -> Result<Vec<u8>, String> {
+        let depth = self.depth();
+        if depth > 8 {
+            return Err(format!(
+                "Octree is {} levels deep, .vox files can only hold 8",
+                depth
+            ));
+        }
+        let size = 1u32 << depth;
+
+        let mut voxels = Vec::new();
+        let solid = self.leaves(WalkSettings::default()).filter(|leaf| {
+            leaf.node.pointer > CHUNK_OFFSET || leaf.node.value != Voxel::new(0, 0, 0)
+        });
+        for leaf in solid {
+            let node = leaf.node;
+            let colour = if node.value == Voxel::new(0, 0, 0) {
+                // Reference without mips
+                Voxel::new(128, 128, 128)
+            } else {
+                node.value
+            };
+
+            let scale = 1 << (depth - leaf.depth);
+            for x in 0..scale {
+                for y in 0..scale {
+                    for z in 0..scale {
+                        let pos = leaf.pos * scale + Vector3::new(x, y, z);
+                        voxels.push((pos, colour));
+                    }
+                }
+            }
+        }
+
+        // .vox has 255 colours, drop bits until everything fits
+        let mut palette = HashMap::new();
+        for bits in 0..8 {
+            let quantize = |c: u8| {
+                if bits == 0 {
+                    c
+                } else {
+                    (c >> bits << bits) | (1 << (bits - 1))
+                }
+            };
+            palette.clear();
+            for (_, colour) in &voxels {
+                let colour = Voxel::new(quantize(colour.r), quantize(colour.g), quantize(colour.b));
+                let next = palette.len();
+                palette.entry(colour.to_cpu_value()).or_insert(next);
+            }
+
+            if palette.len() <= 255 {
+                for (_, colour) in &mut voxels {
+                    *colour =
+                        Voxel::new(quantize(colour.r), quantize(colour.g), quantize(colour.b));
+                }
+                break;
+            }
+        }
+
+        let mut colours = vec![0; 256];
+        for (value, i) in &palette {
+            let colour = Voxel::from_value(*value);
+            colours[*i] = u32::from_le_bytes([colour.r, colour.g, colour.b, 255]);
+        }
+
+        let model = dot_vox::Model {
+            size: dot_vox::Size {
+                x: size,
+                y: size,
+                z: size,
+            },
+            voxels: voxels
+                .iter()
+                .map(|(pos, colour)| {
+                    let pos = Orientation::VOX.inverse().apply_grid(*pos, size);
+                    dot_vox::Voxel {
+                        x: pos.x as u8,
+                        y: pos.y as u8,
+                        z: pos.z as u8,
+                        i: palette[&colour.to_cpu_value()] as u8,
+                    }
+                })
+                .collect(),
+        };
+
+        let data = dot_vox::DotVoxData {
+            version: 150,
+            models: vec![model],
+            palette: colours,
+            materials: Vec::new(),
+        };
+
+        let mut out = Vec::new();
+        data.write_vox(&mut out).map_err(|e| e.to_string())?;
+        Ok(out)
+    }
+
+    /// Only stores which voxels are filled, .rsvo has no colours. Header fields other than
+    /// the ones load_octree reads are left as zero.
+    fn save_octree(&self) -> Vec<u8> {
+        #[derive(Clone, Copy)]
+        enum Item {
+            Node(usize),
+            /// Inside a leaf above the deepest level
+            Solid,
+        }
+
+        // Whether each node has anything in it
+        let mut filled = vec![false; self.nodes.len()];
+        let mut order = Vec::new();
+        let mut stack: Vec<usize> = (0..8).collect();
+        while let Some(index) = stack.pop() {
+            order.push(index);
+            let node = self.nodes[index];
+            if node.pointer < CHUNK_OFFSET {
+                stack.extend(node.pointer as usize..node.pointer as usize + 8);
+            }
+        }
+        for index in order.into_iter().rev() {
+            let node = self.nodes[index];
+            filled[index] = if node.pointer < CHUNK_OFFSET {
+                filled[node.pointer as usize..node.pointer as usize + 8]
+                    .iter()
+                    .any(|f| *f)
+            } else {
+                node.pointer > CHUNK_OFFSET || node.value != Voxel::new(0, 0, 0)
+            };
+        }
+
+        let children = |item: Item| -> [Option<Item>; 8] {
+            let mut children = [None; 8];
+            for (i, child) in children.iter_mut().enumerate() {
+                *child = match item {
+                    Item::Solid => Some(Item::Solid),
+                    Item::Node(index) => {
+                        let node = self.nodes[index];
+                        if node.pointer >= CHUNK_OFFSET {
+                            Some(Item::Solid)
+                        } else if filled[node.pointer as usize + i] {
+                            Some(Item::Node(node.pointer as usize + i))
+                        } else {
+                            None
+                        }
+                    }
+                };
+            }
+            children
+        };
+        let mask = |children: [Option<Item>; 8]| {
+            (0..8).fold(0u8, |mask, i| mask | ((children[i].is_some() as u8) << i))
+        };
+
+        let top_level = self.depth();
+
+        // Level 0 is the root, its children are the top level of the CpuOctree
+        let mut level: Vec<Item> = (0..8).filter(|i| filled[*i]).map(Item::Node).collect();
+        let mut masks = vec![(0..8).fold(0u8, |mask, i| mask | ((filled[i] as u8) << i))];
+        let mut node_counts = vec![1u32];
+        for _ in 1..top_level {
+            node_counts.push(level.len() as u32);
+
+            let mut next = Vec::new();
+            for item in &level {
+                let children = children(*item);
+                masks.push(mask(children));
+                next.extend(children.iter().flatten());
+            }
+            level = next;
+        }
+        node_counts.push(level.len() as u32);
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"RSVO");
+        out.extend_from_slice(&[0; 12]);
+        out.extend_from_slice(&[top_level as u8, 0, 0, 0]);
+        for count in node_counts {
+            out.extend_from_slice(&count.to_le_bytes());
+        }
+        out.extend_from_slice(&masks);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn octree(voxels: &[(Vector3<u32>, Voxel)], depth: u32) -> CpuOctree {
+        let mut leaves: Vec<_> = voxels
+            .iter()
+            .map(|&(pos, colour)| (morton(pos, depth), Node::new(CHUNK_OFFSET, colour)))
+            .collect();
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, depth)
+    }
+
+    /// Solid voxels at `depth` and their colours
+    fn solid(octree: &CpuOctree, depth: u32) -> Vec<(Vector3<u32>, Voxel)> {
+        let size = 1 << depth;
+        let mut solid = Vec::new();
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    let pos = Vector3::new(x, y, z);
+                    let (index, _) = octree.lookup(pos, depth);
+                    let node = octree.nodes[index];
+                    if is_solid(node) {
+                        solid.push((pos, node.value));
+                    }
+                }
+            }
+        }
+        solid
+    }
+
+    /// Saves to a temporary file named `name` and loads it again
+    fn roundtrip(octree: &CpuOctree, name: &str, depth: u32) -> Result<CpuOctree, String> {
+        let path = std::env::temp_dir().join(format!("{}_{}", std::process::id(), name));
+        octree.save_file(&path)?;
+        let loaded = CpuOctree::load_file(path.to_string_lossy().to_string(), depth);
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    /// A few voxels and the top corner octant solid, which collapses into one leaf
+    fn sample() -> CpuOctree {
+        let mut voxels = vec![
+            (Vector3::new(0, 0, 0), Voxel::new(255, 0, 0)),
+            (Vector3::new(3, 0, 1), Voxel::new(0, 255, 0)),
+            (Vector3::new(0, 2, 0), Voxel::new(0, 0, 255)),
+        ];
+        for i in 0..8 {
+            let pos = Vector3::new(2 + (i & 1), 2 + (i >> 1 & 1), 2 + (i >> 2));
+            voxels.push((pos, Voxel::new(10, 20, 30)));
+        }
+        octree(&voxels, 2)
+    }
+
+    #[test]
+    fn vox_roundtrip() {
+        let original = sample();
+        let loaded = roundtrip(&original, "roundtrip.vox", 0).unwrap();
+        assert_eq!(solid(&loaded, 2), solid(&original, 2));
+        assert_eq!(solid(&loaded, 2).len(), 11);
+
+        let deep = octree(&[(Vector3::zero(), Voxel::new(255, 0, 0))], 9);
+        assert!(deep.save_vox().is_err());
+    }
+
+    #[test]
+    fn vox_orientation_inverse() {
+        let inverse = Orientation::VOX.inverse();
+        assert_eq!(Orientation::VOX.then(inverse), Orientation::IDENTITY);
+        assert_eq!(inverse.then(Orientation::VOX), Orientation::IDENTITY);
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    let pos = Vector3::new(x, y, z);
+                    assert_eq!(
+                        inverse.apply_grid(Orientation::VOX.apply_grid(pos, 4), 4),
+                        pos
+                    );
+                }
+            }
+        }
+        // Mirrored x and swapped y and z
+        assert_eq!(
+            inverse.apply_grid(Vector3::new(0, 1, 2), 4),
+            Vector3::new(3, 2, 1)
+        );
+    }
+
+    #[test]
+    fn vox_palette_is_quantized() {
+        // 300 colours in a row, too many for a .vox palette
+        let voxels: Vec<_> = (0..300u32)
+            .map(|i| {
+                let pos = Vector3::new(i % 32, i / 32, 0);
+                (pos, Voxel::new(i as u8, (i / 256) as u8 * 100 + 1, 50))
+            })
+            .collect();
+        let original = octree(&voxels, 5);
+        let loaded = roundtrip(&original, "palette.vox", 0).unwrap();
+
+        let loaded = solid(&loaded, 5);
+        let original = solid(&original, 5);
+        assert_eq!(loaded.len(), 300);
+        let mut colours = HashMap::new();
+        for ((pos, before), (loaded_pos, after)) in original.iter().zip(&loaded) {
+            assert_eq!(pos, loaded_pos);
+            let close = |a: u8, b: u8| (a as i32 - b as i32).abs() < 4;
+            assert!(
+                close(before.r, after.r) && close(before.g, after.g) && close(before.b, after.b),
+                "{:?} became {:?}",
+                before,
+                after
+            );
+            colours.insert(after.to_cpu_value(), ());
+        }
+        assert!(colours.len() <= 255);
+    }
+
+    #[test]
+    fn rsvo_roundtrip() {
+        let original = sample();
+        let occupied = |octree: &CpuOctree, depth| -> Vec<Vector3<u32>> {
+            solid(octree, depth)
+                .into_iter()
+                .map(|(pos, _)| pos)
+                .collect()
+        };
+
+        let loaded = roundtrip(&original, "roundtrip.rsvo", 2).unwrap();
+        assert_eq!(occupied(&loaded, 2), occupied(&original, 2));
+
+        // Only the top level, a node is solid if anything under it is
+        let loaded = roundtrip(&original, "top.rsvo", 1).unwrap();
+        assert_eq!(
+            occupied(&loaded, 1),
+            [
+                Vector3::new(0, 0, 0),
+                Vector3::new(0, 1, 0),
+                Vector3::new(1, 0, 0),
+                Vector3::new(1, 1, 1)
+            ]
+        );
+
+        let path = std::env::temp_dir().join(format!("{}_truncated.rsvo", std::process::id()));
+        let data = original.save_octree();
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(CpuOctree::load_file(path.to_string_lossy().to_string(), 2).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,15 +2,36 @@ use super::*;
 
 pub const CHUNK_OFFSET: u32 = 2147483648;
 
+/// Stored as is in the .bin files so the layout has to stay fixed
+#[repr(C)]
 #[derive(Copy, Clone)]
 pub struct Node {
     pub pointer: u32,
     pub value: Voxel,
+    /// How much of the node is filled out of 255, set when the mips are built. Fits in what
+    /// used to be padding so the node is still 8 bytes.
+    pub coverage: u8,
 }
 
 impl Node {
     pub fn new(pointer: u32, value: Voxel) -> Self {
-        Node { value, pointer }
+        Node {
+            value,
+            pointer,
+            coverage: 0,
+        }
+    }
+
+    /// Value of the node in the gpu octree
+    pub fn to_value(self, filter: MipFilter) -> u32 {
+        match filter {
+            MipFilter::Threshold(threshold)
+                if self.pointer < CHUNK_OFFSET && self.coverage < threshold =>
+            {
+                (VOXEL_OFFSET + SEE_THROUGH) << 4
+            }
+            _ => self.value.to_value(),
+        }
     }
 }
 
@@ -50,36 +71,16 @@ impl CpuOctree {
         pos: Vector3<f32>,
         max_depth: Option<u32>,
     ) -> (usize, u32, Vector3<f32>) {
-        let mut node_index = 0;
-        let mut node_pos = Vector3::zero();
-        let mut depth = 0;
-        loop {
-            depth += 1;
-
-            let p = Vector3::new(
-                (pos.x >= node_pos.x) as usize,
-                (pos.y >= node_pos.y) as usize,
-                (pos.z >= node_pos.z) as usize,
-            );
-            let child_index = p.x * 4 + p.y * 2 + p.z;
-
-            node_pos += Octree::pos_offset(child_index, depth);
-
-            if self.nodes[node_index + child_index].pointer >= CHUNK_OFFSET
-                || depth == max_depth.unwrap_or(u32::MAX)
-            {
-                return (node_index + child_index, depth, node_pos);
-            }
-
-            node_index = self.nodes[node_index + child_index].pointer as usize;
-        }
+        let leaf = descend(self, pos, max_depth, false, &mut |_| {}).unwrap();
+        (leaf.index, leaf.depth, leaf.centre())
     }
 
-    /// Takes a pointer to the first child NOT to the parent
-    pub fn get_node_mask(&self, node: usize) -> [Voxel; 8] {
-        let mut mask = [Voxel::new(0, 0, 0); 8];
+    /// Takes a pointer to the first child NOT to the parent. Returns the values of the
+    /// children in the gpu octree.
+    pub fn get_node_mask(&self, node: usize, filter: MipFilter) -> [u32; 8] {
+        let mut mask = [0; 8];
         for i in 0..8 {
-            mask[i] = self.nodes[node + i].value;
+            mask[i] = self.nodes[node + i].to_value(filter);
         }
         mask
     }
@@ -112,11 +113,33 @@ impl CpuOctree {
 
     pub fn load_file(file: String, octree_depth: u32) -> Result<CpuOctree, String> {
         let path = std::path::Path::new(&file);
-        let data = std::fs::read(path).map_err(|e| e.to_string())?;
         use std::ffi::OsStr;
-        let octree = match path.extension().and_then(OsStr::to_str) {
+        let extension = path.extension().and_then(OsStr::to_str);
+        if let Some("ply" | "xyz") = extension {
+            // Streamed instead of read all at once
+            return CpuOctree::load_point_cloud(path, octree_depth);
+        } else if let Some("nrrd" | "nhdr") = extension {
+            // Read a brick at a time
+            let volume = Volume::load_nrrd(path)?;
+            let transfer = TransferFunction::default_for(volume.sample_type);
+            return CpuOctree::load_volume(&volume, &transfer);
+        }
+
+        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+        let octree = match extension {
             Some("rsvo") => CpuOctree::load_octree(&data, octree_depth)?,
             Some("vox") => CpuOctree::load_vox(&data)?,
+            // Meshes and point clouds are voxelized at the depth .rsvo files are loaded to
+            Some("obj" | "stl") => Mesh::load_file(path)?.voxelize(octree_depth, false)?,
+            Some(extension @ ("schem" | "schematic" | "mca")) => {
+                CpuOctree::load_minecraft(&data, extension, &mut BlockTable::new("")?)?
+            }
+            Some("bin") => {
+                if data.is_empty() || !data.len().is_multiple_of(8 * std::mem::size_of::<Node>()) {
+                    return Err("Chunk file isn't a whole number of node groups".to_string());
+                }
+                unsafe { CpuOctree::from_bin(data) }
+            }
             _ => return Err("Unknown file type".to_string()),
         };
 
@@ -129,9 +152,15 @@ impl CpuOctree {
         let top_level_start = 16;
         let node_count_start = 20;
 
+        if data.len() <= top_level_start {
+            return Err("File is truncated".to_string());
+        }
         let top_level = data[top_level_start] as usize;
 
         let data_start = node_count_start + 4 * (top_level + 1);
+        if data.len() <= data_start {
+            return Err("File is truncated".to_string());
+        }
 
         let mut node_counts = Vec::new();
         for i in 0..(top_level + 1) {
@@ -145,14 +174,13 @@ impl CpuOctree {
             node_counts.push(node_count);
         }
 
-        if octree_depth as usize > top_level {
-            return Err(format!(
-                "Octree depth ({}) is greater than top level ({})",
-                octree_depth, top_level
-            ));
-        }
+        // Deeper than the file just loads all of it
+        let octree_depth = octree_depth.min(top_level as u32);
 
         let node_end = node_counts[0..octree_depth as usize].iter().sum::<u32>() as usize;
+        if data.len() < data_start + node_end {
+            return Err("File is truncated".to_string());
+        }
 
         let mut octree = CpuOctree::new(data[data_start]);
         let mut data_index = 1;
@@ -191,11 +219,11 @@ impl CpuOctree {
         let mut octree = CpuOctree::new(0);
         for voxel in &vox_data.models[0].voxels {
             let colour = vox_data.palette[voxel.i as usize].to_le_bytes();
-            let mut pos = Vector3::new(
-                size as f32 - voxel.x as f32 - 1.0,
-                voxel.z as f32,
-                voxel.y as f32,
-            );
+            let pos = Vector3::new(voxel.x as u32, voxel.y as u32, voxel.z as u32);
+            let mut pos = Orientation::VOX
+                .apply_grid(pos, size as u32)
+                .cast::<f32>()
+                .unwrap();
             pos /= size as f32;
             pos = pos * 2.0 - Vector3::new(1.0, 1.0, 1.0);
 
@@ -219,11 +247,11 @@ impl CpuOctree {
         let mut voxels = Vec::new();
         for voxel in &vox_data.models[0].voxels {
             let pos = Vector3::new(
-                size.x as i32 / 2 - voxel.x as i32,
-                voxel.z as i32,
+                voxel.x as i32 - size.x as i32 / 2,
                 voxel.y as i32 - size.y as i32 / 2,
+                voxel.z as i32,
             );
-            voxels.push((pos, voxel.i as u32 + 1));
+            voxels.push((Orientation::VOX.apply_signed(pos), voxel.i as u32 + 1));
         }
 
         return voxels;
@@ -251,6 +279,219 @@ impl CpuOctree {
         octree
     }
 
+    /// Makes `other` the subtree of the node at `index`
+    pub fn graft(&mut self, index: usize, other: &CpuOctree) {
+        let offset = self.nodes.len() as u32;
+        self.nodes[index].pointer = offset;
+        self.nodes.extend(other.nodes.iter().map(|node| {
+            if node.pointer < CHUNK_OFFSET {
+                Node::new(node.pointer + offset, node.value)
+            } else {
+                *node
+            }
+        }));
+    }
+
+    /// Rebuilds the nodes in breadth first order so the layout only depends on the content
+    pub fn canonicalize(&mut self) {
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        nodes.extend_from_slice(&self.nodes[0..8]);
+
+        let mut i = 0;
+        while i < nodes.len() {
+            let pointer = nodes[i].pointer as usize;
+            if pointer < CHUNK_OFFSET as usize {
+                nodes[i].pointer = nodes.len() as u32;
+                nodes.extend_from_slice(&self.nodes[pointer..pointer + 8]);
+            }
+
+            i += 1;
+        }
+
+        self.nodes = nodes;
+    }
+
+    /// FNV-1a hash of the leaves in breadth first order. Doesn't depend on the node layout or
+    /// the mip colours.
+    pub fn content_hash(&self) -> u64 {
+        let mut hash = 0xcbf29ce484222325u64;
+        let mut write = |value: u32| {
+            for byte in value.to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+
+        use std::collections::VecDeque;
+        let mut queue = VecDeque::from([0]);
+        while let Some(group) = queue.pop_front() {
+            for node in &self.nodes[group..group + 8] {
+                if node.pointer < CHUNK_OFFSET {
+                    write(0);
+                    queue.push_back(node.pointer as usize);
+                } else if node.pointer == CHUNK_OFFSET {
+                    write(node.pointer);
+                    write(node.value.to_cpu_value());
+                } else {
+                    write(node.pointer);
+                }
+            }
+        }
+
+        hash
+    }
+
+    /// Collapses every solid region that can't be seen from outside the octree into a single
+    /// leaf. `opaque_ref` says whether the block or chunk with that id hides what's behind it.
+    /// Returns the node count before and after.
+    pub fn hollow(&mut self, opaque_ref: &dyn Fn(u32) -> bool) -> (usize, usize) {
+        let before = self.nodes.len();
+
+        // Children always come after their parents once canonical
+        self.canonicalize();
+
+        let opaque_leaf = |node: &Node| {
+            if node.pointer == CHUNK_OFFSET {
+                node.value != Voxel::new(0, 0, 0)
+            } else {
+                opaque_ref(node.pointer - CHUNK_OFFSET)
+            }
+        };
+
+        // Whether every leaf under each node is opaque and the leaf to replace it with if so
+        let mut full = vec![false; self.nodes.len()];
+        let mut solid = self.nodes.clone();
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            if node.pointer >= CHUNK_OFFSET {
+                full[i] = opaque_leaf(&node);
+            } else {
+                let children = node.pointer as usize..node.pointer as usize + 8;
+                full[i] = full[children.clone()].iter().all(|f| *f);
+                if full[i] {
+                    solid[i] = representative(&solid[children]);
+                }
+            }
+        }
+
+        let mut stack: Vec<_> = (0..8).map(|i| (i, child_offset(i), 1)).collect();
+        while let Some((index, coords, depth)) = stack.pop() {
+            let node = self.nodes[index];
+            if node.pointer >= CHUNK_OFFSET {
+                continue;
+            }
+
+            if full[index] && self.is_hidden(coords, depth, &opaque_leaf) {
+                self.nodes[index] = solid[index];
+            } else {
+                for i in 0..8 {
+                    stack.push((
+                        node.pointer as usize + i,
+                        coords * 2 + child_offset(i),
+                        depth + 1,
+                    ));
+                }
+            }
+        }
+
+        // Drops the groups that are no longer reachable
+        self.canonicalize();
+        (before, self.nodes.len())
+    }
+
+    /// Whether all six faces of the octree are covered, used to tell which blocks hide what's
+    /// behind them
+    pub fn faces_opaque(&self) -> bool {
+        let opaque_leaf =
+            |node: &Node| node.pointer == CHUNK_OFFSET && node.value != Voxel::new(0, 0, 0);
+        (0..3).all(|axis| {
+            (0..2).all(|side| {
+                (0..8)
+                    .filter(|i| (i >> (2 - axis)) & 1 == side)
+                    .all(|i| self.face_opaque(i, axis, side, &opaque_leaf))
+            })
+        })
+    }
+
+    /// Whether the six neighbours of the node at integer `coords` and `depth` cover its faces
+    fn is_hidden(
+        &self,
+        coords: Vector3<u32>,
+        depth: u32,
+        opaque_leaf: &dyn Fn(&Node) -> bool,
+    ) -> bool {
+        let size = 1u32 << depth;
+        for axis in 0..3 {
+            for dir in [-1i64, 1] {
+                let neighbour = coords[axis] as i64 + dir;
+                if neighbour < 0 || neighbour >= size as i64 {
+                    // Could be seen from outside the octree
+                    return false;
+                }
+
+                let mut neighbour_coords = coords;
+                neighbour_coords[axis] = neighbour as u32;
+                let (index, neighbour_depth) = self.lookup(neighbour_coords, depth);
+
+                // The face of the neighbour that touches this node
+                let side = (dir < 0) as usize;
+                let opaque = if neighbour_depth < depth {
+                    opaque_leaf(&self.nodes[index])
+                } else {
+                    self.face_opaque(index, axis, side, opaque_leaf)
+                };
+
+                if !opaque {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn face_opaque(
+        &self,
+        index: usize,
+        axis: usize,
+        side: usize,
+        opaque_leaf: &dyn Fn(&Node) -> bool,
+    ) -> bool {
+        let node = self.nodes[index];
+        if node.pointer >= CHUNK_OFFSET {
+            return opaque_leaf(&node);
+        }
+
+        (0..8)
+            .filter(|i| (i >> (2 - axis)) & 1 == side)
+            .all(|i| self.face_opaque(node.pointer as usize + i, axis, side, opaque_leaf))
+    }
+
+    /// Returns the node at integer `coords` and `depth` or the leaf containing it
+    pub fn lookup(&self, coords: Vector3<u32>, depth: u32) -> (usize, u32) {
+        let size = 2.0 / (1u64 << depth) as f32;
+        let pos = (coords.cast::<f32>().unwrap() + Vector3::new(0.5, 0.5, 0.5)) * size
+            - Vector3::new(1.0, 1.0, 1.0);
+        let leaf = descend(self, pos, Some(depth), false, &mut |_| {}).unwrap();
+        (leaf.index, leaf.depth)
+    }
+
+    /// Builds an octree `depth` levels deep from leaves sorted by their `morton` code,
+    /// collapsing groups that are all the same voxel
+    pub fn from_sorted_leaves(leaves: &[(u64, Node)], depth: u32) -> CpuOctree {
+        let mut nodes = Vec::new();
+        let root = build_sorted(leaves, 1, depth, &mut nodes);
+        if root.pointer >= CHUNK_OFFSET {
+            // Everything collapsed into one leaf, the top level has to stay a group
+            nodes = vec![root; 8];
+        }
+        CpuOctree {
+            nodes,
+            top_mip: Voxel::new(0, 0, 0),
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn raw(&self) -> Vec<u32> {
         let mut raw = Vec::new();
         for node in &self.nodes {
@@ -272,6 +513,103 @@ impl CpuOctree {
     }
 }
 
+/// Position in the order the octree stores its children, so sorting puts the voxels of each
+/// node next to each other
+pub fn morton(pos: Vector3<u32>, depth: u32) -> u64 {
+    let mut code = 0;
+    for shift in (0..depth).rev() {
+        let bit = |c: u32| ((c >> shift) & 1) as u64;
+        code = code << 3 | bit(pos.x) << 2 | bit(pos.y) << 1 | bit(pos.z);
+    }
+    code
+}
+
+/// Builds the group for the sorted leaves at `level`, collapsing groups that are all the same
+fn build_sorted(leaves: &[(u64, Node)], level: u32, depth: u32, nodes: &mut Vec<Node>) -> Node {
+    let start = nodes.len();
+    nodes.extend([EMPTY; 8]);
+
+    let shift = 3 * (depth - level);
+    let mut rest = leaves;
+    for i in 0..8 {
+        let count = rest.partition_point(|(code, _)| (code >> shift) & 7 == i as u64);
+        let (child, remaining) = rest.split_at(count);
+        rest = remaining;
+
+        nodes[start + i] = if child.is_empty() {
+            EMPTY
+        } else if level == depth {
+            child[0].1
+        } else {
+            build_sorted(child, level + 1, depth, nodes)
+        };
+    }
+
+    collapse(nodes, start)
+}
+
+/// Replaces the group at the end of `nodes` with a leaf if its children are all the same voxel,
+/// otherwise returns a node pointing at it. References aren't merged, a bigger one would draw
+/// the block or chunk bigger.
+pub fn collapse(nodes: &mut Vec<Node>, group: usize) -> Node {
+    let children = &nodes[group..group + 8];
+    let first = children[0];
+    if children
+        .iter()
+        .all(|child| child.pointer == CHUNK_OFFSET && child.value == first.value)
+    {
+        nodes.truncate(group);
+        return first;
+    }
+
+    Node::new(group as u32, Voxel::new(0, 0, 0))
+}
+
+/// Average colour of the non empty nodes
+fn average_colour(nodes: &[Node]) -> Voxel {
+    let mut colour = Vector3::new(0.0, 0.0, 0.0);
+    let mut divisor = 0.0;
+
+    for node in nodes {
+        if node.value != Voxel::new(0, 0, 0) {
+            let voxel = node.value;
+            colour += Vector3::new(voxel.r as f32, voxel.g as f32, voxel.b as f32);
+            divisor += 1.0;
+        }
+    }
+
+    colour /= divisor;
+
+    Voxel::new(
+        (colour.x as u8).max(1),
+        (colour.y as u8).max(1),
+        (colour.z as u8).max(1),
+    )
+}
+
+/// Leaf that stands in for a group of solid leaves, the average colour if they are all voxels
+/// otherwise the most common reference
+fn representative(leaves: &[Node]) -> Node {
+    if leaves.iter().all(|leaf| leaf.pointer == CHUNK_OFFSET) {
+        return Node::new(CHUNK_OFFSET, average_colour(leaves));
+    }
+
+    *leaves
+        .iter()
+        .filter(|leaf| leaf.pointer > CHUNK_OFFSET)
+        .max_by_key(|leaf| {
+            let count = leaves.iter().filter(|l| l.pointer == leaf.pointer).count();
+            // Ties go to the lowest id
+            (count, std::cmp::Reverse(leaf.pointer))
+        })
+        .unwrap()
+}
+
+/// Position of child `i` within its parent
+pub fn child_offset(i: usize) -> Vector3<u32> {
+    Vector3::new((i as u32 >> 2) & 1, (i as u32 >> 1) & 1, i as u32 & 1)
+}
+
 impl std::fmt::Debug for Node {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let voxel = self.value;
@@ -309,3 +647,123 @@ impl std::fmt::Debug for CpuOctree {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(r: u8) -> Node {
+        Node::new(CHUNK_OFFSET, Voxel::new(r, 0, 0))
+    }
+
+    /// Two groups under the first two top level nodes, stored in either order
+    fn two_groups(swapped: bool) -> CpuOctree {
+        let (a, b) = if swapped { (16, 8) } else { (8, 16) };
+        let mut nodes = vec![leaf(0); 24];
+        nodes[0] = Node::new(a, Voxel::new(1, 1, 1));
+        nodes[1] = Node::new(b, Voxel::new(2, 2, 2));
+        nodes[2] = Node::new(CHUNK_OFFSET + BLOCK_STONE, Voxel::new(0, 0, 0));
+        for i in 0..8 {
+            nodes[a as usize + i] = leaf(10 + i as u8);
+            nodes[b as usize + i] = leaf(20 + i as u8);
+        }
+        CpuOctree {
+            nodes,
+            top_mip: Voxel::new(0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn content_hash_ignores_layout_and_mips() {
+        let octree = two_groups(false);
+        let mut swapped = two_groups(true);
+        assert_eq!(octree.content_hash(), swapped.content_hash());
+
+        swapped.nodes[0].value = Voxel::new(9, 9, 9);
+        assert_eq!(octree.content_hash(), swapped.content_hash());
+
+        swapped.nodes[20] = leaf(99);
+        assert_ne!(octree.content_hash(), swapped.content_hash());
+
+        // Manifests on disk store this, so it can't change between versions
+        assert_eq!(octree.content_hash(), 0x2c8381e6bc2c5884);
+    }
+
+    #[test]
+    fn canonicalize_orders_breadth_first() {
+        let octree = two_groups(false);
+        let mut swapped = two_groups(true);
+        swapped.canonicalize();
+        assert_eq!(format!("{:?}", swapped), format!("{:?}", octree));
+        assert_eq!(swapped.content_hash(), octree.content_hash());
+
+        // Already canonical so nothing moves
+        let mut again = two_groups(false);
+        again.canonicalize();
+        assert_eq!(format!("{:?}", again), format!("{:?}", octree));
+    }
+
+    #[test]
+    fn bin_layout() {
+        assert_eq!(std::mem::size_of::<Node>(), 8);
+
+        let mut node = Node::new(CHUNK_OFFSET + 3, Voxel::new(1, 2, 3));
+        node.coverage = 4;
+        let octree = CpuOctree {
+            nodes: vec![node; 8],
+            top_mip: Voxel::new(0, 0, 0),
+        };
+        let bin = unsafe { octree.bin() }.to_vec();
+        let mut expected = (CHUNK_OFFSET + 3).to_ne_bytes().to_vec();
+        expected.extend([1, 2, 3, 4]);
+        assert_eq!(bin[..8], expected);
+
+        let loaded = unsafe { CpuOctree::from_bin(bin) };
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", octree));
+        assert_eq!(loaded.nodes[7].coverage, 4);
+    }
+
+    #[test]
+    fn hollowing_keeps_the_surface() {
+        // A solid 8x8x8 cube with a different colour in every voxel so nothing collapses
+        let colour = |pos: Vector3<u32>| {
+            let channel = |c: u32| c as u8 * 30 + 10;
+            Voxel::new(channel(pos.x), channel(pos.y), channel(pos.z))
+        };
+        let mut leaves = Vec::new();
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let pos = Vector3::new(x, y, z);
+                    leaves.push((morton(pos, 3), Node::new(CHUNK_OFFSET, colour(pos))));
+                }
+            }
+        }
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        let mut octree = CpuOctree::from_sorted_leaves(&leaves, 3);
+
+        let (before, after) = octree.hollow(&|_| true);
+        // Only the 8 groups in the middle that don't touch the outside collapse
+        assert_eq!(after, before - 64);
+        assert_eq!(after, octree.nodes.len());
+
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let pos = Vector3::new(x, y, z);
+                    let (index, depth) = octree.lookup(pos, 3);
+                    let node = octree.nodes[index];
+                    assert_eq!(node.pointer, CHUNK_OFFSET);
+                    let inside = (0..3).all(|axis| (2..6).contains(&pos[axis]));
+                    if inside {
+                        assert_eq!(depth, 2);
+                        assert_ne!(node.value, Voxel::new(0, 0, 0));
+                    } else {
+                        assert_eq!(depth, 3);
+                        assert_eq!(node.value, colour(pos), "{:?}", pos);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use super::*;
+
+/// Which octree wins where both are solid
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Priority {
+    Existing,
+    Placed,
+}
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CsgOp {
+    Union(Priority),
+    /// Removes everything the placed octree fills
+    Subtract,
+    /// Keeps the existing leaves where the placed octree is also solid
+    Intersect,
+}
+
+/// A node of the existing octree, or a leaf of it that covers a bigger area
+#[derive(Copy, Clone)]
+enum Part {
+    Group(usize),
+    Leaf(Node),
+}
+
+/// What the placed octree has in a cube
+#[derive(Copy, Clone)]
+enum Sample {
+    Empty,
+    /// Inside a single solid leaf
+    Leaf(Node),
+    /// Exactly an internal node, the children start at the group
+    Group(usize),
+    /// Straddles leaves, the cube has to be split to line up
+    Mixed,
+}
+
+pub const EMPTY: Node = Node {
+    pointer: CHUNK_OFFSET,
+    value: Voxel { r: 0, g: 0, b: 0 },
+    coverage: 0,
+};
+
+pub fn is_solid(node: Node) -> bool {
+    node.pointer > CHUNK_OFFSET || node.value != Voxel::new(0, 0, 0)
+}
+
+struct Csg<'a> {
+    existing: &'a CpuOctree,
+    placed: &'a CpuOctree,
+    op: CsgOp,
+    offset: Vector3<i64>,
+    depth: u32,
+    nodes: Vec<Node>,
+}
+
+impl<'a> Csg<'a> {
+    /// Builds the node at integer `pos` and `level` of the result, the root is level 0
+    fn build(&mut self, a: Part, pos: Vector3<i64>, level: u32) -> Result<Node, String> {
+        let a_solid = match a {
+            Part::Leaf(node) => Some(is_solid(node)),
+            Part::Group(_) => None,
+        };
+        // Cases that don't depend on the placed octree, so references in it that don't line
+        // up here don't matter
+        match (self.op, a_solid) {
+            (CsgOp::Union(Priority::Existing), Some(true)) => return Ok(self.copy_existing(a)),
+            (CsgOp::Subtract | CsgOp::Intersect, Some(false)) => return Ok(EMPTY),
+            _ => {}
+        }
+
+        let b = self.sample(pos, level)?;
+        Ok(match (self.op, b, a_solid) {
+            (CsgOp::Intersect, Sample::Empty, _) => EMPTY,
+            (_, Sample::Empty, _) => self.copy_existing(a),
+            (CsgOp::Union(Priority::Placed), Sample::Leaf(node), _) => node,
+            (CsgOp::Subtract, Sample::Leaf(_), _) => EMPTY,
+            (CsgOp::Intersect, Sample::Leaf(_), _) => self.copy_existing(a),
+            (CsgOp::Union(_), _, Some(false)) => self.copy_placed(b, pos, level)?,
+            _ => self.split(a, pos, level)?,
+        })
+    }
+
+    fn split(&mut self, a: Part, pos: Vector3<i64>, level: u32) -> Result<Node, String> {
+        if let Part::Leaf(node) = a {
+            // Smaller copies of a block would look different
+            if node.pointer > CHUNK_OFFSET {
+                return Err(format!(
+                    "Only part of reference {} would be kept",
+                    node.pointer - CHUNK_OFFSET
+                ));
+            }
+        }
+
+        let group = self.nodes.len();
+        self.nodes.extend([EMPTY; 8]);
+        for i in 0..8 {
+            let child = match a {
+                Part::Group(group) => self.part(group + i),
+                Part::Leaf(node) => Part::Leaf(node),
+            };
+            let child_pos = pos * 2 + child_offset(i).cast::<i64>().unwrap();
+            self.nodes[group + i] = self.build(child, child_pos, level + 1)?;
+        }
+
+        Ok(collapse(&mut self.nodes, group))
+    }
+
+    fn part(&self, index: usize) -> Part {
+        let node = self.existing.nodes[index];
+        if node.pointer < CHUNK_OFFSET {
+            Part::Group(node.pointer as usize)
+        } else {
+            Part::Leaf(node)
+        }
+    }
+
+    fn copy_existing(&mut self, a: Part) -> Node {
+        match a {
+            Part::Leaf(node) => node,
+            Part::Group(group) => copy_group(self.existing, group, &mut self.nodes),
+        }
+    }
+
+    /// Used when the existing octree is empty here
+    fn copy_placed(&mut self, b: Sample, pos: Vector3<i64>, level: u32) -> Result<Node, String> {
+        Ok(match b {
+            Sample::Empty => EMPTY,
+            Sample::Leaf(node) => node,
+            Sample::Group(group) => copy_group(self.placed, group, &mut self.nodes),
+            Sample::Mixed => self.split(Part::Leaf(EMPTY), pos, level)?,
+        })
+    }
+
+    /// Looks up the cube of the result at `pos` and `level` in the placed octree. References
+    /// only line up with the cube when it's exactly their node.
+    fn sample(&self, pos: Vector3<i64>, level: u32) -> Result<Sample, String> {
+        // Integer coordinates at a level where the offset and the cube both line up
+        let fine = level.max(self.depth);
+        let size = 1i64 << (fine - level);
+        let lo = pos * size - self.offset * (1i64 << (fine - self.depth));
+
+        let full = 1i64 << fine;
+        if (0..3).any(|axis| lo[axis] >= full || lo[axis] + size <= 0) {
+            return Ok(Sample::Empty);
+        } else if (0..3).any(|axis| lo[axis] < 0 || lo[axis] + size > full) {
+            return Ok(Sample::Mixed);
+        } else if size == full {
+            return Ok(Sample::Group(0));
+        }
+
+        let mut group = 0;
+        for shift in (0..fine).rev() {
+            let child = lo.map(|c| c >> shift);
+            if (0..3).any(|axis| (lo[axis] + size - 1) >> shift != child[axis]) {
+                return Ok(Sample::Mixed);
+            }
+
+            let child_index = ((child.x & 1) * 4 + (child.y & 1) * 2 + (child.z & 1)) as usize;
+            let node = self.placed.nodes[group + child_index];
+            if node.pointer > CHUNK_OFFSET && size != 1 << shift {
+                return Err(format!(
+                    "Reference {} doesn't line up with the octree it's placed in",
+                    node.pointer - CHUNK_OFFSET
+                ));
+            } else if node.pointer >= CHUNK_OFFSET {
+                return Ok(if is_solid(node) {
+                    Sample::Leaf(node)
+                } else {
+                    Sample::Empty
+                });
+            } else if size == 1 << shift {
+                return Ok(Sample::Group(node.pointer as usize));
+            }
+
+            group = node.pointer as usize;
+        }
+
+        // The cube is smaller than a voxel at the fine level, can't happen
+        Ok(Sample::Mixed)
+    }
+}
+
+/// Appends the subtree of the group to `nodes`, returns a node pointing at it
+fn copy_group(octree: &CpuOctree, group: usize, nodes: &mut Vec<Node>) -> Node {
+    let start = nodes.len();
+    nodes.extend_from_slice(&octree.nodes[group..group + 8]);
+    for i in start..start + 8 {
+        if nodes[i].pointer < CHUNK_OFFSET {
+            nodes[i] = copy_group(octree, nodes[i].pointer as usize, nodes);
+        }
+    }
+
+    Node::new(start as u32, Voxel::new(0, 0, 0))
+}
+
+impl CpuOctree {
+    /// Combines `other` into a new octree without going voxel by voxel. `other` is the same
+    /// size as this octree, moved by `offset` nodes at `depth`, and anything of it that ends
+    /// up outside is cut off. Voxels that end up all the same are collapsed and the mips are
+    /// rebuilt with the mean filter. References are kept whole, it's an error for only part of
+    /// one to end up in the result.
+    pub fn csg(
+        &self,
+        other: &CpuOctree,
+        op: CsgOp,
+        offset: Vector3<i64>,
+        depth: u32,
+    ) -> Result<Self, String> {
+        let mut csg = Csg {
+            existing: self,
+            placed: other,
+            op,
+            offset,
+            depth,
+            nodes: Vec::new(),
+        };
+
+        let root = csg.split(Part::Group(0), Vector3::zero(), 0)?;
+        let mut octree = CpuOctree {
+            nodes: csg.nodes,
+            top_mip: self.top_mip,
+        };
+        if root.pointer >= CHUNK_OFFSET {
+            // Everything collapsed into one leaf, the top level has to stay a group
+            octree.nodes = vec![root; 8];
+        }
+
+        // References keep the colour they had in either octree
+        let mut ref_mips = self.ref_mips();
+        ref_mips.extend(other.ref_mips());
+        octree.generate_mips(
+            &|id| ref_mips.get(&id).copied().unwrap_or(Voxel::new(0, 0, 0)),
+            MipFilter::Mean,
+        );
+        Ok(octree)
+    }
+
+    pub fn union(
+        &self,
+        other: &CpuOctree,
+        offset: Vector3<i64>,
+        depth: u32,
+        priority: Priority,
+    ) -> Result<Self, String> {
+        self.csg(other, CsgOp::Union(priority), offset, depth)
+    }
+
+    #[allow(dead_code)]
+    pub fn subtract(
+        &self,
+        other: &CpuOctree,
+        offset: Vector3<i64>,
+        depth: u32,
+    ) -> Result<Self, String> {
+        self.csg(other, CsgOp::Subtract, offset, depth)
+    }
+
+    #[allow(dead_code)]
+    pub fn intersect(
+        &self,
+        other: &CpuOctree,
+        offset: Vector3<i64>,
+        depth: u32,
+    ) -> Result<Self, String> {
+        self.csg(other, CsgOp::Intersect, offset, depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Voxel = Voxel { r: 255, g: 0, b: 0 };
+    const BLUE: Voxel = Voxel { r: 0, g: 0, b: 255 };
+
+    fn voxels(positions: &[(u32, u32, u32)], colour: Voxel) -> CpuOctree {
+        let mut leaves: Vec<_> = positions
+            .iter()
+            .map(|&(x, y, z)| {
+                let node = Node::new(CHUNK_OFFSET, colour);
+                (morton(Vector3::new(x, y, z), 2), node)
+            })
+            .collect();
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, 2)
+    }
+
+    /// Solid voxels at depth 2 and their colours
+    fn solid(octree: &CpuOctree) -> Vec<((u32, u32, u32), Voxel)> {
+        let mut solid = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    let (index, _) = octree.lookup(Vector3::new(x, y, z), 2);
+                    let node = octree.nodes[index];
+                    if is_solid(node) {
+                        solid.push(((x, y, z), node.value));
+                    }
+                }
+            }
+        }
+        solid
+    }
+
+    #[test]
+    fn ops_on_small_trees() {
+        let existing = voxels(&[(0, 0, 0), (1, 0, 0)], RED);
+        // The far corner ends up outside and is cut off
+        let placed = voxels(&[(0, 0, 0), (3, 3, 3)], BLUE);
+        let offset = Vector3::new(1, 0, 0);
+
+        let union = existing
+            .union(&placed, offset, 2, Priority::Placed)
+            .unwrap();
+        assert_eq!(solid(&union), vec![((0, 0, 0), RED), ((1, 0, 0), BLUE)]);
+        let union = existing
+            .union(&placed, offset, 2, Priority::Existing)
+            .unwrap();
+        assert_eq!(solid(&union), vec![((0, 0, 0), RED), ((1, 0, 0), RED)]);
+
+        let subtract = existing.subtract(&placed, offset, 2).unwrap();
+        assert_eq!(solid(&subtract), vec![((0, 0, 0), RED)]);
+        let intersect = existing.intersect(&placed, offset, 2).unwrap();
+        assert_eq!(solid(&intersect), vec![((1, 0, 0), RED)]);
+
+        // Collapsed down to the top level, which stays a group
+        let nothing = existing.subtract(&existing, Vector3::zero(), 2).unwrap();
+        assert_eq!(nothing.nodes.len(), 8);
+        assert!(solid(&nothing).is_empty());
+    }
+
+    #[test]
+    fn references_stay_whole() {
+        let mut existing = CpuOctree::new(0);
+        existing.put_in_block(Vector3::new(-1.0, -1.0, -1.0), BLOCK_STONE, 1);
+        let placed = voxels(&[(0, 0, 0)], BLUE);
+
+        assert!(existing
+            .union(&placed, Vector3::zero(), 2, Priority::Placed)
+            .is_err());
+        assert!(existing.subtract(&placed, Vector3::zero(), 2).is_err());
+        let union = existing
+            .union(&placed, Vector3::zero(), 2, Priority::Existing)
+            .unwrap();
+        assert_eq!(union.nodes[0].pointer, CHUNK_OFFSET + BLOCK_STONE);
+
+        // Moving by a whole node at the reference's depth keeps it lined up
+        let moved = existing.translate(Vector3::new(1, 0, 0), 1).unwrap();
+        assert_eq!(moved.nodes[4].pointer, CHUNK_OFFSET + BLOCK_STONE);
+        assert!(existing.translate(Vector3::new(1, 0, 0), 2).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use super::*;
+use std::collections::HashSet;
+
+#[derive(Debug)]
+pub enum ProblemKind {
+    /// The node count isn't a multiple of 8
+    Truncated,
+    /// Child pointer that isn't the start of a group
+    Misaligned(u32),
+    OutOfBounds(u32),
+    /// Child pointer to a group that was already reached from another node
+    Cycle(u32),
+    /// Reference to a block or chunk that doesn't exist
+    MissingChunk(u32),
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub index: usize,
+    /// Node indices from the top level down to `index`
+    pub path: Vec<usize>,
+    pub kind: ProblemKind,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "node {}: ", self.index)?;
+        } else {
+            let path: Vec<String> = self.path.iter().map(|i| i.to_string()).collect();
+            write!(f, "node {} (path {}): ", self.index, path.join(" > "))?;
+        }
+        match self.kind {
+            ProblemKind::Truncated => write!(f, "node count isn't a multiple of 8"),
+            ProblemKind::Misaligned(pointer) => {
+                write!(f, "pointer {} isn't the start of a group", pointer)
+            }
+            ProblemKind::OutOfBounds(pointer) => write!(f, "pointer {} is out of bounds", pointer),
+            ProblemKind::Cycle(pointer) => write!(
+                f,
+                "group {} is already used (cycle or shared subtree)",
+                pointer
+            ),
+            ProblemKind::MissingChunk(id) => write!(f, "chunk {} doesn't exist", id),
+        }
+    }
+}
+
+impl CpuOctree {
+    /// Walks everything reachable from the root and reports every structural problem.
+    /// `chunk_exists` says whether a referenced block or chunk id can be loaded.
+    pub fn validate(&self, chunk_exists: &dyn Fn(u32) -> bool) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let len = self.nodes.len() / 8 * 8;
+        if len != self.nodes.len() || len == 0 {
+            problems.push(Problem {
+                index: len,
+                path: Vec::new(),
+                kind: ProblemKind::Truncated,
+            });
+            if len == 0 {
+                return problems;
+            }
+        }
+
+        // Node that points to each group, for building paths
+        let mut parents = vec![None; len / 8];
+        parents[0] = Some(usize::MAX);
+
+        let mut queue = std::collections::VecDeque::from([0]);
+        while let Some(group) = queue.pop_front() {
+            for index in group..group + 8 {
+                let pointer = self.nodes[index].pointer;
+                let kind = if pointer < CHUNK_OFFSET {
+                    let child = pointer as usize;
+                    if !child.is_multiple_of(8) {
+                        Some(ProblemKind::Misaligned(pointer))
+                    } else if child + 8 > len {
+                        Some(ProblemKind::OutOfBounds(pointer))
+                    } else if parents[child / 8].is_some() {
+                        Some(ProblemKind::Cycle(pointer))
+                    } else {
+                        parents[child / 8] = Some(index);
+                        queue.push_back(child);
+                        None
+                    }
+                } else if pointer > CHUNK_OFFSET && !chunk_exists(pointer - CHUNK_OFFSET) {
+                    Some(ProblemKind::MissingChunk(pointer - CHUNK_OFFSET))
+                } else {
+                    None
+                };
+
+                if let Some(kind) = kind {
+                    let mut path = vec![index];
+                    while let Some(Some(parent)) = parents.get(path[0] / 8) {
+                        if *parent == usize::MAX {
+                            break;
+                        }
+                        path.insert(0, *parent);
+                    }
+
+                    problems.push(Problem { index, path, kind });
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Prunes the subtrees with problems by replacing them with empty voxels and fixes the
+    /// mips above them. Takes the problems from `validate`.
+    pub fn repair(&mut self, problems: &[Problem], filter: MipFilter) {
+        let len = self.nodes.len() / 8 * 8;
+        if len == 0 {
+            *self = CpuOctree::new(0);
+            return;
+        }
+        self.nodes.truncate(len);
+
+        for problem in problems {
+            if let ProblemKind::Truncated = problem.kind {
+                continue;
+            }
+
+            self.nodes[problem.index] = Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0));
+            self.update_mips(&problem.path, filter);
+        }
+    }
+}
+
+impl World {
+    /// Checks every chunk file in a world folder and the references between them. Returns
+    /// (chunk id, problem) for everything found, and prunes them if `repair` is set.
+    pub fn fsck<S: AsRef<std::ffi::OsStr> + Sized>(
+        path: S,
+        repair: bool,
+    ) -> Result<Vec<(u32, Problem)>, String> {
+        let path = std::path::Path::new(&path);
+
+        // Chunks that can be loaded, files that aren't a whole number of nodes can't be
+        let mut files = HashSet::new();
+        let mut problems = Vec::new();
+        for entry in std::fs::read_dir(path).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let file_name = entry.file_name();
+            let id = match file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".bin"))
+                .and_then(|id| id.parse::<u32>().ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let size = entry.metadata().map_err(|e| e.to_string())?.len();
+            if size.is_multiple_of(std::mem::size_of::<Node>() as u64) {
+                files.insert(id);
+            } else {
+                problems.push((
+                    id,
+                    Problem {
+                        index: 0,
+                        path: Vec::new(),
+                        kind: ProblemKind::Truncated,
+                    },
+                ));
+            }
+        }
+
+        if !files.contains(&0) {
+            return Err("Root chunk is unreadable".to_string());
+        }
+
+        let world = World::load_world(path)?;
+
+        let chunk_exists = |id: u32| files.contains(&id) || world.chunks.contains_key(&id);
+
+        let mut ids: Vec<u32> = files.iter().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let file =
+                std::fs::read(path.join(format!("{}.bin", id))).map_err(|e| e.to_string())?;
+            let mut chunk = unsafe { CpuOctree::from_bin(file) };
+
+            let chunk_problems = chunk.validate(&chunk_exists);
+            if repair && !chunk_problems.is_empty() {
+                chunk.repair(&chunk_problems, world.mip_filter);
+                let data = unsafe { chunk.bin() };
+                std::fs::write(path.join(format!("{}.bin", id)), data)
+                    .map_err(|e| e.to_string())?;
+            }
+
+            problems.extend(chunk_problems.into_iter().map(|problem| (id, problem)));
+        }
+
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A good group under the first top level node and a misaligned pointer next to it
+    fn misaligned() -> CpuOctree {
+        let mut nodes = vec![Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0)); 16];
+        nodes[0] = Node::new(8, Voxel::new(0, 0, 0));
+        nodes[1] = Node::new(12, Voxel::new(0, 0, 0));
+        nodes[9] = Node::new(CHUNK_OFFSET, Voxel::new(100, 0, 0));
+        CpuOctree {
+            nodes,
+            top_mip: Voxel::new(0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn finds_every_kind() {
+        let mut octree = misaligned();
+        octree.nodes[2] = Node::new(800, Voxel::new(0, 0, 0));
+        octree.nodes[3] = Node::new(8, Voxel::new(0, 0, 0));
+        octree.nodes[10] = Node::new(CHUNK_OFFSET + 77, Voxel::new(0, 0, 0));
+        octree.nodes[11] = Node::new(CHUNK_OFFSET + BLOCK_STONE, Voxel::new(0, 0, 0));
+        octree
+            .nodes
+            .push(Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0)));
+
+        let problems = octree.validate(&|id| id == BLOCK_STONE);
+        let found: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            found,
+            vec![
+                "node 16: node count isn't a multiple of 8",
+                "node 1 (path 1): pointer 12 isn't the start of a group",
+                "node 2 (path 2): pointer 800 is out of bounds",
+                "node 3 (path 3): group 8 is already used (cycle or shared subtree)",
+                "node 10 (path 0 > 10): chunk 77 doesn't exist",
+            ]
+        );
+    }
+
+    #[test]
+    fn repairs_misaligned_pointer() {
+        let mut octree = misaligned();
+        let problems = octree.validate(&|_| true);
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(problems[0].kind, ProblemKind::Misaligned(12)));
+
+        octree.repair(&problems, MipFilter::Mean);
+        assert!(octree.validate(&|_| true).is_empty());
+        assert_eq!(octree.nodes[1].pointer, CHUNK_OFFSET);
+        assert_eq!(octree.nodes[9].value, Voxel::new(100, 0, 0));
+    }
+
+    #[test]
+    fn repairs_world_folder() {
+        let path = std::env::temp_dir().join(format!("fsck_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("0.bin"), unsafe { misaligned().bin() }).unwrap();
+
+        let problems = World::fsck(&path, true).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, 0);
+        let problems = World::fsck(&path, false).unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(problems.is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+use super::*;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Shared between the generator and whoever is watching it
+pub struct GenProgress {
+    total: AtomicU64,
+    done: AtomicU64,
+    cancelled: AtomicBool,
+    start: Instant,
+}
+
+impl Default for GenProgress {
+    fn default() -> Self {
+        Self {
+            total: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl GenProgress {
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self, chunks: u64) {
+        self.done.fetch_add(chunks, Ordering::Relaxed);
+    }
+
+    /// (done, total) in chunks
+    pub fn get(&self) -> (u64, u64) {
+        (
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let (done, total) = self.get();
+        if done == 0 {
+            return None;
+        }
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        Some(Duration::from_secs_f64(
+            elapsed / done as f64 * total.saturating_sub(done) as f64,
+        ))
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Generates a world on its own thread and gpu device so the app keeps rendering
+pub struct GenJob {
+    pub path: PathBuf,
+    pub progress: Arc<GenProgress>,
+    handle: Option<std::thread::JoinHandle<Result<(), String>>>,
+}
+
+impl GenJob {
+    pub fn start(path: PathBuf, gen_settings: GenSettings) -> Self {
+        let progress = Arc::new(GenProgress::default());
+
+        let handle = {
+            let path = path.clone();
+            let progress = progress.clone();
+            std::thread::spawn(move || {
+                let gpu = pollster::block_on(Gpu::headless())?;
+                let mut procedural = Procedural::new(&gpu);
+                World::generate_world(&path, &mut procedural, &gen_settings, &gpu, &progress)
+            })
+        };
+
+        Self {
+            path,
+            progress,
+            handle: Some(handle),
+        }
+    }
+
+    /// Returns the result once the job has finished
+    pub fn poll(&mut self) -> Option<Result<(), String>> {
+        if !self.handle.as_ref()?.is_finished() {
+            return None;
+        }
+
+        let result = self.handle.take()?.join();
+        Some(result.unwrap_or_else(|_| Err("World generation crashed".to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_counts_and_cancels() {
+        let progress = GenProgress::default();
+        progress.set_total(64);
+        assert_eq!(progress.get(), (0, 64));
+        assert!(progress.eta().is_none());
+
+        // Whole nodes proven empty count all their chunks at once
+        progress.inc(8);
+        progress.inc(1);
+        assert_eq!(progress.get(), (9, 64));
+
+        assert!(!progress.is_cancelled());
+        progress.cancel();
+        assert!(progress.is_cancelled());
+    }
+
+    #[test]
+    fn eta_scales_with_chunks_left() {
+        let progress = GenProgress {
+            start: Instant::now() - Duration::from_secs(10),
+            ..Default::default()
+        };
+        progress.set_total(10);
+        progress.inc(2);
+        // 5 seconds a chunk with 8 left
+        let eta = progress.eta().unwrap().as_secs_f64();
+        assert!((40.0..41.0).contains(&eta), "{}", eta);
+
+        progress.inc(8);
+        assert_eq!(progress.eta(), Some(Duration::ZERO));
+        // More done than expected doesn't go negative
+        progress.inc(1);
+        assert_eq!(progress.eta(), Some(Duration::ZERO));
+    }
+}
@@ -1,7 +1,8 @@
 use super::*;
 
 pub struct Gpu {
-    pub surface: wgpu::Surface,
+    /// None when running without a window
+    pub surface: Option<wgpu::Surface>,
     pub adapter: wgpu::Adapter,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
@@ -14,14 +15,29 @@ impl Gpu {
         let instance = wgpu::Instance::new(wgpu::Backends::all());
         let surface = unsafe { instance.create_surface(window) };
 
+        Self::from_instance(instance, Some(surface))
+            .await
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// For generating worlds without opening a window
+    pub async fn headless() -> Result<Self, String> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        Self::from_instance(instance, None).await
+    }
+
+    async fn from_instance(
+        instance: wgpu::Instance,
+        surface: Option<wgpu::Surface>,
+    ) -> Result<Self, String> {
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
                 power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
+                compatible_surface: surface.as_ref(),
                 force_fallback_adapter: false,
             })
             .await
-            .unwrap();
+            .ok_or("No suitable gpu adapter found")?;
 
         let (device, queue) = adapter
             .request_device(
@@ -36,15 +52,21 @@ impl Gpu {
                 None, // Trace path
             )
             .await
-            .unwrap();
+            .map_err(|e| format!("Couldn't open the gpu: {}", e))?;
 
         // println!("Info: {:?}", device.limits().max_storage_buffer_binding_size);
 
-        Self {
+        Ok(Self {
             surface,
             adapter,
             device,
             queue,
-        }
+        })
+    }
+
+    pub fn surface(&self) -> &wgpu::Surface {
+        self.surface
+            .as_ref()
+            .expect("Gpu was created without a window")
     }
 }
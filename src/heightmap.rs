@@ -0,0 +1,418 @@
+use super::*;
+use std::path::Path;
+
+/// Images a world is built from, the longest side of the heightmap fills the world
+pub struct Heightmap {
+    pub heights: Image,
+    /// Colours the surface voxels instead of the surface block of the biome
+    pub colours: Option<Image>,
+    /// The first channel is the index of the biome for each column
+    pub materials: Option<Image>,
+    /// Height of a white pixel as a fraction of the world height
+    pub vertical_scale: f32,
+}
+
+#[derive(Copy, Clone)]
+struct Column {
+    /// Number of solid voxels from the bottom of the world
+    top: u32,
+    biome: usize,
+    colour: Option<Voxel>,
+}
+
+impl Heightmap {
+    pub fn load(
+        heights: &Path,
+        colours: Option<&Path>,
+        materials: Option<&Path>,
+        vertical_scale: f32,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            heights: Image::load(heights)?,
+            colours: colours.map(Image::load).transpose()?,
+            materials: materials.map(Image::load).transpose()?,
+            vertical_scale,
+        })
+    }
+
+    /// The column at `x`, `z` of a world `size` voxels wide, None outside the heightmap
+    fn column(&self, x: u32, z: u32, size: u32, biomes: usize) -> Option<Column> {
+        let (width, height) = (self.heights.width as f32, self.heights.height as f32);
+        let longest = width.max(height);
+        let scale = longest / size as f32;
+        // Position in pixels, centred along the shorter side
+        let u = (x as f32 + 0.5) * scale - (longest - width) / 2.0;
+        let v = (z as f32 + 0.5) * scale - (longest - height) / 2.0;
+        if u < 0.0 || v < 0.0 || u >= width || v >= height {
+            return None;
+        }
+
+        // Blended between the centres of the 4 nearest pixels
+        let (fu, fv) = ((u - 0.5).max(0.0), (v - 0.5).max(0.0));
+        let (x0, y0) = (fu as usize, fv as usize);
+        let (x1, y1) = (
+            (x0 + 1).min(self.heights.width - 1),
+            (y0 + 1).min(self.heights.height - 1),
+        );
+        let (tu, tv) = (fu.fract(), fv.fract());
+        let top_row = self.heights.grey(x0, y0) * (1.0 - tu) + self.heights.grey(x1, y0) * tu;
+        let bottom_row = self.heights.grey(x0, y1) * (1.0 - tu) + self.heights.grey(x1, y1) * tu;
+        let h = top_row * (1.0 - tv) + bottom_row * tv;
+
+        // Nearest pixel of the other maps, which don't have to be the same size
+        let nearest = |image: &Image| {
+            (
+                ((u / width * image.width as f32) as usize).min(image.width - 1),
+                ((v / height * image.height as f32) as usize).min(image.height - 1),
+            )
+        };
+
+        let top = 1 + (h * self.vertical_scale * (size - 1) as f32).round() as u32;
+        Some(Column {
+            top: top.min(size),
+            biome: self.materials.as_ref().map_or(0, |materials| {
+                let (x, y) = nearest(materials);
+                materials.value(x, y) as usize % biomes
+            }),
+            colour: self.colours.as_ref().map(|colours| {
+                let (x, y) = nearest(colours);
+                non_black(colours.colour(x, y))
+            }),
+        })
+    }
+}
+
+/// Columns under one chunk of the world
+struct ChunkColumns<'a> {
+    gen_settings: &'a GenSettings,
+    columns: Vec<Option<Column>>,
+    /// Highest top under each node of every level, level 0 is the whole chunk
+    max_tops: Vec<Vec<u32>>,
+}
+
+impl<'a> ChunkColumns<'a> {
+    fn new(gen_settings: &'a GenSettings, columns: Vec<Option<Column>>) -> Self {
+        let depth = gen_settings.chunk_depth;
+        let mut max_tops = vec![columns
+            .iter()
+            .map(|column| column.map_or(0, |column| column.top))
+            .collect::<Vec<u32>>()];
+        for level in (0..depth).rev() {
+            let size = 1usize << level;
+            let finer = max_tops.last().unwrap();
+            let tops = (0..size * size)
+                .map(|i| {
+                    let (x, z) = (i / size * 2, i % size * 2);
+                    [(0, 0), (0, 1), (1, 0), (1, 1)]
+                        .iter()
+                        .map(|(dx, dz)| finer[(x + dx) * size * 2 + z + dz])
+                        .max()
+                        .unwrap()
+                })
+                .collect();
+            max_tops.push(tops);
+        }
+        max_tops.reverse();
+
+        Self {
+            gen_settings,
+            columns,
+            max_tops,
+        }
+    }
+
+    /// Block for the voxel `depth` voxels under the top of the column
+    fn layer(&self, column: Column, depth: u32) -> Node {
+        let biome = &self.gen_settings.biomes[column.biome];
+        let block = if depth as f32 > self.gen_settings.deep_depth {
+            self.gen_settings.deep_block
+        } else if depth == 0 {
+            if let Some(colour) = column.colour {
+                return Node::new(CHUNK_OFFSET, colour);
+            }
+            biome.surface
+        } else if depth <= biome.subsurface_depth {
+            biome.subsurface
+        } else {
+            biome.base
+        };
+        Node::new(CHUNK_OFFSET + block, Voxel::new(0, 0, 0))
+    }
+
+    /// Builds the node at `pos` and `level` of the chunk, `base_y` is the height of the
+    /// bottom of the chunk in the world
+    fn build(&self, nodes: &mut Vec<Node>, pos: Vector3<u32>, level: u32, base_y: u32) -> Node {
+        let depth = self.gen_settings.chunk_depth;
+        let y = base_y + (pos.y << (depth - level));
+        let max_top = self.max_tops[level as usize][(pos.x << level | pos.z) as usize];
+        if y >= max_top {
+            return EMPTY;
+        }
+
+        if level == depth {
+            let column = self.columns[(pos.x << depth | pos.z) as usize].unwrap();
+            return self.layer(column, column.top - 1 - y);
+        }
+
+        let start = nodes.len();
+        nodes.extend([EMPTY; 8]);
+        for i in 0..8 {
+            let child_pos = pos * 2 + child_offset(i);
+            nodes[start + i] = self.build(nodes, child_pos, level + 1, base_y);
+        }
+        collapse(nodes, start)
+    }
+}
+
+impl World {
+    /// Builds a world into the folder at `path` with the same chunks as `generate_world`.
+    /// Columns get the layers of their biome from the top down, and `deep_block` deeper than
+    /// `deep_depth`.
+    pub fn import_heightmap<S: AsRef<std::ffi::OsStr> + Sized>(
+        path: S,
+        heightmap: &Heightmap,
+        gen_settings: &GenSettings,
+    ) -> Result<(), String> {
+        if gen_settings.world_depth == 0 || gen_settings.chunk_depth == 0 {
+            return Err("World and chunk depth have to be at least 1".to_string());
+        } else if gen_settings.world_depth > MAX_WORLD_DEPTH {
+            return Err(format!(
+                "World depth can't be more than {}",
+                MAX_WORLD_DEPTH
+            ));
+        } else if gen_settings.world_depth + gen_settings.chunk_depth > 24 {
+            return Err("World and chunk depth can't add up to more than 24".to_string());
+        } else if gen_settings.biomes.is_empty() {
+            return Err("There has to be at least one biome".to_string());
+        }
+
+        let path = std::path::Path::new(&path);
+        if path.exists() {
+            return Err("File already exists".to_string());
+        }
+        std::fs::create_dir(path).map_err(|e| e.to_string())?;
+
+        let world_size = 1u32 << gen_settings.world_depth;
+        let chunk_size = 1u32 << gen_settings.chunk_depth;
+        let size = world_size * chunk_size;
+        let chunk_width = 2.0 / world_size as f32;
+
+        let mut world = World::new(path.to_str().unwrap().to_string());
+        world.mip_filter = gen_settings.mip_filter;
+        let opaque_blocks = world.opaque_blocks();
+        let mut root = CpuOctree::new(0);
+
+        for x in 0..world_size {
+            for z in 0..world_size {
+                let mut columns = Vec::with_capacity((chunk_size * chunk_size) as usize);
+                for cx in 0..chunk_size {
+                    for cz in 0..chunk_size {
+                        columns.push(heightmap.column(
+                            x * chunk_size + cx,
+                            z * chunk_size + cz,
+                            size,
+                            gen_settings.biomes.len(),
+                        ));
+                    }
+                }
+                let columns = ChunkColumns::new(gen_settings, columns);
+                let min_top = columns
+                    .columns
+                    .iter()
+                    .map(|column| column.map_or(0, |column| column.top))
+                    .min()
+                    .unwrap();
+
+                for y in 0..world_size {
+                    let pos = Vector3::new(x, y, z);
+                    let corner =
+                        pos.cast::<f32>().unwrap() * chunk_width - Vector3::new(1.0, 1.0, 1.0);
+                    let base_y = y * chunk_size;
+                    if base_y >= columns.max_tops[0][0] {
+                        continue;
+                    }
+
+                    // Chunks that are all deep layer are left as one big block, like the chunks
+                    // the generator proves to be full
+                    let shallowest = min_top as i64 - (base_y + chunk_size) as i64;
+                    if shallowest as f32 > gen_settings.deep_depth {
+                        root.put_in_block(
+                            corner,
+                            gen_settings.deep_block,
+                            gen_settings.world_depth,
+                        );
+                        continue;
+                    }
+
+                    let mut nodes = Vec::new();
+                    let node = columns.build(&mut nodes, Vector3::zero(), 0, base_y);
+                    if node.pointer >= CHUNK_OFFSET {
+                        nodes = vec![node; 8];
+                    }
+                    let mut chunk = CpuOctree {
+                        nodes,
+                        top_mip: Voxel::new(0, 0, 0),
+                    };
+
+                    if gen_settings.hollow {
+                        let (before, after) = chunk.hollow(&|id| opaque_blocks.contains(&id));
+                        println!("Hollowed chunk from {} to {} nodes", before, after);
+                    }
+                    println!(
+                        "({}, {}, {}): {} million",
+                        x,
+                        y,
+                        z,
+                        chunk.nodes.len() as f32 / 1000000.0
+                    );
+
+                    let index = CHUNK_OFFSET / 2 + (x * world_size + y) * world_size + z;
+                    world.chunks.insert(index, chunk);
+                    world.generate_mip_tree(index)?;
+                    world.save_chunk(index);
+                    world.chunks.get_mut(&index).unwrap().nodes = Vec::new(); // To free the ram while keeping the top_mip
+                    root.put_in_block(corner, index, gen_settings.world_depth);
+                }
+            }
+        }
+
+        // The chunks can't be regenerated from the settings so only the root is listed
+        let mut manifest = Manifest::new(gen_settings.clone());
+        manifest.root_hash = Some(root.content_hash());
+        manifest.imported = true;
+        manifest.save(path)?;
+
+        world.chunks.insert(0, root);
+        world.generate_mip_tree(0)?;
+        world.save_chunk(0);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(width: usize, height: usize, samples: Vec<u16>) -> Image {
+        Image {
+            width,
+            height,
+            channels: 1,
+            max: 255,
+            samples,
+        }
+    }
+
+    #[test]
+    fn columns_are_blended_and_centred() {
+        // Twice as wide as it's high so it's centred along z
+        let heightmap = Heightmap {
+            heights: grey(2, 1, vec![0, 255]),
+            colours: None,
+            materials: None,
+            vertical_scale: 1.0,
+        };
+        let tops: Vec<Option<u32>> = (0..4)
+            .map(|x| heightmap.column(x, 1, 4, 1).map(|column| column.top))
+            .collect();
+        // Heights of 0, 0.25, 0.75 and 1 between the pixel centres
+        assert_eq!(tops, [Some(1), Some(2), Some(3), Some(4)]);
+        assert!(heightmap.column(1, 0, 4, 1).is_none());
+        assert_eq!(heightmap.column(1, 2, 4, 1).unwrap().top, 2);
+        assert!(heightmap.column(1, 3, 4, 1).is_none());
+
+        let heightmap = Heightmap {
+            vertical_scale: 0.5,
+            materials: Some(grey(1, 1, vec![4])),
+            colours: Some(grey(1, 1, vec![0])),
+            ..heightmap
+        };
+        let column = heightmap.column(3, 1, 4, 3).unwrap();
+        assert_eq!(column.top, 3);
+        assert_eq!(column.biome, 1);
+        // Black would be empty
+        assert_eq!(column.colour, Some(Voxel::new(1, 1, 1)));
+    }
+
+    #[test]
+    fn layers_go_down_the_biome() {
+        let gen_settings = GenSettings {
+            chunk_depth: 1,
+            ..Default::default()
+        };
+        let column = Column {
+            top: 2,
+            biome: 0,
+            colour: None,
+        };
+        let columns = ChunkColumns::new(&gen_settings, vec![Some(column); 4]);
+        let block = |column, depth| columns.layer(column, depth).pointer - CHUNK_OFFSET;
+
+        // Meadow is grass over 3 dirt over stone
+        assert_eq!(block(column, 0), BLOCK_GRASS);
+        assert_eq!(block(column, 1), BLOCK_DIRT);
+        assert_eq!(block(column, 3), BLOCK_DIRT);
+        assert_eq!(block(column, 4), BLOCK_STONE);
+        assert_eq!(block(column, 40), BLOCK_STONE);
+        assert_eq!(block(column, 41), BLOCK_SLATE);
+
+        let rocky = Column { biome: 1, ..column };
+        assert_eq!(block(rocky, 1), BLOCK_STONE);
+
+        let coloured = Column {
+            colour: Some(Voxel::new(10, 20, 30)),
+            ..column
+        };
+        let surface = columns.layer(coloured, 0);
+        assert_eq!(surface.pointer, CHUNK_OFFSET);
+        assert_eq!(surface.value, Voxel::new(10, 20, 30));
+        assert_eq!(block(coloured, 1), BLOCK_DIRT);
+    }
+
+    #[test]
+    fn imports_a_small_heightmap() {
+        let path = std::env::temp_dir().join(format!("heightmap_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        // Only the corner at high x and low z is tall enough to reach the upper chunks
+        let heightmap = Heightmap {
+            heights: grey(2, 2, vec![0, 255, 0, 0]),
+            colours: None,
+            materials: None,
+            vertical_scale: 1.0,
+        };
+        let gen_settings = GenSettings {
+            world_depth: 1,
+            chunk_depth: 2,
+            ..Default::default()
+        };
+        World::import_heightmap(&path, &heightmap, &gen_settings).unwrap();
+
+        let world = World::new(path.to_str().unwrap().to_string());
+        let mut ids: Vec<u32> = world.chunk_files().unwrap().into_keys().collect();
+        ids.sort_unstable();
+        let chunk = |x: u32, y: u32, z: u32| CHUNK_OFFSET / 2 + (x * 2 + y) * 2 + z;
+        assert_eq!(
+            ids,
+            [
+                0,
+                chunk(0, 0, 0),
+                chunk(0, 0, 1),
+                chunk(1, 0, 0),
+                chunk(1, 0, 1),
+                chunk(1, 1, 0)
+            ]
+        );
+
+        let manifest = Manifest::load(&path).unwrap();
+        assert!(manifest.imported);
+        assert!(manifest.root_hash.is_some());
+        assert_eq!(manifest.gen_settings.chunk_depth, 2);
+
+        // Never into an existing folder
+        assert!(World::import_heightmap(&path, &heightmap, &gen_settings).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
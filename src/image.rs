@@ -0,0 +1,288 @@
+use super::*;
+use std::path::Path;
+
+/// Pixels of a .png, .pgm or .ppm file, row by row with the channels of each pixel together
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    /// Value of a full channel, 255 or 65535 for 16 bit images
+    pub max: u16,
+    pub samples: Vec<u16>,
+}
+
+impl Image {
+    pub fn load(path: &Path) -> Result<Image, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let image = match path.extension().and_then(std::ffi::OsStr::to_str) {
+            Some("png") => Image::load_png(&data),
+            Some("pgm" | "ppm" | "pnm") => Image::load_pnm(&data),
+            _ => Err("Unknown image type".to_string()),
+        };
+        image.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn load_png(data: &[u8]) -> Result<Image, String> {
+        let mut decoder = png::Decoder::new(data);
+        // Palettes become rgb and grey under 8 bits becomes 8 bits
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+        buffer.truncate(info.buffer_size());
+
+        let samples = match info.bit_depth {
+            png::BitDepth::Sixteen => buffer
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect(),
+            _ => buffer.iter().map(|&sample| sample as u16).collect(),
+        };
+        Ok(Image {
+            width: info.width as usize,
+            height: info.height as usize,
+            channels: info.color_type.samples(),
+            max: match info.bit_depth {
+                png::BitDepth::Sixteen => u16::MAX,
+                _ => 255,
+            },
+            samples,
+        })
+    }
+
+    /// P2 and P3 are text, P5 and P6 are binary with 2 big endian bytes per sample when the
+    /// max is over 255
+    fn load_pnm(data: &[u8]) -> Result<Image, String> {
+        let mut pos = 0;
+        let mut word = || -> Result<String, String> {
+            loop {
+                match data.get(pos) {
+                    Some(b'#') => {
+                        while data.get(pos).is_some_and(|&c| c != b'\n') {
+                            pos += 1;
+                        }
+                    }
+                    Some(c) if c.is_ascii_whitespace() => pos += 1,
+                    Some(_) => break,
+                    None => return Err("Image is truncated".to_string()),
+                }
+            }
+
+            let start = pos;
+            while data.get(pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+                pos += 1;
+            }
+            Ok(String::from_utf8_lossy(&data[start..pos]).to_string())
+        };
+
+        let magic = word()?;
+        let (channels, binary) = match magic.as_str() {
+            "P2" => (1, false),
+            "P3" => (3, false),
+            "P5" => (1, true),
+            "P6" => (3, true),
+            _ => return Err(format!("Unsupported image format '{}'", magic)),
+        };
+        let width: usize = parse(&word()?)?;
+        let height: usize = parse(&word()?)?;
+        let max: u16 = parse(&word()?)?;
+        if max == 0 {
+            return Err("Image has a max of 0".to_string());
+        }
+
+        let count = width * height * channels;
+        let samples = if binary {
+            // A single whitespace character comes before the data
+            let start = pos + 1;
+            let size = if max > 255 { 2 } else { 1 };
+            let bytes = data
+                .get(start..start + count * size)
+                .ok_or("Image is truncated")?;
+            if size == 2 {
+                bytes
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect()
+            } else {
+                bytes.iter().map(|&sample| sample as u16).collect()
+            }
+        } else {
+            (0..count)
+                .map(|_| word().and_then(|word| parse(&word)))
+                .collect::<Result<Vec<u16>, String>>()?
+        };
+
+        Ok(Image {
+            width,
+            height,
+            channels,
+            max,
+            samples,
+        })
+    }
+
+    /// Writes a .png, or a binary .pgm or .ppm for grey and rgb images
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let bytes: Vec<u8> = if self.max > 255 {
+            self.samples.iter().flat_map(|s| s.to_be_bytes()).collect()
+        } else {
+            self.samples.iter().map(|&s| s as u8).collect()
+        };
+
+        let data = match path.extension().and_then(std::ffi::OsStr::to_str) {
+            Some("png") => {
+                let mut data = Vec::new();
+                let mut encoder =
+                    png::Encoder::new(&mut data, self.width as u32, self.height as u32);
+                encoder.set_color(match self.channels {
+                    1 => png::ColorType::Grayscale,
+                    2 => png::ColorType::GrayscaleAlpha,
+                    3 => png::ColorType::Rgb,
+                    _ => png::ColorType::Rgba,
+                });
+                encoder.set_depth(if self.max > 255 {
+                    png::BitDepth::Sixteen
+                } else {
+                    png::BitDepth::Eight
+                });
+                let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+                writer.write_image_data(&bytes).map_err(|e| e.to_string())?;
+                writer.finish().map_err(|e| e.to_string())?;
+                data
+            }
+            Some("pgm" | "ppm" | "pnm") => {
+                let magic = match self.channels {
+                    1 => "P5",
+                    3 => "P6",
+                    _ => {
+                        return Err(
+                            "Only grey and rgb images can be saved as .pgm or .ppm".to_string()
+                        )
+                    }
+                };
+                let mut data = format!("{}\n{} {}\n{}\n", magic, self.width, self.height, self.max)
+                    .into_bytes();
+                data.extend(bytes);
+                data
+            }
+            _ => return Err("Unknown image type".to_string()),
+        };
+
+        std::fs::write(path, data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> &[u16] {
+        let start = (y * self.width + x) * self.channels;
+        &self.samples[start..start + self.channels]
+    }
+
+    /// Brightness from 0 to 1, colour images use the average of red, green and blue
+    pub fn grey(&self, x: usize, y: usize) -> f32 {
+        let pixel = self.pixel(x, y);
+        let colours = if self.channels >= 3 { 3 } else { 1 };
+        let sum: f32 = pixel[..colours].iter().map(|&c| c as f32).sum();
+        sum / colours as f32 / self.max as f32
+    }
+
+    /// The first channel as it's stored, for images of indices
+    pub fn value(&self, x: usize, y: usize) -> u16 {
+        self.pixel(x, y)[0]
+    }
+
+    pub fn colour(&self, x: usize, y: usize) -> Voxel {
+        let pixel = self.pixel(x, y);
+        let channel = |i: usize| {
+            let c = pixel[if self.channels >= 3 { i } else { 0 }];
+            (c as f32 / self.max as f32 * 255.0).round() as u8
+        };
+        Voxel::new(channel(0), channel(1), channel(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_pnm() {
+        let image = Image::load_pnm(b"P2\n# comment\n3 2\n15\n0 5 10\n15 3 6\n").unwrap();
+        assert_eq!((image.width, image.height, image.channels), (3, 2, 1));
+        assert_eq!(image.max, 15);
+        assert_eq!(image.samples, [0, 5, 10, 15, 3, 6]);
+        assert_eq!(image.grey(1, 0), 5.0 / 15.0);
+        assert_eq!(image.value(0, 1), 15);
+
+        let mut data = b"P5 2 2 255\n".to_vec();
+        data.extend([0, 64, 128, 255]);
+        let image = Image::load_pnm(&data).unwrap();
+        assert_eq!((image.width, image.height, image.channels), (2, 2, 1));
+        assert_eq!(image.samples, [0, 64, 128, 255]);
+        assert_eq!(image.colour(0, 1), Voxel::new(128, 128, 128));
+
+        // 16 bit samples are big endian
+        let mut data = b"P5\n2 1\n65535\n".to_vec();
+        data.extend([0x12, 0x34, 0xff, 0xff]);
+        let image = Image::load_pnm(&data).unwrap();
+        assert_eq!(image.max, u16::MAX);
+        assert_eq!(image.samples, [0x1234, 0xffff]);
+        assert_eq!(image.grey(1, 0), 1.0);
+
+        let image = Image::load_pnm(b"P3 1 1 255 10 20 30").unwrap();
+        assert_eq!(image.colour(0, 0), Voxel::new(10, 20, 30));
+
+        assert!(Image::load_pnm(b"P5\n2 2\n255\n\x00\x01\x02").is_err());
+        assert!(Image::load_pnm(b"P2 2 1 255 0").is_err());
+        assert!(Image::load_pnm(b"P2 1 1 0 0").is_err());
+        assert!(Image::load_pnm(b"P4 1 1 1").is_err());
+    }
+
+    #[test]
+    fn saved_images_load_the_same() {
+        let path = std::env::temp_dir().join(format!("image_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+
+        let rgb = Image {
+            width: 3,
+            height: 2,
+            channels: 3,
+            max: 255,
+            samples: (0..18).map(|i| i * 14).collect(),
+        };
+        let grey = Image {
+            width: 2,
+            height: 3,
+            channels: 1,
+            max: u16::MAX,
+            samples: vec![0, 1, 300, 0x1234, 0xff00, 0xffff],
+        };
+        for (image, name) in [
+            (&rgb, "rgb.png"),
+            (&rgb, "rgb.ppm"),
+            (&grey, "grey.png"),
+            (&grey, "grey.pgm"),
+        ] {
+            image.save(&path.join(name)).unwrap();
+            let loaded = Image::load(&path.join(name)).unwrap();
+            assert_eq!(
+                (loaded.width, loaded.height, loaded.channels, loaded.max),
+                (image.width, image.height, image.channels, image.max),
+                "{}",
+                name
+            );
+            assert_eq!(loaded.samples, image.samples, "{}", name);
+        }
+
+        let grey_alpha = Image {
+            channels: 2,
+            samples: vec![0; 12],
+            ..rgb
+        };
+        assert!(grey_alpha.save(&path.join("grey_alpha.pgm")).is_err());
+        assert!(grey.save(&path.join("grey.bmp")).is_err());
+        std::fs::write(path.join("grey.bmp"), b"BM").unwrap();
+        assert!(Image::load(&path.join("grey.bmp")).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -9,32 +9,82 @@ use winit::{
 
 mod adaptive;
 mod app;
+mod cli;
 mod compute;
+mod convert;
 mod cpu_octree;
+mod csg;
+mod fsck;
+mod gen_job;
 mod gpu;
+mod heightmap;
+mod image;
+mod manifest;
+mod mesh;
+mod meshing;
+mod minecraft;
+mod mip;
 mod octree;
+mod point_cloud;
 mod procedural;
+mod query;
+mod raycast;
 mod render;
+mod resample;
+mod slice;
+mod stats;
+mod transform;
+mod volume;
+mod walk;
 mod world;
 use adaptive::*;
 use app::*;
+use cli::*;
 use compute::*;
 use cpu_octree::*;
+use csg::*;
+use fsck::*;
+use gen_job::*;
 use gpu::*;
+use heightmap::*;
+use image::*;
+use manifest::*;
+use mesh::*;
+use meshing::*;
+use minecraft::*;
+use mip::*;
 use octree::*;
 use procedural::*;
 use render::*;
+use slice::*;
+use transform::*;
+use volume::*;
+use walk::*;
 use world::*;
 
 #[tokio::main]
 async fn main() {
+    env_logger::init();
+
+    let cli = <Cli as clap::Parser>::parse();
+    let (path, octree_depth) = match cli.command {
+        None => (None, 12),
+        Some(Command::View { path, depth }) => (path, depth),
+        Some(command) => {
+            if let Err(e) = command.run() {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    };
+
     println!("octree-tracer v0.1.0");
 
-    env_logger::init();
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
-    let mut app = pollster::block_on(App::new(&window));
+    let mut app = pollster::block_on(App::new(&window, path, octree_depth));
 
     let now = Instant::now();
     event_loop.run(move |event, _, control_flow| {
@@ -114,6 +164,8 @@ impl Input {
 
 pub struct Settings {
     octree_depth: u32,
+    /// Collapse the hidden inside of opened files
+    hollow: bool,
     fov: f32,
     sensitivity: f32,
 }
@@ -0,0 +1,210 @@
+use super::*;
+use std::path::Path;
+
+pub const MANIFEST_FILE: &str = "manifest.txt";
+
+pub struct ChunkEntry {
+    pub id: u32,
+    /// Position in the grid of chunks at the world depth
+    pub pos: Vector3<u32>,
+    pub hash: u64,
+}
+
+/// Everything needed to regenerate a world, stored next to the chunks as plain text
+pub struct Manifest {
+    pub gen_settings: GenSettings,
+    /// None until the world has finished generating
+    pub root_hash: Option<u64>,
+    /// Made from something other than `gen_settings`, like a heightmap, so the chunks can't be
+    /// regenerated
+    pub imported: bool,
+    pub chunks: Vec<ChunkEntry>,
+    /// Chunks that came out empty, they have no file
+    pub empty_chunks: Vec<u32>,
+}
+
+impl Manifest {
+    pub fn new(gen_settings: GenSettings) -> Self {
+        Self {
+            gen_settings,
+            root_hash: None,
+            imported: false,
+            chunks: Vec::new(),
+            empty_chunks: Vec::new(),
+        }
+    }
+
+    pub fn save(&self, world_path: &Path) -> Result<(), String> {
+        let mut out = String::new();
+        out += "# octree-tracer world manifest\n";
+        self.gen_settings.write_manifest(&mut out);
+        if self.imported {
+            out += "imported\n";
+        }
+        if let Some(root_hash) = self.root_hash {
+            out += &format!("root {:016x}\n", root_hash);
+        }
+        for chunk in &self.chunks {
+            out += &chunk_line(chunk);
+        }
+        for id in &self.empty_chunks {
+            out += &format!("empty {}\n", id);
+        }
+
+        std::fs::write(world_path.join(MANIFEST_FILE), out).map_err(|e| e.to_string())
+    }
+
+    /// Records a finished chunk without rewriting the whole manifest
+    pub fn add_chunk(&mut self, world_path: &Path, chunk: ChunkEntry) -> Result<(), String> {
+        append_line(world_path, &chunk_line(&chunk))?;
+        self.chunks.push(chunk);
+        Ok(())
+    }
+
+    /// Records a chunk that came out empty so a resumed run doesn't generate it again
+    pub fn add_empty_chunk(&mut self, world_path: &Path, id: u32) -> Result<(), String> {
+        append_line(world_path, &format!("empty {}\n", id))?;
+        self.empty_chunks.push(id);
+        Ok(())
+    }
+
+    pub fn load(world_path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(world_path.join(MANIFEST_FILE))
+            .map_err(|e| format!("Failed to read manifest: {}", e))?;
+
+        let mut manifest = Manifest::new(GenSettings {
+            biomes: Vec::new(),
+            caves: Vec::new(),
+            ..Default::default()
+        });
+
+        for (i, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let result = match words.as_slice() {
+                [] => Ok(()),
+                [comment, ..] if comment.starts_with('#') => Ok(()),
+                ["gen", rest @ ..] => manifest.gen_settings.read_manifest(rest),
+                ["imported"] => {
+                    manifest.imported = true;
+                    Ok(())
+                }
+                ["empty", id] => parse(id).map(|id| manifest.empty_chunks.push(id)),
+                ["root", hash] => parse_hash(hash).map(|h| manifest.root_hash = Some(h)),
+                ["chunk", id, x, y, z, hash] => (|| {
+                    manifest.chunks.push(ChunkEntry {
+                        id: parse(id)?,
+                        pos: Vector3::new(parse(x)?, parse(y)?, parse(z)?),
+                        hash: parse_hash(hash)?,
+                    });
+                    Ok(())
+                })(),
+                _ => Err("Unknown entry".to_string()),
+            };
+
+            result.map_err(|e| format!("Manifest line {}: {}", i + 1, e))?;
+        }
+
+        Ok(manifest)
+    }
+}
+
+fn append_line(world_path: &Path, line: &str) -> Result<(), String> {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(world_path.join(MANIFEST_FILE))
+        .map_err(|e| e.to_string())?;
+    file.write_all(line.as_bytes()).map_err(|e| e.to_string())
+}
+
+fn chunk_line(chunk: &ChunkEntry) -> String {
+    format!(
+        "chunk {} {} {} {} {:016x}\n",
+        chunk.id, chunk.pos.x, chunk.pos.y, chunk.pos.z, chunk.hash
+    )
+}
+
+pub fn parse<T: std::str::FromStr>(word: &str) -> Result<T, String> {
+    word.parse()
+        .map_err(|_| format!("Couldn't parse '{}'", word))
+}
+
+fn parse_hash(word: &str) -> Result<u64, String> {
+    u64::from_str_radix(word, 16).map_err(|_| format!("Couldn't parse hash '{}'", word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_roundtrip() {
+        let path = std::env::temp_dir().join(format!("manifest_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+
+        let mut manifest = Manifest::new(GenSettings {
+            seed: 1234,
+            world_depth: 3,
+            hollow: true,
+            ..Default::default()
+        });
+        manifest.save(&path).unwrap();
+        manifest
+            .add_chunk(
+                &path,
+                ChunkEntry {
+                    id: CHUNK_OFFSET / 2 + 5,
+                    pos: Vector3::new(0, 1, 1),
+                    hash: 0xfedcba9876543210,
+                },
+            )
+            .unwrap();
+        manifest
+            .add_empty_chunk(&path, CHUNK_OFFSET / 2 + 6)
+            .unwrap();
+        manifest.root_hash = Some(42);
+        manifest.imported = true;
+        let saved = std::fs::read_to_string(path.join(MANIFEST_FILE)).unwrap();
+        manifest.save(&path).unwrap();
+
+        let loaded = Manifest::load(&path).unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(saved.contains("chunk"));
+        assert!(saved.contains("empty"));
+        assert_eq!(loaded.empty_chunks, [CHUNK_OFFSET / 2 + 6]);
+        assert_eq!(loaded.root_hash, Some(42));
+        assert!(loaded.imported);
+        assert_eq!(loaded.chunks.len(), 1);
+        let chunk = &loaded.chunks[0];
+        assert_eq!(
+            (chunk.id, chunk.pos, chunk.hash),
+            (
+                CHUNK_OFFSET / 2 + 5,
+                Vector3::new(0, 1, 1),
+                0xfedcba9876543210
+            )
+        );
+
+        let mut original = String::new();
+        manifest.gen_settings.write_manifest(&mut original);
+        let mut reloaded = String::new();
+        loaded.gen_settings.write_manifest(&mut reloaded);
+        assert_eq!(reloaded, original);
+    }
+
+    #[test]
+    fn bad_lines_are_errors() {
+        let path = std::env::temp_dir().join(format!("bad_manifest_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join(MANIFEST_FILE), "# comment\n\nroot xyz\n").unwrap();
+
+        let result = Manifest::load(&path);
+        std::fs::remove_dir_all(&path).unwrap();
+        assert_eq!(
+            result.err().unwrap(),
+            "Manifest line 3: Couldn't parse hash 'xyz'"
+        );
+    }
+}
@@ -0,0 +1,679 @@
+use super::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Used when a mesh or point cloud has no colours
+pub const DEFAULT_COLOUR: Voxel = Voxel {
+    r: 200,
+    g: 200,
+    b: 200,
+};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Triangle {
+    pub corners: [Vector3<f32>; 3],
+    pub colours: [Voxel; 3],
+}
+
+/// Triangles with a colour at each corner, loaded from .obj or .stl files or made from voxels.
+/// Coordinates are used as they are, with y up.
+pub struct Mesh {
+    pub triangles: Vec<Triangle>,
+    /// Material libraries an .obj refers to that couldn't be read, with why
+    pub missing_materials: Vec<String>,
+}
+
+impl Mesh {
+    pub fn load_file(path: &Path) -> Result<Mesh, String> {
+        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+        let mesh = match path.extension().and_then(std::ffi::OsStr::to_str) {
+            Some("obj") => Mesh::load_obj(&data, path.parent().unwrap_or(Path::new(".")))?,
+            Some("stl") => Mesh::load_stl(&data)?,
+            _ => return Err("Unknown mesh type".to_string()),
+        };
+
+        if mesh.triangles.is_empty() {
+            return Err("Mesh has no triangles".to_string());
+        }
+        Ok(mesh)
+    }
+
+    /// Vertices can have colours after their position, faces with more than 3 corners are
+    /// split into a fan. Materials only give their diffuse colour.
+    fn load_obj(data: &[u8], folder: &Path) -> Result<Mesh, String> {
+        let text = String::from_utf8_lossy(data);
+
+        let mut positions = Vec::new();
+        let mut colours = Vec::new();
+        let mut materials = HashMap::new();
+        let mut missing_materials = Vec::new();
+        let mut material = None;
+        let mut triangles = Vec::new();
+        for (line_number, line) in text.lines().enumerate() {
+            let error = |e: String| format!("Line {}: {}", line_number + 1, e);
+
+            let line = line.split('#').next().unwrap();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["v", numbers @ ..] => {
+                    let numbers = numbers
+                        .iter()
+                        .map(|n| parse::<f32>(n))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(error)?;
+                    match numbers.as_slice() {
+                        [x, y, z, rest @ ..] => {
+                            positions.push(Vector3::new(*x, *y, *z));
+                            colours.push(match rest {
+                                [r, g, b, ..] => Some(unit_colour(*r, *g, *b)),
+                                _ => None,
+                            });
+                        }
+                        _ => return Err(error("Vertex has less than 3 coordinates".to_string())),
+                    }
+                }
+                ["f", corners @ ..] => {
+                    let corners = corners
+                        .iter()
+                        .map(|corner| {
+                            // Only the position index of "v/vt/vn" is used
+                            let index: i64 = parse(corner.split('/').next().unwrap())?;
+                            let index = if index < 0 {
+                                positions.len() as i64 + index
+                            } else {
+                                index - 1
+                            };
+                            if index < 0 || index >= positions.len() as i64 {
+                                return Err(format!("Vertex {} doesn't exist", corner));
+                            }
+                            Ok(index as usize)
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(error)?;
+
+                    let colour = |i: usize| {
+                        colours[i]
+                            .or_else(|| material.and_then(|m| materials.get(m).copied()))
+                            .unwrap_or(DEFAULT_COLOUR)
+                    };
+                    for k in 2..corners.len() {
+                        let fan = [corners[0], corners[k - 1], corners[k]];
+                        triangles.push(Triangle {
+                            corners: fan.map(|i| positions[i]),
+                            colours: fan.map(colour),
+                        });
+                    }
+                }
+                ["mtllib", names @ ..] => {
+                    for name in names {
+                        match std::fs::read(folder.join(name)) {
+                            Ok(mtl) => materials.extend(load_mtl(&mtl)),
+                            Err(e) => missing_materials.push(format!("{}: {}", name, e)),
+                        }
+                    }
+                }
+                ["usemtl", name] => material = Some(*name),
+                _ => {}
+            }
+        }
+
+        Ok(Mesh {
+            triangles,
+            missing_materials,
+        })
+    }
+
+    /// Binary files can have a colour per facet, stored the way VisCAM and SolidView do with
+    /// blue in the low bits
+    fn load_stl(data: &[u8]) -> Result<Mesh, String> {
+        let binary_count = data
+            .get(80..84)
+            .map(|count| u32::from_le_bytes(count.try_into().unwrap()) as usize);
+        if binary_count.is_some_and(|count| data.len() == 84 + count * 50) {
+            let float = |bytes: &[u8]| f32::from_le_bytes(bytes.try_into().unwrap());
+            let triangles = data[84..]
+                .chunks_exact(50)
+                .map(|facet| {
+                    // Skips the normal
+                    let corner = |i: usize| {
+                        let start = 12 + i * 12;
+                        Vector3::new(
+                            float(&facet[start..start + 4]),
+                            float(&facet[start + 4..start + 8]),
+                            float(&facet[start + 8..start + 12]),
+                        )
+                    };
+
+                    let attribute = u16::from_le_bytes([facet[48], facet[49]]);
+                    let colour = if attribute & 0x8000 != 0 {
+                        let channel = |shift: u16| (((attribute >> shift) & 31) * 255 / 31) as u8;
+                        non_black(Voxel::new(channel(10), channel(5), channel(0)))
+                    } else {
+                        DEFAULT_COLOUR
+                    };
+
+                    Triangle {
+                        corners: [corner(0), corner(1), corner(2)],
+                        colours: [colour; 3],
+                    }
+                })
+                .collect();
+            return Ok(Mesh {
+                triangles,
+                missing_materials: Vec::new(),
+            });
+        }
+
+        let text = String::from_utf8_lossy(data);
+        if !text.trim_start().starts_with("solid") {
+            return Err("STL file is truncated".to_string());
+        }
+
+        let mut corners = Vec::new();
+        for line in text.lines() {
+            if let ["vertex", x, y, z] = line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                corners.push(Vector3::new(parse(x)?, parse(y)?, parse(z)?));
+            }
+        }
+        let triangles = corners
+            .chunks_exact(3)
+            .map(|corners| Triangle {
+                corners: [corners[0], corners[1], corners[2]],
+                colours: [DEFAULT_COLOUR; 3],
+            })
+            .collect();
+        Ok(Mesh {
+            triangles,
+            missing_materials: Vec::new(),
+        })
+    }
+
+    /// Writes an .obj or .ply file picked by the extension, both with vertex colours
+    pub fn save_file(&self, path: &Path) -> Result<(), String> {
+        let (positions, colours, faces) = self.indexed();
+        let data = match path.extension().and_then(std::ffi::OsStr::to_str) {
+            Some("obj") => {
+                let mut text = String::new();
+                for (pos, colour) in positions.iter().zip(&colours) {
+                    text += &format!(
+                        "v {} {} {} {:.4} {:.4} {:.4}\n",
+                        pos.x,
+                        pos.y,
+                        pos.z,
+                        colour.r as f32 / 255.0,
+                        colour.g as f32 / 255.0,
+                        colour.b as f32 / 255.0
+                    );
+                }
+                for face in &faces {
+                    text += &format!("f {} {} {}\n", face[0] + 1, face[1] + 1, face[2] + 1);
+                }
+                text.into_bytes()
+            }
+            Some("ply") => {
+                let mut data = format!(
+                    "ply\nformat binary_little_endian 1.0\nelement vertex {}\n\
+                     property float x\nproperty float y\nproperty float z\n\
+                     property uchar red\nproperty uchar green\nproperty uchar blue\n\
+                     element face {}\nproperty list uchar uint vertex_indices\nend_header\n",
+                    positions.len(),
+                    faces.len()
+                )
+                .into_bytes();
+                for (pos, colour) in positions.iter().zip(&colours) {
+                    for c in [pos.x, pos.y, pos.z] {
+                        data.extend(c.to_le_bytes());
+                    }
+                    data.extend([colour.r, colour.g, colour.b]);
+                }
+                for face in &faces {
+                    data.push(3);
+                    for i in face {
+                        data.extend(i.to_le_bytes());
+                    }
+                }
+                data
+            }
+            _ => return Err("Meshes can only be saved as .obj or .ply".to_string()),
+        };
+
+        std::fs::write(path, data).map_err(|e| e.to_string())
+    }
+
+    /// Shares the corners that have the same position and colour between triangles
+    fn indexed(&self) -> (Vec<Vector3<f32>>, Vec<Voxel>, Vec<[u32; 3]>) {
+        let mut positions = Vec::new();
+        let mut colours = Vec::new();
+        let mut indices = HashMap::new();
+        let faces = self
+            .triangles
+            .iter()
+            .map(|triangle| {
+                [0, 1, 2].map(|i| {
+                    let (pos, colour) = (triangle.corners[i], triangle.colours[i]);
+                    let key = (
+                        [pos.x, pos.y, pos.z].map(f32::to_bits),
+                        colour.to_cpu_value(),
+                    );
+                    *indices.entry(key).or_insert_with(|| {
+                        positions.push(pos);
+                        colours.push(colour);
+                        positions.len() as u32 - 1
+                    })
+                })
+            })
+            .collect();
+        (positions, colours, faces)
+    }
+
+    /// Scales the mesh to fit a grid of 2^`depth` voxels along its longest side and fills
+    /// every voxel a triangle touches, colours are blended across the triangle. With `fill`,
+    /// voxels inside the mesh are filled too, which only makes sense for watertight meshes.
+    pub fn voxelize(&self, depth: u32, fill: bool) -> Result<CpuOctree, String> {
+        if depth == 0 || depth > 21 {
+            return Err("Depth has to be from 1 to 21".to_string());
+        }
+        let size = (1u32 << depth) as f32;
+
+        let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+        for corner in self.triangles.iter().flat_map(|triangle| triangle.corners) {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(corner[axis]);
+                max[axis] = max[axis].max(corner[axis]);
+            }
+        }
+        let extent = max - min;
+        let longest = extent.x.max(extent.y).max(extent.z);
+        let scale = if longest > 0.0 { size / longest } else { 1.0 };
+        // Centred along the shorter sides
+        let offset = (Vector3::new(size, size, size) - extent * scale) / 2.0;
+
+        let triangles: Vec<Triangle> = self
+            .triangles
+            .iter()
+            .map(|triangle| Triangle {
+                corners: triangle
+                    .corners
+                    .map(|corner| (corner - min) * scale + offset),
+                colours: triangle.colours,
+            })
+            .collect();
+
+        let mut voxelizer = Voxelizer {
+            depth,
+            voxels: HashMap::new(),
+        };
+        for triangle in &triangles {
+            voxelizer.add_triangle(triangle, Vector3::zero(), 0);
+        }
+
+        let mut voxels: HashMap<u64, Voxel> = voxelizer
+            .voxels
+            .into_iter()
+            .map(|(code, (colour, count))| {
+                let colour = colour / count;
+                let voxel = Voxel::new(
+                    colour.x.round() as u8,
+                    colour.y.round() as u8,
+                    colour.z.round() as u8,
+                );
+                (code, non_black(voxel))
+            })
+            .collect();
+        if fill {
+            fill_interior(&triangles, depth, &mut voxels);
+        }
+
+        let mut voxels: Vec<(u64, Node)> = voxels
+            .into_iter()
+            .map(|(code, voxel)| (code, Node::new(CHUNK_OFFSET, voxel)))
+            .collect();
+        voxels.sort_unstable_by_key(|(code, _)| *code);
+        Ok(CpuOctree::from_sorted_leaves(&voxels, depth))
+    }
+}
+
+/// Diffuse colours of the materials in an .mtl file
+fn load_mtl(data: &[u8]) -> HashMap<String, Voxel> {
+    let mut materials = HashMap::new();
+    let mut name = None;
+    for line in String::from_utf8_lossy(data).lines() {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["newmtl", new_name] => name = Some(new_name.to_string()),
+            ["Kd", r, g, b] => {
+                if let (Some(name), Ok(r), Ok(g), Ok(b)) = (&name, r.parse(), g.parse(), b.parse())
+                {
+                    materials.insert(name.clone(), unit_colour(r, g, b));
+                }
+            }
+            _ => {}
+        }
+    }
+    materials
+}
+
+fn unit_colour(r: f32, g: f32, b: f32) -> Voxel {
+    let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    non_black(Voxel::new(channel(r), channel(g), channel(b)))
+}
+
+/// Black leaves are empty
+pub fn non_black(voxel: Voxel) -> Voxel {
+    if voxel == Voxel::new(0, 0, 0) {
+        Voxel::new(1, 1, 1)
+    } else {
+        voxel
+    }
+}
+
+struct Voxelizer {
+    depth: u32,
+    /// Summed colour and how many triangles touched each voxel
+    voxels: HashMap<u64, (Vector3<f32>, f32)>,
+}
+
+impl Voxelizer {
+    /// Goes down the nodes the triangle overlaps, starting from the node at `pos` and `level`
+    fn add_triangle(&mut self, triangle: &Triangle, pos: Vector3<u32>, level: u32) {
+        let half = (1u64 << (self.depth - level)) as f32 / 2.0;
+        let centre = pos.cast::<f32>().unwrap() * half * 2.0 + Vector3::new(half, half, half);
+        if !triangle_overlaps_box(&triangle.corners, centre, half) {
+            return;
+        }
+
+        if level == self.depth {
+            let colour = blend(triangle, centre);
+            let voxel = self
+                .voxels
+                .entry(morton(pos, self.depth))
+                .or_insert((Vector3::zero(), 0.0));
+            voxel.0 += colour;
+            voxel.1 += 1.0;
+            return;
+        }
+
+        for i in 0..8 {
+            self.add_triangle(triangle, pos * 2 + child_offset(i), level + 1);
+        }
+    }
+}
+
+/// Separating axis test between a triangle and a cube, touching counts as overlapping
+fn triangle_overlaps_box(corners: &[Vector3<f32>; 3], centre: Vector3<f32>, half: f32) -> bool {
+    let v = corners.map(|corner| corner - centre);
+    let separated = |axis: Vector3<f32>| {
+        let p = v.map(|v| v.dot(axis));
+        let r = half * (axis.x.abs() + axis.y.abs() + axis.z.abs());
+        p[0].min(p[1]).min(p[2]) > r || p[0].max(p[1]).max(p[2]) < -r
+    };
+
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+    let units = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+    !units.iter().any(|unit| separated(*unit))
+        && !separated(edges[0].cross(edges[1]))
+        && !units
+            .iter()
+            .any(|unit| edges.iter().any(|edge| separated(unit.cross(*edge))))
+}
+
+/// Colour of the point on the triangle closest to `point`, roughly
+fn blend(triangle: &Triangle, point: Vector3<f32>) -> Vector3<f32> {
+    let [a, b, c] = triangle.corners;
+    let (ab, ac, ap) = (b - a, c - a, point - a);
+    let (d00, d01, d11) = (ab.dot(ab), ab.dot(ac), ac.dot(ac));
+    let (d20, d21) = (ap.dot(ab), ap.dot(ac));
+    let denominator = d00 * d11 - d01 * d01;
+
+    let weights = if denominator.abs() < f32::EPSILON {
+        Vector3::new(1.0, 1.0, 1.0)
+    } else {
+        let v = (d11 * d20 - d01 * d21) / denominator;
+        let w = (d00 * d21 - d01 * d20) / denominator;
+        Vector3::new(1.0 - v - w, v, w).map(|weight| weight.max(0.0))
+    };
+    let weights = weights / (weights.x + weights.y + weights.z).max(f32::EPSILON);
+
+    let colour = |voxel: Voxel| Vector3::new(voxel.r as f32, voxel.g as f32, voxel.b as f32);
+    colour(triangle.colours[0]) * weights.x
+        + colour(triangle.colours[1]) * weights.y
+        + colour(triangle.colours[2]) * weights.z
+}
+
+/// Casts a ray along z through the centre of every column and fills between pairs of
+/// crossings. Voxels already on the surface keep their colour, the rest take the colour of
+/// the triangle where the ray went in.
+fn fill_interior(triangles: &[Triangle], depth: u32, voxels: &mut HashMap<u64, Voxel>) {
+    let size = 1i64 << depth;
+    let mut columns: HashMap<(u32, u32), Vec<(f32, Voxel)>> = HashMap::new();
+    for triangle in triangles {
+        let [a, b, c] = triangle.corners;
+        let area = (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y);
+        if area.abs() < f32::EPSILON {
+            // Edge on to the rays
+            continue;
+        }
+
+        let lo = |axis: usize| (a[axis].min(b[axis]).min(c[axis]).floor() as i64).max(0);
+        let hi = |axis: usize| (a[axis].max(b[axis]).max(c[axis]).ceil() as i64).min(size - 1);
+        for x in lo(0)..=hi(0) {
+            for y in lo(1)..=hi(1) {
+                // Nudged off the centre so rays don't go exactly through shared edges
+                let p = (x as f32 + 0.5 + 1.3e-4, y as f32 + 0.5 + 2.9e-4);
+                let edge = |s: Vector3<f32>, e: Vector3<f32>| {
+                    (e.x - s.x) * (p.1 - s.y) - (p.0 - s.x) * (e.y - s.y)
+                };
+                let w = [edge(b, c) / area, edge(c, a) / area, edge(a, b) / area];
+                if w.iter().any(|w| *w < 0.0) {
+                    continue;
+                }
+
+                let z = a.z * w[0] + b.z * w[1] + c.z * w[2];
+                let point = Vector3::new(p.0, p.1, z);
+                let colour = blend(triangle, point);
+                let voxel = non_black(Voxel::new(
+                    colour.x.round() as u8,
+                    colour.y.round() as u8,
+                    colour.z.round() as u8,
+                ));
+                columns
+                    .entry((x as u32, y as u32))
+                    .or_default()
+                    .push((z, voxel));
+            }
+        }
+    }
+
+    for ((x, y), mut crossings) in columns {
+        crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for pair in crossings.chunks_exact(2) {
+            let (z_in, colour) = pair[0];
+            let start = ((z_in - 0.5).ceil() as i64).max(0);
+            let end = ((pair[1].0 - 0.5).floor() as i64).min(size - 1);
+            for z in start..=end {
+                let code = morton(Vector3::new(x, y, z as u32), depth);
+                voxels.entry(code).or_insert(colour);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 12 triangles of the cube from `lo` to `hi`, facing out
+    fn cube(lo: f32, hi: f32, colour: Voxel) -> Vec<Triangle> {
+        let corner = |i: usize| {
+            Vector3::new(
+                if i & 4 != 0 { hi } else { lo },
+                if i & 2 != 0 { hi } else { lo },
+                if i & 1 != 0 { hi } else { lo },
+            )
+        };
+        let quads = [
+            [0, 1, 3, 2],
+            [4, 6, 7, 5],
+            [0, 4, 5, 1],
+            [2, 3, 7, 6],
+            [0, 2, 6, 4],
+            [1, 5, 7, 3],
+        ];
+        quads
+            .iter()
+            .flat_map(|q| [[q[0], q[1], q[2]], [q[0], q[2], q[3]]])
+            .map(|t| Triangle {
+                corners: t.map(corner),
+                colours: [colour; 3],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ascii_stl() {
+        let text = "solid test\n\
+            facet normal 0 0 1\n outer loop\n  vertex 0 0 0\n  vertex 1 0 0\n  vertex 0 1.5 0\n endloop\nendfacet\n\
+            facet normal 0 0 1\n outer loop\n  vertex 1 1 1\n  vertex 2 1 1\n  vertex 1 2 -1e1\n endloop\nendfacet\n\
+            endsolid test\n";
+        let mesh = Mesh::load_stl(text.as_bytes()).unwrap();
+        assert_eq!(mesh.triangles.len(), 2);
+        assert_eq!(mesh.triangles[0].corners[2], Vector3::new(0.0, 1.5, 0.0));
+        assert_eq!(mesh.triangles[1].corners[2], Vector3::new(1.0, 2.0, -10.0));
+        assert_eq!(mesh.triangles[1].colours, [DEFAULT_COLOUR; 3]);
+
+        assert!(Mesh::load_stl(b"not an stl").is_err());
+    }
+
+    #[test]
+    fn binary_stl() {
+        let mut data = vec![0; 80];
+        data.extend(2u32.to_le_bytes());
+        // Red in the high bits, green in the middle, a little blue in the low ones
+        for attribute in [0x8000u16 | 31 << 10 | 15 << 5 | 1, 0] {
+            for v in [
+                0.0f32, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.0, 0.0,
+            ] {
+                data.extend(v.to_le_bytes());
+            }
+            data.extend(attribute.to_le_bytes());
+        }
+
+        let mesh = Mesh::load_stl(&data).unwrap();
+        assert_eq!(mesh.triangles.len(), 2);
+        assert_eq!(
+            mesh.triangles[0].corners,
+            [
+                Vector3::zero(),
+                Vector3::unit_x(),
+                Vector3::new(0.0, 2.0, 0.0)
+            ]
+        );
+        assert_eq!(mesh.triangles[0].colours[0], Voxel::new(255, 123, 8));
+        assert_eq!(mesh.triangles[1].colours[0], DEFAULT_COLOUR);
+    }
+
+    #[test]
+    fn obj_indices_fans_and_colours() {
+        let folder = std::env::temp_dir().join(format!("mesh_obj_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::write(folder.join("colours.mtl"), "newmtl blue\nKd 0 0 1\n").unwrap();
+
+        let obj = "mtllib colours.mtl missing.mtl\n\
+            v 0 0 0 1 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+            usemtl blue\n\
+            f 1/1/1 2//2 3 4 # a quad\n\
+            f -4 -2 -1\n";
+        let mesh = Mesh::load_obj(obj.as_bytes(), &folder).unwrap();
+        std::fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(mesh.missing_materials.len(), 1);
+        assert!(mesh.missing_materials[0].starts_with("missing.mtl"));
+
+        // The quad is a fan around its first corner
+        assert_eq!(mesh.triangles.len(), 3);
+        let corners = |t: &Triangle| t.corners.map(|c| (c.x as i32, c.y as i32));
+        assert_eq!(corners(&mesh.triangles[0]), [(0, 0), (1, 0), (1, 1)]);
+        assert_eq!(corners(&mesh.triangles[1]), [(0, 0), (1, 1), (0, 1)]);
+        // Negative indices count back from the last vertex
+        assert_eq!(corners(&mesh.triangles[2]), [(0, 0), (1, 1), (0, 1)]);
+
+        // Vertex colours win over the material
+        let red = Voxel::new(255, 0, 0);
+        let blue = Voxel::new(0, 0, 255);
+        assert_eq!(mesh.triangles[0].colours, [red, blue, blue]);
+
+        assert!(Mesh::load_obj(b"v 0 0 0\nf 1 2 3\n", &folder).is_err());
+        assert!(Mesh::load_obj(b"v 0 0\n", &folder).is_err());
+    }
+
+    #[test]
+    fn triangle_box_overlap() {
+        let triangle = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(4.0, 0.0, 0.0),
+            Vector3::new(0.0, 4.0, 0.0),
+        ];
+        let half = 0.5;
+        assert!(triangle_overlaps_box(
+            &triangle,
+            Vector3::new(1.0, 1.0, 0.0),
+            half
+        ));
+        // Above the plane of the triangle
+        assert!(!triangle_overlaps_box(
+            &triangle,
+            Vector3::new(1.0, 1.0, 1.0),
+            half
+        ));
+        // Past the long edge, only the edge cross products separate it
+        assert!(!triangle_overlaps_box(
+            &triangle,
+            Vector3::new(3.0, 3.0, 0.0),
+            half
+        ));
+        // Touching a corner counts
+        assert!(triangle_overlaps_box(
+            &triangle,
+            Vector3::new(-0.5, -0.5, 0.0),
+            half
+        ));
+    }
+
+    #[test]
+    fn fills_a_closed_cube() {
+        let colour = Voxel::new(10, 20, 30);
+        let triangles = cube(2.0, 6.0, colour);
+        let mut voxels = HashMap::new();
+        let surface = morton(Vector3::new(2, 2, 2), 3);
+        voxels.insert(surface, Voxel::new(1, 2, 3));
+        fill_interior(&triangles, 3, &mut voxels);
+
+        // Voxels with their centres inside, from 2 to 5 along each axis
+        assert_eq!(voxels.len(), 64);
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let inside = [x, y, z].iter().all(|c| (2..6).contains(c));
+                    let code = morton(Vector3::new(x, y, z), 3);
+                    assert_eq!(voxels.contains_key(&code), inside);
+                }
+            }
+        }
+        assert_eq!(voxels[&surface], Voxel::new(1, 2, 3));
+        assert_eq!(voxels[&morton(Vector3::new(3, 4, 5), 3)], colour);
+
+        // Voxelizing fills it too
+        let mesh = Mesh {
+            triangles,
+            missing_materials: Vec::new(),
+        };
+        let octree = mesh.voxelize(3, true).unwrap();
+        let solid = octree
+            .walk(WalkSettings::default())
+            .filter(|visit| visit.leaf && visit.node.value != Voxel::new(0, 0, 0))
+            .map(|visit| 1 << (3 * (3 - visit.depth)))
+            .sum::<u32>();
+        assert_eq!(solid, 512);
+    }
+}
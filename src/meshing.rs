@@ -0,0 +1,575 @@
+use super::*;
+
+/// Meshing goes a brick of 2^BRICK_DEPTH voxels along each axis at a time, so only one
+/// brick's voxels are in memory
+const BRICK_DEPTH: u32 = 6;
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MeshStyle {
+    /// Faces between solid and empty voxels, merged into rectangles where the colour matches
+    Cubes,
+    /// Surface nets, one vertex per cube of 8 voxel centres that the surface goes through
+    Smooth,
+}
+
+/// Colours of the voxels in a box at one depth, everything outside is empty. Only the
+/// surface of the solid voxels from `own_min` to `own_max` is meshed, the voxels around them
+/// are there so it lines up with the bricks next to it.
+struct Grid {
+    min: Vector3<i64>,
+    size: Vector3<i64>,
+    own_min: Vector3<i64>,
+    own_max: Vector3<i64>,
+    depth: u32,
+    cells: Vec<Option<Voxel>>,
+}
+
+impl Grid {
+    fn new(
+        min: Vector3<i64>,
+        max: Vector3<i64>,
+        own_min: Vector3<i64>,
+        own_max: Vector3<i64>,
+        depth: u32,
+    ) -> Self {
+        let size = max - min;
+        Self {
+            min,
+            size,
+            own_min,
+            own_max,
+            depth,
+            cells: vec![None; (size.x * size.y * size.z) as usize],
+        }
+    }
+
+    fn owned(&self, pos: Vector3<i64>) -> bool {
+        (0..3).all(|axis| pos[axis] >= self.own_min[axis] && pos[axis] < self.own_max[axis])
+    }
+
+    fn index(&self, pos: Vector3<i64>) -> Option<usize> {
+        let p = pos - self.min;
+        if (0..3).any(|axis| p[axis] < 0 || p[axis] >= self.size[axis]) {
+            return None;
+        }
+        Some(((p.x * self.size.y + p.y) * self.size.z + p.z) as usize)
+    }
+
+    fn get(&self, pos: Vector3<i64>) -> Option<Voxel> {
+        self.index(pos).and_then(|i| self.cells[i])
+    }
+
+    /// Fills the cells the leaf covers
+    fn add(&mut self, visit: &Visit, colour: Voxel) {
+        let scale = 1i64 << (self.depth - visit.depth);
+        let lo = visit.pos.cast::<i64>().unwrap() * scale;
+        for x in lo.x.max(self.min.x)..(lo.x + scale).min(self.min.x + self.size.x) {
+            for y in lo.y.max(self.min.y)..(lo.y + scale).min(self.min.y + self.size.y) {
+                for z in lo.z.max(self.min.z)..(lo.z + scale).min(self.min.z + self.size.z) {
+                    let i = self.index(Vector3::new(x, y, z)).unwrap();
+                    self.cells[i] = Some(colour);
+                }
+            }
+        }
+    }
+
+    /// From integer coordinates at the grid depth to the -1 to 1 space used by `find_voxel`
+    fn to_space(&self, pos: Vector3<f32>) -> Vector3<f32> {
+        pos * (2.0 / (1u64 << self.depth) as f32) - Vector3::new(1.0, 1.0, 1.0)
+    }
+
+    fn cubes(&self) -> Vec<Triangle> {
+        let mut triangles = Vec::new();
+        let own_size = self.own_max - self.own_min;
+        for d in 0..3 {
+            let u = (d + 1) % 3;
+            let v = (d + 2) % 3;
+            let (size_u, size_v) = (own_size[u] as usize, own_size[v] as usize);
+
+            for layer in self.own_min[d]..=self.own_max[d] {
+                // Faces on the plane between this layer and the one before, with which way
+                // they point
+                let mut mask = vec![None; size_u * size_v];
+                for i in 0..size_u {
+                    for j in 0..size_v {
+                        let mut pos = self.own_min;
+                        pos[d] = layer;
+                        pos[u] += i as i64;
+                        pos[v] += j as i64;
+                        let mut before = pos;
+                        before[d] -= 1;
+
+                        // Faces belong to the brick their solid voxel is in
+                        mask[i * size_v + j] = match (self.get(before), self.get(pos)) {
+                            (Some(colour), None) if self.owned(before) => Some((colour, true)),
+                            (None, Some(colour)) if self.owned(pos) => Some((colour, false)),
+                            _ => None,
+                        };
+                    }
+                }
+
+                // Grows each face along v then u while the faces match
+                for i in 0..size_u {
+                    let mut j = 0;
+                    while j < size_v {
+                        let face = match mask[i * size_v + j] {
+                            Some(face) => face,
+                            None => {
+                                j += 1;
+                                continue;
+                            }
+                        };
+
+                        let mut height = 1;
+                        while j + height < size_v && mask[i * size_v + j + height] == Some(face) {
+                            height += 1;
+                        }
+                        let mut width = 1;
+                        while i + width < size_u
+                            && (0..height).all(|k| mask[(i + width) * size_v + j + k] == Some(face))
+                        {
+                            width += 1;
+                        }
+                        for a in i..i + width {
+                            for b in j..j + height {
+                                mask[a * size_v + b] = None;
+                            }
+                        }
+
+                        let corner = |du: usize, dv: usize| {
+                            let mut pos = self.own_min.cast::<f32>().unwrap();
+                            pos[d] = layer as f32;
+                            pos[u] += (i + du) as f32;
+                            pos[v] += (j + dv) as f32;
+                            self.to_space(pos)
+                        };
+                        let quad = [
+                            corner(0, 0),
+                            corner(width, 0),
+                            corner(width, height),
+                            corner(0, height),
+                        ];
+                        let (colour, positive) = face;
+                        push_quad(&mut triangles, quad, [colour; 4], positive);
+
+                        j += height;
+                    }
+                }
+            }
+        }
+        triangles
+    }
+
+    fn smooth(&self) -> Vec<Triangle> {
+        // Dual cells have voxel centres for corners, there is one more of them than voxels
+        // along each axis so the surface is closed at the edge of the brick
+        let cell_min = self.own_min - Vector3::new(1, 1, 1);
+        let cell_size = self.own_max - self.own_min + Vector3::new(1, 1, 1);
+        let cell_index = |c: Vector3<i64>| {
+            let p = c - cell_min;
+            ((p.x * cell_size.y + p.y) * cell_size.z + p.z) as usize
+        };
+
+        let mut vertices = vec![None; (cell_size.x * cell_size.y * cell_size.z) as usize];
+        for x in 0..cell_size.x {
+            for y in 0..cell_size.y {
+                for z in 0..cell_size.z {
+                    let c = cell_min + Vector3::new(x, y, z);
+                    let corners = [0, 1, 2, 3, 4, 5, 6, 7]
+                        .map(|i| self.get(c + child_offset(i).cast::<i64>().unwrap()));
+                    let solid = corners.iter().filter(|corner| corner.is_some()).count();
+                    if solid == 0 || solid == 8 {
+                        continue;
+                    }
+
+                    // Average of where the edges that cross the surface are cut
+                    let mut offset = Vector3::zero();
+                    let mut crossings = 0.0;
+                    for i in 0..8 {
+                        for axis in 0..3 {
+                            let j = i | (4 >> axis);
+                            if j != i && corners[i].is_some() != corners[j].is_some() {
+                                let a = child_offset(i).cast::<f32>().unwrap();
+                                let b = child_offset(j).cast::<f32>().unwrap();
+                                offset += (a + b) / 2.0;
+                                crossings += 1.0;
+                            }
+                        }
+                    }
+                    let pos =
+                        c.cast::<f32>().unwrap() + Vector3::new(0.5, 0.5, 0.5) + offset / crossings;
+
+                    let mut colour = Vector3::zero();
+                    for corner in corners.iter().flatten() {
+                        colour += Vector3::new(corner.r as f32, corner.g as f32, corner.b as f32);
+                    }
+                    colour /= solid as f32;
+                    let colour = Voxel::new(
+                        colour.x.round() as u8,
+                        colour.y.round() as u8,
+                        colour.z.round() as u8,
+                    );
+
+                    vertices[cell_index(c)] = Some((self.to_space(pos), colour));
+                }
+            }
+        }
+
+        // A quad around every edge between a solid and an empty voxel
+        let mut triangles = Vec::new();
+        for d in 0..3 {
+            let u = (d + 1) % 3;
+            let v = (d + 2) % 3;
+            let mut end = self.own_max - self.own_min;
+            end[d] += 1;
+            for x in 0..end.x {
+                for y in 0..end.y {
+                    for z in 0..end.z {
+                        let mut pos = self.own_min + Vector3::new(x, y, z);
+                        pos[d] -= 1;
+                        let mut next = pos;
+                        next[d] += 1;
+
+                        let positive = match (self.get(pos), self.get(next)) {
+                            (Some(_), None) if self.owned(pos) => true,
+                            (None, Some(_)) if self.owned(next) => false,
+                            _ => continue,
+                        };
+
+                        let cell = |du: i64, dv: i64| {
+                            let mut c = pos;
+                            c[u] -= 1 - du;
+                            c[v] -= 1 - dv;
+                            vertices[cell_index(c)].unwrap()
+                        };
+                        let quad = [cell(0, 0), cell(1, 0), cell(1, 1), cell(0, 1)];
+                        push_quad(
+                            &mut triangles,
+                            quad.map(|(pos, _)| pos),
+                            quad.map(|(_, colour)| colour),
+                            positive,
+                        );
+                    }
+                }
+            }
+        }
+        triangles
+    }
+}
+
+/// Two triangles facing along the normal of the quad when `forwards`, away from it otherwise
+fn push_quad(
+    triangles: &mut Vec<Triangle>,
+    corners: [Vector3<f32>; 4],
+    colours: [Voxel; 4],
+    forwards: bool,
+) {
+    let order = if forwards {
+        [[0, 1, 2], [0, 2, 3]]
+    } else {
+        [[0, 2, 1], [0, 3, 2]]
+    };
+    for triangle in order {
+        triangles.push(Triangle {
+            corners: triangle.map(|i| corners[i]),
+            colours: triangle.map(|i| colours[i]),
+        });
+    }
+}
+
+/// Colour of a leaf of the walk, None if it's empty. Nodes cut off by the depth use their
+/// mip, so the mips have to be generated.
+pub fn leaf_colour(node: Node, filter: MipFilter) -> Option<Voxel> {
+    let solid = match node.pointer {
+        CHUNK_OFFSET => node.value != Voxel::new(0, 0, 0),
+        pointer if pointer > CHUNK_OFFSET => true,
+        _ => node.coverage > 0 && node.to_value(filter) != (VOXEL_OFFSET + SEE_THROUGH) << 4,
+    };
+    solid.then_some(node.value)
+}
+
+fn to_mesh<'a>(
+    walk: &dyn Fn(WalkSettings) -> Walk<'a>,
+    depth: u32,
+    region: Option<(Vector3<f32>, Vector3<f32>)>,
+    style: MeshStyle,
+    filter: MipFilter,
+    brick_depth: u32,
+) -> Result<Mesh, String> {
+    let size = 1i64 << depth;
+    let (min, max) = match region {
+        Some((min, max)) => {
+            let to_grid = |p: f32| (p + 1.0) / 2.0 * size as f32;
+            (
+                min.map(|p| (to_grid(p).floor() as i64).clamp(0, size)),
+                max.map(|p| (to_grid(p).ceil() as i64).clamp(0, size)),
+            )
+        }
+        None => (Vector3::zero(), Vector3::new(size, size, size)),
+    };
+    // Nodes that overlap the voxels from `min` to `max`
+    let voxel_region = |min: Vector3<i64>, max: Vector3<i64>| {
+        let to_space = |p: i64| (p as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+        Some((min.map(to_space), max.map(|p| to_space(p - 1))))
+    };
+
+    // Bricks are the nodes at `brick_level`, only the ones with something in them are meshed
+    let brick_level = depth - brick_depth.min(depth);
+    let brick_size = 1i64 << (depth - brick_level);
+    let mut bricks = std::collections::BTreeSet::new();
+    let settings = WalkSettings {
+        max_depth: Some(brick_level),
+        region: voxel_region(min, max),
+        ..Default::default()
+    };
+    if brick_level == 0 {
+        bricks.insert([0, 0, 0]);
+    }
+    // Bricks the region touches
+    let region_lo = min.map(|p| p.div_euclid(brick_size));
+    let region_hi = max.map(|p| (p + brick_size - 1).div_euclid(brick_size));
+    for visit in walk(settings).filter(|visit| visit.leaf && brick_level > 0) {
+        let content = visit.node.pointer != CHUNK_OFFSET || is_solid(visit.node);
+        if visit.depth == brick_level && content {
+            bricks.insert(visit.pos.cast::<i64>().unwrap().into());
+        } else if leaf_colour(visit.node, filter).is_some() {
+            // Bricks inside a bigger solid leaf have no surface, only the faces of its box
+            // clipped to the region are meshed
+            let scale = 1i64 << (brick_level - visit.depth);
+            let lo = (visit.pos.cast::<i64>().unwrap() * scale).zip(region_lo, i64::max);
+            let hi = (visit.pos.cast::<i64>().unwrap() * scale + Vector3::new(scale, scale, scale))
+                .zip(region_hi, i64::min);
+            for d in 0..3 {
+                let (u, v) = ((d + 1) % 3, (d + 2) % 3);
+                for side in [lo[d], hi[d] - 1] {
+                    for i in lo[u]..hi[u] {
+                        for j in lo[v]..hi[v] {
+                            let mut brick = [0; 3];
+                            (brick[d], brick[u], brick[v]) = (side, i, j);
+                            bricks.insert(brick);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut triangles = Vec::new();
+    for brick in bricks {
+        let lo = Vector3::from(brick) * brick_size;
+        let own_min = lo.zip(min, i64::max);
+        let own_max = (lo + Vector3::new(brick_size, brick_size, brick_size)).zip(max, i64::min);
+        if (0..3).any(|axis| own_min[axis] >= own_max[axis]) {
+            continue;
+        }
+
+        // A voxel around the brick so faces and vertices at its edge come out the same as in
+        // the bricks next to it
+        let grid_min = (own_min - Vector3::new(1, 1, 1)).zip(min, i64::max);
+        let grid_max = (own_max + Vector3::new(1, 1, 1)).zip(max, i64::min);
+        let mut grid = Grid::new(grid_min, grid_max, own_min, own_max, depth);
+        let settings = WalkSettings {
+            max_depth: Some(depth),
+            region: voxel_region(grid_min, grid_max),
+            ..Default::default()
+        };
+        for visit in walk(settings).filter(|visit| visit.leaf) {
+            if let Some(colour) = leaf_colour(visit.node, filter) {
+                grid.add(&visit, colour);
+            }
+        }
+
+        triangles.extend(match style {
+            MeshStyle::Cubes => grid.cubes(),
+            MeshStyle::Smooth => grid.smooth(),
+        });
+    }
+    Ok(Mesh {
+        triangles,
+        missing_materials: Vec::new(),
+    })
+}
+
+impl CpuOctree {
+    /// Surface of the voxels at `depth`, in the -1 to 1 space used by `find_voxel`. Deeper
+    /// nodes are drawn with their mip colour. Only the voxels in `region` are meshed.
+    #[allow(dead_code)]
+    pub fn to_mesh(
+        &self,
+        depth: u32,
+        region: Option<(Vector3<f32>, Vector3<f32>)>,
+        style: MeshStyle,
+    ) -> Result<Mesh, String> {
+        to_mesh(
+            &|settings| self.walk(settings),
+            depth,
+            region,
+            style,
+            MipFilter::default(),
+            BRICK_DEPTH,
+        )
+    }
+}
+
+impl World {
+    /// Surface of the voxels at `depth` going through the loaded chunks
+    pub fn to_mesh(
+        &self,
+        depth: u32,
+        region: Option<(Vector3<f32>, Vector3<f32>)>,
+        style: MeshStyle,
+    ) -> Result<Mesh, String> {
+        let walk = |settings| {
+            self.walk(WalkSettings {
+                follow_refs: true,
+                ..settings
+            })
+        };
+        to_mesh(&walk, depth, region, style, self.mip_filter, BRICK_DEPTH)
+    }
+}
+
+impl ChunkFiles<'_> {
+    /// Surface of the voxels at `depth`, reading the chunk files as they're reached
+    pub fn to_mesh(
+        &self,
+        depth: u32,
+        region: Option<(Vector3<f32>, Vector3<f32>)>,
+        style: MeshStyle,
+    ) -> Result<Mesh, String> {
+        let walk = |settings| {
+            self.walk(WalkSettings {
+                follow_refs: true,
+                ..settings
+            })
+        };
+        let mesh = to_mesh(
+            &walk,
+            depth,
+            region,
+            style,
+            self.world.mip_filter,
+            BRICK_DEPTH,
+        )?;
+        self.error()?;
+        Ok(mesh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A two coloured ball with a solid slab under it at depth 4
+    fn ball() -> CpuOctree {
+        let mut leaves = Vec::new();
+        for x in 0..16u32 {
+            for y in 0..16u32 {
+                for z in 0..16u32 {
+                    let d =
+                        Vector3::new(x, y, z).cast::<f32>().unwrap() - Vector3::new(7.5, 9.5, 7.5);
+                    let colour = if y < 4 {
+                        Voxel::new(0, 0, 255)
+                    } else if d.magnitude() < 5.0 {
+                        Voxel::new(255 * (x < 8) as u8, 255, 0)
+                    } else {
+                        continue;
+                    };
+                    let pos = Vector3::new(x, y, z);
+                    leaves.push((morton(pos, 4), Node::new(CHUNK_OFFSET, colour)));
+                }
+            }
+        }
+        leaves.sort_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, 4)
+    }
+
+    fn mesh(
+        octree: &CpuOctree,
+        region: Option<(Vector3<f32>, Vector3<f32>)>,
+        style: MeshStyle,
+        brick_depth: u32,
+    ) -> Mesh {
+        let walk = |settings| octree.walk(settings);
+        to_mesh(&walk, 4, region, style, MipFilter::default(), brick_depth).unwrap()
+    }
+
+    /// Area facing each way for each colour, greedy merging doesn't cross bricks so the
+    /// rectangles differ but this doesn't
+    fn areas(mesh: &Mesh) -> Vec<([u8; 3], [i32; 3], i64)> {
+        let mut areas = HashMap::new();
+        for triangle in &mesh.triangles {
+            let [a, b, c] = triangle.corners;
+            let colour = triangle.colours[0];
+            let cross = (b - a).cross(c - a);
+            let normal = cross.normalize().map(|n| n.round() as i32);
+            // In 1/16ths of a voxel face, which is 1/64 at depth 4, so they add up exactly
+            let area = (cross.magnitude() / 2.0 * 64.0 * 16.0).round() as i64;
+            *areas
+                .entry(([colour.r, colour.g, colour.b], normal.into()))
+                .or_insert(0) += area;
+        }
+        let mut areas: Vec<_> = areas.into_iter().map(|((c, n), a)| (c, n, a)).collect();
+        areas.sort();
+        areas
+    }
+
+    fn sorted_corners(mesh: &Mesh) -> Vec<[i64; 9]> {
+        let mut triangles: Vec<_> = mesh
+            .triangles
+            .iter()
+            .map(|t| {
+                let mut corners = [0; 9];
+                for (i, corner) in t.corners.iter().enumerate() {
+                    for axis in 0..3 {
+                        corners[i * 3 + axis] = (corner[axis] * 10000.0).round() as i64;
+                    }
+                }
+                corners
+            })
+            .collect();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn bricks_match_one_grid() {
+        let octree = ball();
+        let region = Some((Vector3::new(-0.8, -1.0, -0.3), Vector3::new(0.6, 0.9, 1.0)));
+        for region in [None, region] {
+            let cubes = mesh(&octree, region, MeshStyle::Cubes, 4);
+            assert!(!cubes.triangles.is_empty());
+            let smooth = mesh(&octree, region, MeshStyle::Smooth, 4);
+            for brick_depth in [1, 2, 3] {
+                let bricks = mesh(&octree, region, MeshStyle::Cubes, brick_depth);
+                assert_eq!(areas(&bricks), areas(&cubes));
+                let bricks = mesh(&octree, region, MeshStyle::Smooth, brick_depth);
+                assert_eq!(sorted_corners(&bricks), sorted_corners(&smooth));
+            }
+        }
+    }
+
+    #[test]
+    fn big_leaves_only_mesh_their_shell() {
+        // Solid leaves filling everything are a cube, the bricks inside them are skipped
+        let leaves: Vec<_> = (0..8)
+            .map(|code| (code, Node::new(CHUNK_OFFSET, Voxel::new(9, 9, 9))))
+            .collect();
+        let octree = CpuOctree::from_sorted_leaves(&leaves, 1);
+        let walk = |settings| octree.walk(settings);
+        let mesh = to_mesh(&walk, 4, None, MeshStyle::Cubes, MipFilter::default(), 1).unwrap();
+        let area: i64 = areas(&mesh).iter().map(|(_, _, a)| a).sum();
+        assert_eq!(area, 6 * 16 * 16 * 16);
+
+        // A region cutting through them is closed where it cuts, 6 by 8 by 7 voxels
+        let region = Some((Vector3::new(-0.7, -0.5, 0.0), Vector3::new(-0.1, 0.5, 0.8)));
+        let one_grid = to_mesh(&walk, 4, region, MeshStyle::Cubes, MipFilter::default(), 4);
+        let bricks = to_mesh(&walk, 4, region, MeshStyle::Cubes, MipFilter::default(), 1);
+        let area: i64 = areas(&bricks.unwrap()).iter().map(|(_, _, a)| a).sum();
+        assert_eq!(area, 2 * (6 * 8 + 8 * 7 + 6 * 7) * 16);
+        let area: i64 = areas(&one_grid.unwrap()).iter().map(|(_, _, a)| a).sum();
+        assert_eq!(area, 2 * (6 * 8 + 8 * 7 + 6 * 7) * 16);
+    }
+}
@@ -0,0 +1,897 @@
+use super::*;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+
+/// Minecraft block names to the block ids loaded by `World::new`, the first match wins. A `*`
+/// at the start or end of a name matches anything, 0 leaves the block empty.
+pub const DEFAULT_BLOCK_TABLE: &str = "
+air 0
+cave_air 0
+void_air 0
+water 0
+lava 0
+bubble_column 0
+light 0
+barrier 0
+structure_void 0
+grass 0
+short_grass 0
+tall_grass 0
+fern 0
+large_fern 0
+seagrass 0
+tall_seagrass 0
+kelp 0
+kelp_plant 0
+dead_bush 0
+vine 0
+snow 0
+ladder 0
+rail 0
+redstone_wire 0
+dandelion 0
+poppy 0
+*torch 0
+*_sapling 0
+*_flower 0
+*_tulip 0
+*_button 0
+*_pressure_plate 0
+*_sign 0
+*_banner 0
+*_carpet 0
+*_rail 0
+grass_block 3
+dirt 2
+coarse_dirt 2
+rooted_dirt 2
+podzol 2
+mud 2
+farmland 2
+dirt_path 2
+*_log 4
+*_wood 4
+*_planks 4
+*_stem 4
+*_hyphae 4
+*_leaves 5
+deepslate* 6
+cobbled_deepslate* 6
+blackstone* 6
+basalt 6
+*amethyst* 7
+*_ore 7
+diamond_block 7
+emerald_block 7
+*glass* 8
+ice 8
+* 1
+";
+
+/// Names of the blocks before 1.13 by numeric id, the rest get a `legacy_` name
+const LEGACY_BLOCKS: &[(u8, &str)] = &[
+    (0, "air"),
+    (1, "stone"),
+    (2, "grass_block"),
+    (3, "dirt"),
+    (4, "cobblestone"),
+    (5, "oak_planks"),
+    (6, "oak_sapling"),
+    (7, "bedrock"),
+    (8, "water"),
+    (9, "water"),
+    (10, "lava"),
+    (11, "lava"),
+    (12, "sand"),
+    (13, "gravel"),
+    (14, "gold_ore"),
+    (15, "iron_ore"),
+    (16, "coal_ore"),
+    (17, "oak_log"),
+    (18, "oak_leaves"),
+    (20, "glass"),
+    (24, "sandstone"),
+    (31, "short_grass"),
+    (32, "dead_bush"),
+    (35, "white_wool"),
+    (37, "dandelion"),
+    (38, "poppy"),
+    (43, "smooth_stone"),
+    (44, "stone_slab"),
+    (45, "bricks"),
+    (48, "mossy_cobblestone"),
+    (49, "obsidian"),
+    (50, "torch"),
+    (53, "oak_stairs"),
+    (56, "diamond_ore"),
+    (57, "diamond_block"),
+    (65, "ladder"),
+    (66, "rail"),
+    (67, "cobblestone_stairs"),
+    (78, "snow"),
+    (79, "ice"),
+    (80, "snow_block"),
+    (82, "clay"),
+    (85, "oak_fence"),
+    (89, "glowstone"),
+    (95, "white_stained_glass"),
+    (98, "stone_bricks"),
+    (102, "glass_pane"),
+    (106, "vine"),
+    (109, "stone_brick_stairs"),
+    (155, "quartz_block"),
+    (159, "white_terracotta"),
+    (161, "acacia_leaves"),
+    (162, "acacia_log"),
+    (171, "white_carpet"),
+    (172, "terracotta"),
+    (174, "packed_ice"),
+];
+
+/// Maps Minecraft block states to block ids
+pub struct BlockTable {
+    rules: Vec<(String, u32)>,
+    /// Names that only matched the catch all rule
+    pub unmapped: BTreeSet<String>,
+}
+
+impl BlockTable {
+    /// Lines of "name id", `#` starts a comment. The rules of `text` are tried before the
+    /// default ones.
+    pub fn new(text: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for line in text.lines().chain(DEFAULT_BLOCK_TABLE.lines()) {
+            let line = line.split('#').next().unwrap();
+            match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [] => {}
+                [name, id] => rules.push((
+                    name.trim_start_matches("minecraft:").to_string(),
+                    parse(id)?,
+                )),
+                _ => return Err(format!("Expected a name and a block id but got '{}'", line)),
+            }
+        }
+
+        Ok(Self {
+            rules,
+            unmapped: BTreeSet::new(),
+        })
+    }
+
+    /// Block id of a state like "minecraft:oak_log[axis=y]", None if it's empty
+    pub fn lookup(&mut self, state: &str) -> Option<u32> {
+        let name = state.split('[').next().unwrap();
+        let name = name.trim_start_matches("minecraft:");
+
+        let (pattern, id) = self
+            .rules
+            .iter()
+            .find(|(pattern, _)| matches(pattern, name))
+            .map(|(pattern, id)| (pattern.as_str(), *id))
+            .unwrap_or(("*", BLOCK_STONE));
+        if pattern == "*" {
+            self.unmapped.insert(name.to_string());
+        }
+
+        (id != 0).then_some(id)
+    }
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    match (pattern.strip_prefix('*'), pattern.strip_suffix('*')) {
+        (Some(rest), Some(_)) => rest.is_empty() || name.contains(rest.trim_end_matches('*')),
+        (Some(suffix), None) => name.ends_with(suffix),
+        (None, Some(prefix)) => name.starts_with(prefix),
+        (None, None) => name == pattern,
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+enum Tag {
+    End,
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(HashMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    fn get(&self, name: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(tags) => tags.get(name),
+            _ => None,
+        }
+    }
+
+    fn int(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(v) => Some(v as i64),
+            Tag::Short(v) => Some(v as i64),
+            Tag::Int(v) => Some(v as i64),
+            Tag::Long(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn get_int(&self, name: &str) -> Result<i64, String> {
+        self.get(name)
+            .and_then(Tag::int)
+            .ok_or_else(|| format!("Missing '{}'", name))
+    }
+
+    fn get_bytes(&self, name: &str) -> Result<&[u8], String> {
+        match self.get(name) {
+            Some(Tag::ByteArray(bytes)) => Ok(bytes),
+            _ => Err(format!("Missing '{}'", name)),
+        }
+    }
+
+    fn list(&self) -> &[Tag] {
+        match self {
+            Tag::List(tags) => tags,
+            _ => &[],
+        }
+    }
+
+    fn string(&self) -> Option<&str> {
+        match self {
+            Tag::String(string) => Some(string),
+            _ => None,
+        }
+    }
+}
+
+/// Reads big endian NBT
+struct NbtReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> NbtReader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + count)
+            .ok_or("NBT data is truncated")?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn length(&mut self) -> Result<usize, String> {
+        let length = i32::from_be_bytes(self.array()?);
+        if length < 0 || length as usize > self.data.len() {
+            return Err("NBT length is out of range".to_string());
+        }
+        Ok(length as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = u16::from_be_bytes(self.array()?) as usize;
+        // Java's modified UTF-8 only differs for nulls and characters outside the BMP
+        Ok(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+    }
+
+    /// The root compound, its name is skipped
+    fn root(&mut self) -> Result<Tag, String> {
+        let kind = self.array::<1>()?[0];
+        self.string()?;
+        self.tag(kind, 0)
+    }
+
+    fn tag(&mut self, kind: u8, depth: u32) -> Result<Tag, String> {
+        if depth > 512 {
+            return Err("NBT is nested too deep".to_string());
+        }
+
+        Ok(match kind {
+            0 => Tag::End,
+            1 => Tag::Byte(self.array::<1>()?[0] as i8),
+            2 => Tag::Short(i16::from_be_bytes(self.array()?)),
+            3 => Tag::Int(i32::from_be_bytes(self.array()?)),
+            4 => Tag::Long(i64::from_be_bytes(self.array()?)),
+            5 => Tag::Float(f32::from_be_bytes(self.array()?)),
+            6 => Tag::Double(f64::from_be_bytes(self.array()?)),
+            7 => {
+                let length = self.length()?;
+                Tag::ByteArray(self.bytes(length)?.to_vec())
+            }
+            8 => Tag::String(self.string()?),
+            9 => {
+                let item = self.array::<1>()?[0];
+                let length = self.length()?;
+                let items = (0..length)
+                    .map(|_| self.tag(item, depth + 1))
+                    .collect::<Result<_, _>>()?;
+                Tag::List(items)
+            }
+            10 => {
+                let mut tags = HashMap::new();
+                loop {
+                    let kind = self.array::<1>()?[0];
+                    if kind == 0 {
+                        break;
+                    }
+                    let name = self.string()?;
+                    tags.insert(name, self.tag(kind, depth + 1)?);
+                }
+                Tag::Compound(tags)
+            }
+            11 => {
+                let length = self.length()?;
+                let items = (0..length)
+                    .map(|_| Ok(i32::from_be_bytes(self.array()?)))
+                    .collect::<Result<_, String>>()?;
+                Tag::IntArray(items)
+            }
+            12 => {
+                let length = self.length()?;
+                let items = (0..length)
+                    .map(|_| Ok(i64::from_be_bytes(self.array()?)))
+                    .collect::<Result<_, String>>()?;
+                Tag::LongArray(items)
+            }
+            _ => return Err(format!("Unknown NBT tag {}", kind)),
+        })
+    }
+}
+
+/// Gzip, zlib or uncompressed NBT
+fn read_nbt(data: &[u8]) -> Result<Tag, String> {
+    let mut decompressed = Vec::new();
+    let data = match data {
+        [0x1f, 0x8b, ..] => {
+            flate2::read::GzDecoder::new(data)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| e.to_string())?;
+            &decompressed
+        }
+        [0x78, ..] => {
+            flate2::read::ZlibDecoder::new(data)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| e.to_string())?;
+            &decompressed
+        }
+        _ => data,
+    };
+
+    NbtReader { data, pos: 0 }.root()
+}
+
+/// Blocks placed by their position, y is up
+struct Blocks {
+    blocks: Vec<(Vector3<u32>, u32)>,
+    size: Vector3<u32>,
+}
+
+impl Blocks {
+    fn to_octree(&self) -> CpuOctree {
+        let longest = self.size.x.max(self.size.y).max(self.size.z).max(2);
+        let depth = 32 - (longest - 1).leading_zeros();
+
+        let mut leaves: Vec<(u64, Node)> = self
+            .blocks
+            .iter()
+            .map(|(pos, id)| {
+                let node = Node::new(CHUNK_OFFSET + id, Voxel::new(0, 0, 0));
+                (morton(*pos, depth), node)
+            })
+            .collect();
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, depth)
+    }
+}
+
+/// Sponge schematics from version 1 to 3
+fn load_schem(root: &Tag, table: &mut BlockTable) -> Result<Blocks, String> {
+    // Version 3 wraps everything in another compound
+    let schematic = root.get("Schematic").unwrap_or(root);
+    let size = Vector3::new(
+        schematic.get_int("Width")? as u16 as u32,
+        schematic.get_int("Height")? as u16 as u32,
+        schematic.get_int("Length")? as u16 as u32,
+    );
+
+    let (palette, data) = match schematic.get("Blocks") {
+        Some(blocks) => (blocks.get("Palette"), blocks.get_bytes("Data")?),
+        None => (schematic.get("Palette"), schematic.get_bytes("BlockData")?),
+    };
+    let palette = match palette {
+        Some(Tag::Compound(palette)) => palette,
+        _ => return Err("Schematic has no palette".to_string()),
+    };
+
+    let mut ids = HashMap::new();
+    for (state, index) in palette {
+        let index = index.int().ok_or("Palette index isn't a number")?;
+        ids.insert(index, table.lookup(state));
+    }
+
+    let mut blocks = Vec::new();
+    let mut bytes = data.iter();
+    for y in 0..size.y {
+        for z in 0..size.z {
+            for x in 0..size.x {
+                let index = read_varint(&mut bytes).ok_or("Block data is truncated")?;
+                if let Some(Some(id)) = ids.get(&index) {
+                    blocks.push((Vector3::new(x, y, z), *id));
+                }
+            }
+        }
+    }
+
+    Ok(Blocks { blocks, size })
+}
+
+fn read_varint<'a>(bytes: &mut impl Iterator<Item = &'a u8>) -> Option<i64> {
+    let mut value = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.next()?;
+        value |= ((byte & 0x7f) as i64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn legacy_name(id: u16) -> String {
+    LEGACY_BLOCKS
+        .iter()
+        .find(|(legacy, _)| *legacy as u16 == id)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("legacy_{}", id))
+}
+
+/// Numeric id of block `i`, the 4 bits above the byte in `ids` are packed two to a byte in
+/// `add` with the even blocks in the low half
+fn legacy_id(ids: &[u8], add: &[u8], i: usize) -> u16 {
+    let high = add
+        .get(i / 2)
+        .map_or(0, |b| if i.is_multiple_of(2) { b & 15 } else { b >> 4 });
+    (high as u16) << 8 | ids[i] as u16
+}
+
+/// MCEdit schematics from before 1.13, with numeric block ids
+fn load_legacy_schematic(root: &Tag, table: &mut BlockTable) -> Result<Blocks, String> {
+    let size = Vector3::new(
+        root.get_int("Width")? as u16 as u32,
+        root.get_int("Height")? as u16 as u32,
+        root.get_int("Length")? as u16 as u32,
+    );
+    let ids = root.get_bytes("Blocks")?;
+    let add = root.get_bytes("AddBlocks").unwrap_or(&[]);
+    if ids.len() < (size.x * size.y * size.z) as usize {
+        return Err("Block data is truncated".to_string());
+    }
+
+    let mut names = HashMap::new();
+    let mut blocks = Vec::new();
+    for y in 0..size.y {
+        for z in 0..size.z {
+            for x in 0..size.x {
+                let i = ((y * size.z + z) * size.x + x) as usize;
+                let id = legacy_id(ids, add, i);
+                let block = *names
+                    .entry(id)
+                    .or_insert_with(|| table.lookup(&legacy_name(id)));
+                if let Some(block) = block {
+                    blocks.push((Vector3::new(x, y, z), block));
+                }
+            }
+        }
+    }
+
+    Ok(Blocks { blocks, size })
+}
+
+/// Anvil region files, 32 by 32 chunks of 16 by 16 blocks
+fn load_region(data: &[u8], table: &mut BlockTable) -> Result<Blocks, String> {
+    if data.len() < 8192 {
+        return Err("Region file is truncated".to_string());
+    }
+
+    // Positions with y relative to the bottom of the world, moved up once it's known
+    let mut placed = Vec::new();
+    let mut min_y = i64::MAX;
+    let mut max_y = i64::MIN;
+    for i in 0..1024 {
+        let location = u32::from_be_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        let offset = (location >> 8) as usize * 4096;
+        if offset == 0 {
+            continue;
+        }
+
+        let header = data
+            .get(offset..offset + 5)
+            .ok_or("Chunk is outside the region file")?;
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        // Chunks that were allocated but never saved
+        if length == 0 {
+            continue;
+        }
+        let chunk = data
+            .get(offset + 5..offset + 4 + length)
+            .ok_or("Chunk is truncated")?;
+        let chunk = match header[4] {
+            1..=3 => read_nbt(chunk)?,
+            compression => return Err(format!("Unknown chunk compression {}", compression)),
+        };
+
+        let base = Vector3::new((i % 32) as i64 * 16, 0, (i / 32) as i64 * 16);
+        // 1.18 moved everything out of "Level"
+        let level = chunk.get("Level").unwrap_or(&chunk);
+        let sections = level
+            .get("sections")
+            .or_else(|| level.get("Sections"))
+            .map(Tag::list)
+            .unwrap_or(&[]);
+        for section in sections {
+            let y = section.get_int("Y")?;
+            let states = match section_states(section, table)? {
+                Some(states) => states,
+                None => continue,
+            };
+            min_y = min_y.min(y);
+            max_y = max_y.max(y);
+
+            for (i, id) in states.into_iter().enumerate() {
+                if let Some(id) = id {
+                    let i = i as i64;
+                    let pos = Vector3::new(i & 15, y * 16 + (i >> 8), (i >> 4) & 15);
+                    placed.push((base + pos, id));
+                }
+            }
+        }
+    }
+
+    if placed.is_empty() {
+        return Err("There are no blocks".to_string());
+    }
+
+    let size = Vector3::new(512, ((max_y - min_y + 1).max(1) * 16) as u32, 512);
+    let blocks = placed
+        .into_iter()
+        .map(|(pos, id)| {
+            let pos = Vector3::new(pos.x, pos.y - min_y * 16, pos.z);
+            (pos.cast::<u32>().unwrap(), id)
+        })
+        .collect();
+
+    Ok(Blocks { blocks, size })
+}
+
+/// Block ids of the 4096 blocks in a section in y, z, x order, None if it's all empty
+fn section_states(
+    section: &Tag,
+    table: &mut BlockTable,
+) -> Result<Option<Vec<Option<u32>>>, String> {
+    // 1.18 and later, then 1.13 to 1.17
+    let (palette, data) = match section.get("block_states") {
+        Some(states) => (states.get("palette"), states.get("data")),
+        None => (section.get("Palette"), section.get("BlockStates")),
+    };
+
+    let palette = match palette {
+        Some(palette) => palette.list(),
+        None => {
+            // Before 1.13 sections have numeric ids
+            return match section.get_bytes("Blocks") {
+                Ok(ids) if ids.len() == 4096 => {
+                    let add = section.get_bytes("Add").unwrap_or(&[]);
+                    let states = (0..4096)
+                        .map(|i| table.lookup(&legacy_name(legacy_id(ids, add, i))))
+                        .collect();
+                    Ok(Some(states))
+                }
+                _ => Ok(None),
+            };
+        }
+    };
+
+    let ids: Vec<Option<u32>> = palette
+        .iter()
+        .map(|state| {
+            state
+                .get("Name")
+                .and_then(Tag::string)
+                .and_then(|name| table.lookup(name))
+        })
+        .collect();
+    if ids.iter().all(Option::is_none) {
+        return Ok(None);
+    }
+
+    let data: &[i64] = match data {
+        Some(Tag::LongArray(data)) => data,
+        // A single entry palette has no data
+        _ => return Ok(Some(vec![ids[0]; 4096])),
+    };
+
+    let bits = (usize::BITS - (ids.len().max(2) - 1).leading_zeros()).max(4) as usize;
+    // 1.16 stopped entries from spanning two longs
+    let padded = data.len() == 4096usize.div_ceil(64 / bits);
+    let mask = (1u64 << bits) - 1;
+
+    let mut states = Vec::with_capacity(4096);
+    for i in 0..4096 {
+        let index = if padded {
+            let per_long = 64 / bits;
+            let long = *data.get(i / per_long).ok_or("Block states are truncated")? as u64;
+            (long >> ((i % per_long) * bits)) & mask
+        } else {
+            let bit = i * bits;
+            let low = *data.get(bit / 64).ok_or("Block states are truncated")? as u64;
+            let mut index = low >> (bit % 64);
+            if bit % 64 + bits > 64 {
+                let high = *data.get(bit / 64 + 1).ok_or("Block states are truncated")? as u64;
+                index |= high << (64 - bit % 64);
+            }
+            index & mask
+        };
+        states.push(ids.get(index as usize).copied().flatten());
+    }
+
+    Ok(Some(states))
+}
+
+impl CpuOctree {
+    /// Reads a Sponge .schem, an MCEdit .schematic or an Anvil .mca region file. Each block
+    /// becomes a reference to the block id `table` maps it to, in an octree just big enough
+    /// to hold it.
+    pub fn load_minecraft(
+        data: &[u8],
+        extension: &str,
+        table: &mut BlockTable,
+    ) -> Result<CpuOctree, String> {
+        let blocks = match extension {
+            "mca" => load_region(data, table)?,
+            _ => {
+                let root = read_nbt(data)?;
+                let schematic = root.get("Schematic").unwrap_or(&root);
+                if schematic
+                    .get("Blocks")
+                    .is_some_and(|blocks| matches!(blocks, Tag::ByteArray(_)))
+                {
+                    load_legacy_schematic(&root, table)?
+                } else {
+                    load_schem(&root, table)?
+                }
+            }
+        };
+
+        if blocks.blocks.is_empty() {
+            return Err("There are no blocks".to_string());
+        }
+        Ok(blocks.to_octree())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn named(kind: u8, name: &str, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend((name.len() as u16).to_be_bytes());
+        bytes.extend(name.as_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    fn compound(tags: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = tags.concat();
+        bytes.push(0);
+        bytes
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+        bytes.extend(value.as_bytes());
+        bytes
+    }
+
+    fn list(kind: u8, items: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend((items.len() as i32).to_be_bytes());
+        bytes.extend(items.concat());
+        bytes
+    }
+
+    fn array(items: &[u8]) -> Vec<u8> {
+        let mut bytes = (items.len() as i32).to_be_bytes().to_vec();
+        bytes.extend(items);
+        bytes
+    }
+
+    fn longs(items: &[i64]) -> Vec<u8> {
+        let mut bytes = (items.len() as i32).to_be_bytes().to_vec();
+        bytes.extend(items.iter().flat_map(|long| long.to_be_bytes()));
+        bytes
+    }
+
+    /// A 1.18 section with a palette of `names` and blocks using `indices` in y, z, x order
+    fn section(names: &[&str], indices: &[u64], bits: usize, padded: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        if padded {
+            for chunk in indices.chunks(64 / bits) {
+                let long = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0, |long, (i, index)| long | index << (i * bits));
+                data.push(long as i64);
+            }
+        } else {
+            data = vec![0i64; (indices.len() * bits).div_ceil(64)];
+            for (i, &index) in indices.iter().enumerate() {
+                let bit = i * bits;
+                data[bit / 64] |= (index << (bit % 64)) as i64;
+                if bit % 64 + bits > 64 {
+                    data[bit / 64 + 1] |= (index >> (64 - bit % 64)) as i64;
+                }
+            }
+        }
+
+        let palette: Vec<_> = names
+            .iter()
+            .map(|name| compound(&[named(8, "Name", &string(name))]))
+            .collect();
+        compound(&[
+            named(1, "Y", &[0]),
+            named(
+                10,
+                "block_states",
+                &compound(&[
+                    named(9, "palette", &list(10, &palette)),
+                    named(12, "data", &longs(&data)),
+                ]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn reads_nbt() {
+        let nbt = named(
+            10,
+            "root",
+            &compound(&[
+                named(1, "byte", &[0xff]),
+                named(3, "int", &1234567i32.to_be_bytes()),
+                named(8, "name", &string("stone")),
+                named(7, "bytes", &array(&[1, 2, 3])),
+                named(
+                    9,
+                    "list",
+                    &list(
+                        2,
+                        &[7i16.to_be_bytes().to_vec(), 8i16.to_be_bytes().to_vec()],
+                    ),
+                ),
+                named(
+                    10,
+                    "inner",
+                    &compound(&[named(4, "long", &(-5i64).to_be_bytes())]),
+                ),
+            ]),
+        );
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&nbt).unwrap();
+        for data in [nbt.clone(), gzip.finish().unwrap()] {
+            let root = read_nbt(&data).unwrap();
+            assert_eq!(root.get_int("byte"), Ok(-1));
+            assert_eq!(root.get_int("int"), Ok(1234567));
+            assert_eq!(root.get("name").and_then(Tag::string), Some("stone"));
+            assert_eq!(root.get_bytes("bytes"), Ok(&[1u8, 2, 3][..]));
+            let list: Vec<_> = root
+                .get("list")
+                .unwrap()
+                .list()
+                .iter()
+                .map(|tag| tag.int())
+                .collect();
+            assert_eq!(list, [Some(7), Some(8)]);
+            assert_eq!(root.get("inner").unwrap().get_int("long"), Ok(-5));
+        }
+
+        assert!(read_nbt(&nbt[..nbt.len() - 3]).is_err());
+        assert!(read_nbt(&named(13, "", &[])).is_err());
+    }
+
+    #[test]
+    fn unpacks_palette_bits() {
+        let rules: String = (1..17).map(|id| format!("b{} {}\n", id, id)).collect();
+        let mut table = BlockTable::new(&rules).unwrap();
+        let names: Vec<String> = (0..17).map(|id| format!("b{}", id)).collect();
+        let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+        names[0] = "air";
+        let indices: Vec<u64> = (0..4096).map(|i| (i * 7 % 17) as u64).collect();
+
+        // 17 entries take 5 bits, which only fit 12 to a long so 1.16 padding matters
+        for padded in [false, true] {
+            let nbt = section(&names, &indices, 5, padded);
+            let tag = NbtReader { data: &nbt, pos: 0 }.tag(10, 0).unwrap();
+            let states = section_states(&tag, &mut table).unwrap().unwrap();
+            let expected: Vec<_> = indices
+                .iter()
+                .map(|&i| (i > 0).then_some(i as u32))
+                .collect();
+            assert_eq!(states, expected);
+        }
+
+        // Small palettes still use 4 bits
+        let indices: Vec<u64> = (0..4096).map(|i| (i % 2) as u64).collect();
+        let nbt = section(&["air", "b3"], &indices, 4, true);
+        let tag = NbtReader { data: &nbt, pos: 0 }.tag(10, 0).unwrap();
+        let states = section_states(&tag, &mut table).unwrap().unwrap();
+        assert_eq!(states[..4], [None, Some(3), None, Some(3)]);
+    }
+
+    #[test]
+    fn add_blocks_put_even_blocks_in_the_low_half() {
+        let mut table = BlockTable::new("legacy_257 5\nlegacy_513 6").unwrap();
+        let nbt = named(
+            10,
+            "Schematic",
+            &compound(&[
+                named(2, "Width", &2i16.to_be_bytes()),
+                named(2, "Height", &1i16.to_be_bytes()),
+                named(2, "Length", &1i16.to_be_bytes()),
+                named(7, "Blocks", &array(&[1, 1])),
+                named(7, "AddBlocks", &array(&[0x21])),
+            ]),
+        );
+        let root = read_nbt(&nbt).unwrap();
+        let blocks = load_legacy_schematic(&root, &mut table).unwrap();
+        assert_eq!(
+            blocks.blocks,
+            [(Vector3::new(0, 0, 0), 5), (Vector3::new(1, 0, 0), 6)]
+        );
+    }
+
+    #[test]
+    fn region_skips_empty_chunks() {
+        let mut table = BlockTable::new("b1 1").unwrap();
+        let indices: Vec<u64> = (0..4096).map(|i| (i == 0) as u64).collect();
+        let chunk = named(
+            10,
+            "",
+            &compound(&[named(
+                9,
+                "sections",
+                &list(10, &[section(&["air", "b1"], &indices, 4, true)]),
+            )]),
+        );
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        zlib.write_all(&chunk).unwrap();
+        let chunk = zlib.finish().unwrap();
+
+        // Chunk 0 is allocated but has a length of 0, chunk 1 is the one above
+        let mut data = vec![0; 3 * 4096];
+        data[..4].copy_from_slice(&(2u32 << 8 | 1).to_be_bytes());
+        data[4..8].copy_from_slice(&(3u32 << 8 | 1).to_be_bytes());
+        data.extend((chunk.len() as u32 + 1).to_be_bytes());
+        data.push(2);
+        data.extend(&chunk);
+
+        let blocks = load_region(&data, &mut table).unwrap();
+        assert_eq!(blocks.blocks, [(Vector3::new(16, 0, 0), 1)]);
+
+        // Nothing but empty chunks
+        data[4..8].fill(0);
+        assert!(load_region(&data, &mut table).is_err());
+    }
+}
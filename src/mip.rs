@@ -0,0 +1,333 @@
+use super::*;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// How the colour of a coarse node is picked from its children
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum MipFilter {
+    /// Mean weighted by how much of each child is filled
+    #[default]
+    Mean,
+    /// Most common colour, keeps detailed models from turning muddy
+    Majority,
+    /// Mean in linear light so the brightness matches what the children would show
+    Luminance,
+    /// Mean, but nodes that are less than this full out of 255 are drawn see through until
+    /// they are subdivided so thin structures don't become solid blocks
+    Threshold(u8),
+}
+
+impl MipFilter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MipFilter::Mean => "mean",
+            MipFilter::Majority => "majority",
+            MipFilter::Luminance => "luminance",
+            MipFilter::Threshold(_) => "threshold",
+        }
+    }
+
+    /// Words written after the name in world metadata
+    pub fn to_words(self) -> String {
+        match self {
+            MipFilter::Threshold(threshold) => format!("threshold {}", threshold),
+            _ => self.name().to_string(),
+        }
+    }
+
+    pub fn from_words(words: &[&str]) -> Result<Self, String> {
+        match words {
+            ["mean"] => Ok(MipFilter::Mean),
+            ["majority"] => Ok(MipFilter::Majority),
+            ["luminance"] => Ok(MipFilter::Luminance),
+            ["threshold", threshold] => Ok(MipFilter::Threshold(parse(threshold)?)),
+            _ => Err(format!("Unknown mip filter '{}'", words.join(" "))),
+        }
+    }
+}
+
+impl CpuOctree {
+    /// Sets the colour and coverage of every internal node from its children, deepest level
+    /// first. `ref_mip` gives the colour of the blocks and chunks the octree references.
+    pub fn generate_mips(&mut self, ref_mip: &(dyn Fn(u32) -> Voxel + Sync), filter: MipFilter) {
+        self.nodes.par_iter_mut().for_each(|node| {
+            if node.pointer > CHUNK_OFFSET {
+                node.value = ref_mip(node.pointer - CHUNK_OFFSET);
+                node.coverage = 255;
+            } else if node.pointer == CHUNK_OFFSET {
+                node.coverage = leaf_coverage(node);
+            }
+        });
+
+        // Internal nodes grouped by depth
+        let mut levels = Vec::new();
+        let mut level: Vec<usize> = (0..8)
+            .filter(|i| self.nodes[*i].pointer < CHUNK_OFFSET)
+            .collect();
+        while !level.is_empty() {
+            let next = level
+                .iter()
+                .flat_map(|i| {
+                    let pointer = self.nodes[*i].pointer as usize;
+                    pointer..pointer + 8
+                })
+                .filter(|i| self.nodes[*i].pointer < CHUNK_OFFSET)
+                .collect();
+            levels.push(level);
+            level = next;
+        }
+
+        // Every node in a level only reads from the level below
+        for level in levels.iter().rev() {
+            let nodes = &self.nodes;
+            let mips: Vec<_> = level
+                .par_iter()
+                .map(|i| {
+                    let pointer = nodes[*i].pointer as usize;
+                    mip_of(&nodes[pointer..pointer + 8], filter)
+                })
+                .collect();
+
+            for (i, (value, coverage)) in level.iter().zip(mips) {
+                self.nodes[*i].value = value;
+                self.nodes[*i].coverage = coverage;
+            }
+        }
+
+        self.top_mip = self.mip(0, filter);
+    }
+
+    /// Updates the mips after the node at the end of `path` was edited, touching only its
+    /// ancestors. `path` holds node indices from the top level down as returned by `path_to`.
+    pub fn update_mips(&mut self, path: &[usize], filter: MipFilter) {
+        for i in path.iter().rev() {
+            let node = self.nodes[*i];
+            if node.pointer < CHUNK_OFFSET {
+                let pointer = node.pointer as usize;
+                let (value, coverage) = mip_of(&self.nodes[pointer..pointer + 8], filter);
+                self.nodes[*i].value = value;
+                self.nodes[*i].coverage = coverage;
+            } else if node.pointer == CHUNK_OFFSET {
+                self.nodes[*i].coverage = leaf_coverage(&node);
+            } else {
+                self.nodes[*i].coverage = 255;
+            }
+        }
+
+        self.top_mip = self.mip(0, filter);
+    }
+
+    /// Node indices from the top level down to the leaf containing `pos`
+    #[allow(dead_code)]
+    pub fn path_to(&self, pos: Vector3<f32>, max_depth: Option<u32>) -> Vec<usize> {
+        let mut path = Vec::new();
+        descend(self, pos, max_depth, false, &mut |visit| {
+            path.push(visit.index)
+        });
+        path
+    }
+
+    /// Colours the references were last given, so the mips can be rebuilt without the blocks
+    /// and chunks
+    pub fn ref_mips(&self) -> HashMap<u32, Voxel> {
+        let mut ref_mips = HashMap::new();
+        for node in &self.nodes {
+            if node.pointer > CHUNK_OFFSET {
+                ref_mips.insert(node.pointer - CHUNK_OFFSET, node.value);
+            }
+        }
+        ref_mips
+    }
+
+    /// Colour of the children starting at `node`
+    pub fn mip(&self, node: usize, filter: MipFilter) -> Voxel {
+        mip_of(&self.nodes[node..node + 8], filter).0
+    }
+}
+
+fn leaf_coverage(node: &Node) -> u8 {
+    if node.value == Voxel::new(0, 0, 0) {
+        0
+    } else {
+        255
+    }
+}
+
+/// Colour and coverage of a node from its 8 children
+fn mip_of(children: &[Node], filter: MipFilter) -> (Voxel, u8) {
+    let filled = || children.iter().filter(|child| child.coverage > 0);
+    let coverage = filled().map(|child| child.coverage as u32).sum::<u32>();
+
+    let colour = match filter {
+        MipFilter::Mean | MipFilter::Threshold(_) => weighted_mean(filled(), |c| c),
+        MipFilter::Majority => filled()
+            .max_by_key(|child| {
+                let votes: u32 = filled()
+                    .filter(|other| other.value == child.value)
+                    .map(|other| other.coverage as u32)
+                    .sum();
+                // Ties are broken by colour so child order doesn't matter
+                (votes, std::cmp::Reverse(child.value.to_cpu_value()))
+            })
+            .map(|child| {
+                let voxel = child.value;
+                Vector3::new(voxel.r as f32, voxel.g as f32, voxel.b as f32)
+            })
+            .unwrap_or_else(Vector3::zero),
+        MipFilter::Luminance => {
+            let linear = weighted_mean(filled(), |c| (c / 255.0).powf(2.2));
+            linear.map(|c| c.powf(1.0 / 2.2) * 255.0)
+        }
+    };
+
+    // Internal nodes are never black, coverage is rounded up so anything with content in it
+    // keeps some weight
+    let voxel = Voxel::new(
+        (colour.x.round() as u8).max(1),
+        (colour.y.round() as u8).max(1),
+        (colour.z.round() as u8).max(1),
+    );
+    (voxel, coverage.div_ceil(8) as u8)
+}
+
+/// Mean of `f` applied to each colour channel, weighted by coverage. Weighting stops a child
+/// with a single voxel in it counting as much as a solid one.
+fn weighted_mean<'a>(
+    children: impl Iterator<Item = &'a Node>,
+    f: impl Fn(f32) -> f32,
+) -> Vector3<f32> {
+    let mut colour = Vector3::new(0.0, 0.0, 0.0);
+    let mut weight = 0.0;
+
+    for child in children {
+        let voxel = child.value;
+        let w = child.coverage as f32;
+        colour += Vector3::new(f(voxel.r as f32), f(voxel.g as f32), f(voxel.b as f32)) * w;
+        weight += w;
+    }
+
+    colour / weight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(r: u8, g: u8, b: u8) -> Node {
+        Node::new(CHUNK_OFFSET, Voxel::new(r, g, b))
+    }
+
+    /// A group under the first top level node, the rest of the top level empty
+    fn one_group(children: [Node; 8]) -> CpuOctree {
+        let mut nodes = vec![leaf(0, 0, 0); 8];
+        nodes[0] = Node::new(8, Voxel::new(0, 0, 0));
+        nodes.extend(children);
+        CpuOctree {
+            nodes,
+            top_mip: Voxel::new(0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn mean_is_weighted_by_coverage() {
+        let mut children = [leaf(0, 0, 0); 8];
+        children[0] = leaf(200, 0, 0);
+        children[1] = Node::new(CHUNK_OFFSET + 1, Voxel::new(0, 0, 0));
+        let mut octree = one_group(children);
+        octree.generate_mips(&|_| Voxel::new(0, 0, 100), MipFilter::Mean);
+
+        // The reference counts as full, empty leaves don't count towards the colour
+        assert_eq!(octree.nodes[9].value, Voxel::new(0, 0, 100));
+        assert_eq!(octree.nodes[0].value, Voxel::new(100, 1, 50));
+        assert_eq!(octree.nodes[0].coverage, 64);
+        // Only one of the eight top level nodes has anything in it
+        assert_eq!(octree.top_mip, Voxel::new(100, 1, 50));
+        assert_eq!(mip_of(&octree.nodes[0..8], MipFilter::Mean).1, 8);
+    }
+
+    #[test]
+    fn update_mips_matches_generate() {
+        let mut octree = one_group([leaf(10, 20, 30); 8]);
+        octree.generate_mips(&|_| Voxel::new(0, 0, 0), MipFilter::Mean);
+
+        let path = octree.path_to(Vector3::new(-0.9, -0.9, -0.9), None);
+        assert_eq!(path, vec![0, 8]);
+        octree.nodes[8] = leaf(250, 20, 30);
+        octree.update_mips(&path, MipFilter::Mean);
+
+        let mut regenerated = CpuOctree {
+            nodes: octree.nodes.clone(),
+            top_mip: Voxel::new(0, 0, 0),
+        };
+        regenerated.generate_mips(&|_| Voxel::new(0, 0, 0), MipFilter::Mean);
+        assert_eq!(octree.nodes[0].value, regenerated.nodes[0].value);
+        assert_eq!(octree.nodes[0].value, Voxel::new(40, 20, 30));
+        assert_eq!(octree.top_mip, regenerated.top_mip);
+    }
+
+    #[test]
+    fn filters() {
+        let mut children = [leaf(0, 0, 0); 8];
+        children[0] = leaf(255, 0, 0);
+        children[1] = leaf(255, 0, 0);
+        children[2] = leaf(0, 0, 255);
+        for child in &mut children {
+            child.coverage = leaf_coverage(child);
+        }
+
+        let (mean, coverage) = mip_of(&children, MipFilter::Mean);
+        assert_eq!((mean, coverage), (Voxel::new(170, 1, 85), 96));
+        let (majority, _) = mip_of(&children, MipFilter::Majority);
+        assert_eq!(majority, Voxel::new(255, 1, 1));
+        // Brighter than the mean because the mean is taken in linear light
+        let (luminance, _) = mip_of(&children, MipFilter::Luminance);
+        assert_eq!(luminance, Voxel::new(212, 1, 155));
+
+        // Ties go to the lower colour whatever order the children are in
+        children[3] = leaf(0, 0, 255);
+        children[3].coverage = 255;
+        assert_eq!(
+            mip_of(&children, MipFilter::Majority).0,
+            Voxel::new(1, 1, 255)
+        );
+        children.reverse();
+        assert_eq!(
+            mip_of(&children, MipFilter::Majority).0,
+            Voxel::new(1, 1, 255)
+        );
+    }
+
+    #[test]
+    fn threshold_hides_sparse_nodes() {
+        let mut sparse = Node::new(8, Voxel::new(50, 50, 50));
+        sparse.coverage = 31;
+        let threshold = MipFilter::Threshold(32);
+        assert_eq!(
+            sparse.to_value(threshold),
+            (VOXEL_OFFSET + SEE_THROUGH) << 4
+        );
+        assert_eq!(sparse.to_value(MipFilter::Mean), sparse.value.to_value());
+
+        sparse.coverage = 32;
+        assert_eq!(sparse.to_value(threshold), sparse.value.to_value());
+        // Leaves are drawn whatever their coverage
+        let leaf = leaf(50, 50, 50);
+        assert_eq!(leaf.to_value(threshold), leaf.value.to_value());
+    }
+
+    #[test]
+    fn filter_words_roundtrip() {
+        for filter in [
+            MipFilter::Mean,
+            MipFilter::Majority,
+            MipFilter::Luminance,
+            MipFilter::Threshold(40),
+        ] {
+            let words = filter.to_words();
+            let words: Vec<&str> = words.split_whitespace().collect();
+            assert_eq!(MipFilter::from_words(&words), Ok(filter));
+        }
+        assert!(MipFilter::from_words(&["threshold"]).is_err());
+        assert!(MipFilter::from_words(&["median"]).is_err());
+    }
+}
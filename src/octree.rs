@@ -1,9 +1,13 @@
-use cgmath::*;
+use super::*;
 
 // First palette colour is empty voxel
 // const PALETTE: [u32; 3] = [0x00000000, 0x0000FF00, 0x000000FF];
 pub const VOXEL_OFFSET: u32 = 134217728;
+/// Voxel value for coarse nodes that are too empty to draw but still get subdivided when rays
+/// pass through them
+pub const SEE_THROUGH: u32 = 16777216;
 
+#[repr(C)]
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Voxel {
     pub r: u8,
@@ -48,13 +52,13 @@ pub struct Octree {
 }
 
 impl Octree {
-    pub fn new(mask: [Voxel; 8]) -> Self {
+    pub fn new(mask: [u32; 8]) -> Self {
         let mut nodes = Vec::new();
         let mut positions = Vec::new();
         let hole_stack = Vec::new();
 
         for i in 0..8 {
-            nodes.push(mask[i].to_value());
+            nodes.push(mask[i]);
             positions.push(Octree::pos_offset(i, 1));
         }
 
@@ -69,7 +73,7 @@ impl Octree {
         self.nodes[index] >> 4
     }
 
-    pub fn subdivide(&mut self, node: usize, mask: [Voxel; 8], depth: u32) {
+    pub fn subdivide(&mut self, node: usize, mask: [u32; 8], depth: u32) {
         if self.get_node(node) < VOXEL_OFFSET {
             panic!("Node already subdivided!");
         }
@@ -79,14 +83,14 @@ impl Octree {
             self.nodes[node] = create_node(index);
 
             for i in 0..8 {
-                self.nodes[index + i] = mask[i].to_value();
+                self.nodes[index + i] = mask[i];
                 self.positions[index + i] = pos + Octree::pos_offset(i, depth);
             }
         } else {
             self.nodes[node] = create_node(self.nodes.len());
 
             for i in 0..8 {
-                self.nodes.push(mask[i].to_value());
+                self.nodes.push(mask[i]);
                 self.positions.push(pos + Octree::pos_offset(i, depth));
             }
         }
@@ -115,29 +119,8 @@ impl Octree {
         pos: Vector3<f32>,
         max_depth: Option<u32>,
     ) -> (usize, u32, Vector3<f32>) {
-        let mut node_index = 0;
-        let mut node_pos = Vector3::zero();
-        let mut depth = 0;
-        loop {
-            depth += 1;
-
-            let p = Vector3::new(
-                (pos.x >= node_pos.x) as usize,
-                (pos.y >= node_pos.y) as usize,
-                (pos.z >= node_pos.z) as usize,
-            );
-            let child_index = p.x * 4 + p.y * 2 + p.z;
-
-            node_pos += Octree::pos_offset(child_index, depth);
-
-            if self.get_node(node_index + child_index) >= VOXEL_OFFSET
-                || depth == max_depth.unwrap_or(u32::MAX)
-            {
-                return (node_index + child_index, depth, node_pos);
-            }
-
-            node_index = self.get_node(node_index + child_index) as usize;
-        }
+        let leaf = descend(self, pos, max_depth, false, &mut |_| {}).unwrap();
+        (leaf.index, leaf.depth, leaf.centre())
     }
 
     pub fn expanded(&self, size: usize) -> Vec<u32> {
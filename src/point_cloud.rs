@@ -0,0 +1,478 @@
+use super::*;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum PlyFormat {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Clone, Debug)]
+struct PlyProperty {
+    name: String,
+    /// Type of the value, or of each item for lists
+    kind: String,
+    /// Type of the length before the items, for lists
+    list: Option<String>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+/// Calls `f` with every point in the file and its colour if it has one. Points are read
+/// straight from the file so the whole cloud is never in memory.
+fn read_points(path: &Path, f: &mut dyn FnMut(Vector3<f32>, Option<Voxel>)) -> Result<(), String> {
+    let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(file);
+    match path.extension().and_then(std::ffi::OsStr::to_str) {
+        Some("ply") => read_ply(&mut reader, f),
+        Some("xyz") => read_xyz(&mut reader, f),
+        _ => Err("Unknown point cloud type".to_string()),
+    }
+}
+
+/// One point per line as "x y z" or "x y z r g b", colours are 0 to 255 unless they have a
+/// decimal point. Lines that don't start with 3 numbers are skipped.
+fn read_xyz(
+    reader: &mut dyn BufRead,
+    f: &mut dyn FnMut(Vector3<f32>, Option<Voxel>),
+) -> Result<(), String> {
+    for line in reader.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let words: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        let numbers: Vec<f32> = words.iter().map_while(|word| word.parse().ok()).collect();
+        if numbers.len() < 3 {
+            continue;
+        }
+
+        let pos = Vector3::new(numbers[0], numbers[1], numbers[2]);
+        let colour = if numbers.len() >= 6 {
+            let unit = words[3..6].iter().any(|word| word.contains('.'));
+            Some(colour_from(&numbers[3..6], unit))
+        } else {
+            None
+        };
+        f(pos, colour);
+    }
+    Ok(())
+}
+
+fn colour_from(channels: &[f32], unit: bool) -> Voxel {
+    let channel = |c: f32| {
+        if unit {
+            (c.clamp(0.0, 1.0) * 255.0).round() as u8
+        } else {
+            c.clamp(0.0, 255.0).round() as u8
+        }
+    };
+    non_black(Voxel::new(
+        channel(channels[0]),
+        channel(channels[1]),
+        channel(channels[2]),
+    ))
+}
+
+/// Points are the vertex element, faces and anything else after it are ignored
+fn read_ply(
+    reader: &mut dyn BufRead,
+    f: &mut dyn FnMut(Vector3<f32>, Option<Voxel>),
+) -> Result<(), String> {
+    let mut line = String::new();
+    let mut next_line = |reader: &mut dyn BufRead| -> Result<String, String> {
+        line.clear();
+        if reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Err("PLY header is truncated".to_string());
+        }
+        Ok(line.trim().to_string())
+    };
+
+    if next_line(reader)? != "ply" {
+        return Err("Not a PLY file".to_string());
+    }
+
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    loop {
+        let line = next_line(reader)?;
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["format", "ascii", _] => format = Some(PlyFormat::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(PlyFormat::LittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(PlyFormat::BigEndian),
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: parse(count)?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", list, kind, name] => {
+                let element = elements.last_mut().ok_or("Property before any element")?;
+                element.properties.push(PlyProperty {
+                    name: name.to_string(),
+                    kind: kind.to_string(),
+                    list: Some(list.to_string()),
+                });
+            }
+            ["property", kind, name] => {
+                let element = elements.last_mut().ok_or("Property before any element")?;
+                element.properties.push(PlyProperty {
+                    name: name.to_string(),
+                    kind: kind.to_string(),
+                    list: None,
+                });
+            }
+            ["end_header"] => break,
+            _ => {}
+        }
+    }
+    let format = format.ok_or("PLY file has no format")?;
+
+    for element in &elements {
+        let find = |names: &[&str]| {
+            element
+                .properties
+                .iter()
+                .position(|property| names.contains(&property.name.as_str()))
+        };
+        let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+        let colour = [
+            find(&["red", "r", "diffuse_red"]),
+            find(&["green", "g", "diffuse_green"]),
+            find(&["blue", "b", "diffuse_blue"]),
+        ];
+        // Floats are 0 to 1, integers 0 to 255
+        let unit = colour.iter().flatten().any(|i| {
+            element.properties[*i].kind.starts_with("float")
+                || element.properties[*i].kind == "double"
+        });
+
+        let is_vertex = element.name == "vertex";
+        let mut values = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            let mut words = String::new();
+            if format == PlyFormat::Ascii
+                && reader.read_line(&mut words).map_err(|e| e.to_string())? == 0
+            {
+                return Err("PLY file is truncated".to_string());
+            }
+            let mut words = words.split_whitespace();
+
+            for (property, value) in element.properties.iter().zip(values.iter_mut()) {
+                let mut read = |kind: &str| match format {
+                    PlyFormat::Ascii => words
+                        .next()
+                        .ok_or_else(|| "PLY line has too few values".to_string())
+                        .and_then(parse::<f64>),
+                    _ => read_binary(reader, kind, format == PlyFormat::BigEndian),
+                };
+
+                match &property.list {
+                    Some(list) => {
+                        let count = read(list)? as usize;
+                        for _ in 0..count {
+                            read(&property.kind)?;
+                        }
+                    }
+                    None => *value = read(&property.kind)?,
+                }
+            }
+
+            if is_vertex {
+                let get = |i: Option<usize>| i.map(|i| values[i] as f32);
+                let pos = match position.map(get) {
+                    [Some(x), Some(y), Some(z)] => Vector3::new(x, y, z),
+                    _ => return Err("PLY vertices have no position".to_string()),
+                };
+                let colour = match colour.map(get) {
+                    [Some(r), Some(g), Some(b)] => Some(colour_from(&[r, g, b], unit)),
+                    _ => None,
+                };
+                f(pos, colour);
+            }
+        }
+
+        if is_vertex {
+            return Ok(());
+        }
+    }
+
+    Err("PLY file has no vertices".to_string())
+}
+
+fn read_binary(reader: &mut dyn BufRead, kind: &str, big_endian: bool) -> Result<f64, String> {
+    let size = match kind {
+        "char" | "uchar" | "int8" | "uint8" => 1,
+        "short" | "ushort" | "int16" | "uint16" => 2,
+        "int" | "uint" | "float" | "int32" | "uint32" | "float32" => 4,
+        "double" | "float64" => 8,
+        _ => return Err(format!("Unknown PLY type '{}'", kind)),
+    };
+    let mut bytes = [0; 8];
+    reader
+        .read_exact(&mut bytes[..size])
+        .map_err(|_| "PLY file is truncated".to_string())?;
+    if big_endian {
+        bytes[..size].reverse();
+    }
+
+    let two = [bytes[0], bytes[1]];
+    let four = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    Ok(match kind {
+        "char" | "int8" => bytes[0] as i8 as f64,
+        "uchar" | "uint8" => bytes[0] as f64,
+        "short" | "int16" => i16::from_le_bytes(two) as f64,
+        "ushort" | "uint16" => u16::from_le_bytes(two) as f64,
+        "int" | "int32" => i32::from_le_bytes(four) as f64,
+        "uint" | "uint32" => u32::from_le_bytes(four) as f64,
+        "float" | "float32" => f32::from_le_bytes(four) as f64,
+        _ => f64::from_le_bytes(bytes),
+    })
+}
+
+impl CpuOctree {
+    /// Reads an ASCII or binary .ply or an .xyz point cloud twice, once to find its bounds
+    /// and once to put the points in voxels at `depth`. The longest side of the bounds fills
+    /// the octree. Points in the same voxel have their colours averaged, points without a
+    /// colour are grey.
+    pub fn load_point_cloud(path: &Path, depth: u32) -> Result<CpuOctree, String> {
+        if depth == 0 || depth > 21 {
+            return Err("Depth has to be from 1 to 21".to_string());
+        }
+
+        let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+        let mut count = 0u64;
+        read_points(path, &mut |pos, _| {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(pos[axis]);
+                max[axis] = max[axis].max(pos[axis]);
+            }
+            count += 1;
+        })?;
+        if count == 0 {
+            return Err("Point cloud has no points".to_string());
+        }
+
+        let size = (1u32 << depth) as f32;
+        let extent = max - min;
+        let longest = extent.x.max(extent.y).max(extent.z);
+        let scale = if longest > 0.0 { size / longest } else { 1.0 };
+        // Centred along the shorter sides
+        let offset = (Vector3::new(size, size, size) - extent * scale) / 2.0;
+
+        // Summed colour and count for every voxel
+        let mut voxels: HashMap<u64, ([u64; 3], u64)> = HashMap::new();
+        read_points(path, &mut |pos, colour| {
+            let grid = ((pos - min) * scale + offset).map(|c| (c as u32).min(size as u32 - 1));
+            let colour = colour.unwrap_or(DEFAULT_COLOUR);
+            let voxel = voxels.entry(morton(grid, depth)).or_default();
+            voxel.0[0] += colour.r as u64;
+            voxel.0[1] += colour.g as u64;
+            voxel.0[2] += colour.b as u64;
+            voxel.1 += 1;
+        })?;
+
+        let mut voxels: Vec<(u64, Node)> = voxels
+            .into_iter()
+            .map(|(code, (sum, count))| {
+                let channel = |c: u64| ((c + count / 2) / count) as u8;
+                let colour = Voxel::new(channel(sum[0]), channel(sum[1]), channel(sum[2]));
+                (code, Node::new(CHUNK_OFFSET, non_black(colour)))
+            })
+            .collect();
+        voxels.sort_unstable_by_key(|(code, _)| *code);
+
+        Ok(CpuOctree::from_sorted_leaves(&voxels, depth))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Points = Vec<(Vector3<f32>, Option<Voxel>)>;
+
+    fn ply(data: &[u8]) -> Result<Points, String> {
+        let mut points = Vec::new();
+        read_ply(&mut &data[..], &mut |pos, colour| {
+            points.push((pos, colour))
+        })?;
+        Ok(points)
+    }
+
+    fn xyz(text: &str) -> Points {
+        let mut points = Vec::new();
+        read_xyz(&mut text.as_bytes(), &mut |pos, colour| {
+            points.push((pos, colour))
+        })
+        .unwrap();
+        points
+    }
+
+    #[test]
+    fn ascii_ply() {
+        let data = "ply\nformat ascii 1.0\ncomment made by hand\nelement vertex 2\n\
+            property float x\nproperty float y\nproperty float z\n\
+            property float diffuse_red\nproperty float diffuse_green\nproperty float diffuse_blue\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            1 2 3 1 0.5 0\n-1 0.5 4 0 0 0\n3 0 1 1\n";
+        assert_eq!(
+            ply(data.as_bytes()).unwrap(),
+            [
+                (Vector3::new(1.0, 2.0, 3.0), Some(Voxel::new(255, 128, 0))),
+                (Vector3::new(-1.0, 0.5, 4.0), Some(Voxel::new(1, 1, 1))),
+            ]
+        );
+
+        assert!(ply(
+            b"ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nend_header\n1\n2\n"
+        )
+        .is_err());
+        // Too few values and too few lines
+        let header = "ply\nformat ascii 1.0\nelement vertex 2\n\
+            property float x\nproperty float y\nproperty float z\nend_header\n";
+        assert!(ply(format!("{}1 2\n4 5 6\n", header).as_bytes()).is_err());
+        assert!(ply(format!("{}1 2 3\n", header).as_bytes()).is_err());
+        assert!(ply(b"ply\nformat ascii 1.0\nelement face 0\nend_header\n").is_err());
+        assert!(ply(b"ply\nelement vertex 0\nend_header\n").is_err());
+        assert!(ply(b"xyz\n").is_err());
+    }
+
+    /// A camera with a list before the vertices and faces after them
+    fn binary_file(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "big" } else { "little" };
+        let mut data = format!(
+            "ply\nformat binary_{}_endian 1.0\n\
+            element camera 1\nproperty list uchar int indices\nproperty float scale\n\
+            element vertex 2\nproperty float x\nproperty float y\nproperty float z\n\
+            property uchar red\nproperty uchar green\nproperty uchar blue\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n",
+            format
+        )
+        .into_bytes();
+
+        let mut push = |bytes: &[u8]| {
+            let mut bytes = bytes.to_vec();
+            if big_endian {
+                bytes.reverse();
+            }
+            data.extend(bytes);
+        };
+        push(&[2]);
+        push(&7i32.to_le_bytes());
+        push(&8i32.to_le_bytes());
+        push(&1.5f32.to_le_bytes());
+        for (pos, colour) in [
+            ([1.0f32, 2.0, 3.0], [10, 20, 30]),
+            ([-1.0, 0.5, 4.0], [255, 0, 0]),
+        ] {
+            for c in pos {
+                push(&c.to_le_bytes());
+            }
+            for c in colour {
+                push(&[c]);
+            }
+        }
+        push(&[3]);
+        for i in 0..3i32 {
+            push(&i.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn binary_ply() {
+        let expected = [
+            (Vector3::new(1.0, 2.0, 3.0), Some(Voxel::new(10, 20, 30))),
+            (Vector3::new(-1.0, 0.5, 4.0), Some(Voxel::new(255, 0, 0))),
+        ];
+        for big_endian in [false, true] {
+            let data = binary_file(big_endian);
+            assert_eq!(ply(&data).unwrap(), expected, "big endian {}", big_endian);
+
+            // Cut off in the second vertex
+            assert!(ply(&data[..data.len() - 15]).is_err());
+        }
+    }
+
+    #[test]
+    fn xyz_lines() {
+        let points = xyz("x y z\n1 2 3\n4,5,6,255,128,0\n# comment\n7 8 9 1.0 0.5 0\n10 11\n");
+        assert_eq!(
+            points,
+            [
+                (Vector3::new(1.0, 2.0, 3.0), None),
+                (Vector3::new(4.0, 5.0, 6.0), Some(Voxel::new(255, 128, 0))),
+                (Vector3::new(7.0, 8.0, 9.0), Some(Voxel::new(255, 128, 0))),
+            ]
+        );
+        assert_eq!(xyz("0 0 0 0 0 0")[0].1, Some(Voxel::new(1, 1, 1)));
+    }
+
+    /// Colours of the solid voxels at `depth`
+    fn solid(octree: &CpuOctree, depth: u32) -> Vec<((u32, u32, u32), Voxel)> {
+        let size = 1 << depth;
+        let mut solid = Vec::new();
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    let (index, _) = octree.lookup(Vector3::new(x, y, z), depth);
+                    let node = octree.nodes[index];
+                    if is_solid(node) {
+                        solid.push(((x, y, z), node.value));
+                    }
+                }
+            }
+        }
+        solid
+    }
+
+    fn load(name: &str, text: &str, depth: u32) -> Result<CpuOctree, String> {
+        let path = std::env::temp_dir().join(format!("{}_{}.xyz", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let octree = CpuOctree::load_point_cloud(&path, depth);
+        std::fs::remove_file(&path).unwrap();
+        octree
+    }
+
+    #[test]
+    fn points_in_a_leaf_are_averaged() {
+        let octree = load(
+            "average_points",
+            "0 0 0 255 0 0\n0.1 0 0 0 0 255\n1 1 1 0 255 0\n",
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            solid(&octree, 1),
+            [
+                ((0, 0, 0), Voxel::new(128, 0, 128)),
+                ((1, 1, 1), Voxel::new(0, 255, 0))
+            ]
+        );
+    }
+
+    #[test]
+    fn flat_clouds_are_centred() {
+        // Flat along z so it's centred in the middle layer
+        let octree = load("flat_points", "0 0 5\n4 0 5\n0 2 5\n4 2 5\n", 2).unwrap();
+        let positions: Vec<_> = solid(&octree, 2).into_iter().map(|(pos, _)| pos).collect();
+        assert_eq!(positions, [(0, 1, 2), (0, 3, 2), (3, 1, 2), (3, 3, 2)]);
+        assert_eq!(solid(&octree, 2)[0].1, DEFAULT_COLOUR);
+
+        // A single point ends up in the middle
+        let octree = load("one_point", "1 1 1\n1 1 1\n", 2).unwrap();
+        assert_eq!(solid(&octree, 2)[0].0, (2, 2, 2));
+
+        assert!(load("no_points", "x y z\n", 2).is_err());
+        assert!(load("zero_depth", "1 1 1\n", 0).is_err());
+    }
+}
@@ -3,15 +3,34 @@ struct Uniforms {
     dispatch_size: u32;
     base_depth: u32;
     chunk_depth: u32;
+    biome_count: u32;
+    biome_scale: f32;
+    deep_block: u32;
+    deep_depth: f32;
     misc1: f32;
     misc2: f32;
     misc3: f32;
+    cave_count: u32;
+    cave_floor_block: u32;
+    // (surface, subsurface, subsurface_depth, base)
+    biomes: array<vec4<u32>, 4>;
+    cave_ceiling_block: u32;
+    sdf_slope: f32;
+    capacity: u32;
+    // 1: worms, 2: caverns, 3: ravines
+    cave_kinds: vec4<u32>;
+    caves: array<vec4<f32>, 4>;
+    seed_offset: vec4<f32>;
 };
 
 struct AtomicU32s {
     len: atomic<u32>;
     panic: atomic<u32>;
-    lock: atomic<u32>;
+    retry: atomic<u32>;
+    data: [[stride(4)]] array<atomic<u32>>;
+};
+
+struct Classifications {
     data: [[stride(4)]] array<u32>;
 };
 
@@ -19,23 +38,32 @@ struct AtomicU32s {
 var<uniform> u: Uniforms; // Uniforms
 [[group(0), binding(1)]]
 var<storage, read_write> n: AtomicU32s; // Nodes
+[[group(0), binding(2)]]
+var<storage, read_write> c: Classifications; // Classifications
 
 var<workgroup> counter: atomic<u32>;
 
 let BLOCK_OFFSET = 2147483648u;
+// Set on a node while a thread is subdividing it
+let LOCK_BIT = 1073741824u;
 
 fn get_node(index: u32) -> u32 {
-    return n.data[index];
+    return atomicLoad(&n.data[index]);
 }
 
 fn add_voxels() -> u32 {
     let index = atomicAdd(&n.len, 8u);
 
-    if (index >= 256000000u) {
+    if (index + 8u > u.capacity) {
         atomicStore(&n.panic, 1u);
         return 0u;
     }
 
+    // The buffer isn't cleared between jobs
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        atomicStore(&n.data[index + i], 0u);
+    }
+
     return index;
 }
 
@@ -77,6 +105,7 @@ fn find_voxel(
         if (
             tnipt >= BLOCK_OFFSET
             || tnipt == 0u
+            || (tnipt & LOCK_BIT) != 0u
             || (depth != 0u && depth == max_depth)
         ) {
             return FoundVoxel(node_index + child_index, depth, node_pos);
@@ -88,24 +117,40 @@ fn find_voxel(
     return FoundVoxel(0u, 0u, vec3<f32>(0.0));
 }
 
+// Gives up and asks for another pass if the node is locked by another thread
 fn put_in_voxel(pos: vec3<f32>, block_id: u32, depth: u32) {
     loop {
         let found_voxel = find_voxel(pos, depth);
         if (found_voxel.depth >= depth) {
-            n.data[found_voxel.index] = BLOCK_OFFSET + block_id;
-
-            // Release global lock
-            // atomicStore(&n.lock, 0u);
+            atomicStore(&n.data[found_voxel.index], BLOCK_OFFSET + block_id);
             return;
-        } else {
-            n.data[found_voxel.index] = add_voxels();
+        }
 
-            // Release global lock
-            // atomicStore(&n.lock, 0u);
+        // Only the thread that takes the lock on an empty node gets to subdivide it so no
+        // voxels are written into a group that is later replaced
+        let old = atomicOr(&n.data[found_voxel.index], LOCK_BIT);
+        if (old == 0u) {
+            let children = add_voxels();
+            atomicStore(&n.data[found_voxel.index], children);
+            if (children == 0u) {
+                // Out of space, the panic flag is already set
+                return;
+            }
+        } else if ((old & LOCK_BIT) == 0u) {
+            // Subdivided by someone else since we looked, put their pointer back
+            atomicAnd(&n.data[found_voxel.index], ~LOCK_BIT);
+        } else {
+            atomicAdd(&n.retry, 1u);
+            return;
         }
     }
 }
 
+// Noise offset by the seed
+fn noise(pos: vec3<f32>) -> f32 {
+    return simplexNoise3(pos + u.seed_offset.xyz);
+}
+
 fn sdf(pos: vec3<f32>) -> f32 {
     var v = 0.0;
 
@@ -114,7 +159,7 @@ fn sdf(pos: vec3<f32>) -> f32 {
 
     // Some basic noise
     let scale = 1.6;
-    let base_noise = simplexNoise3(pos * scale) + 0.5 * simplexNoise3(pos * scale * 2.0);
+    let base_noise = noise(pos * scale) + 0.5 * noise(pos * scale * 2.0);
     v = v + 0.07 * base_noise;
 
     // Distance from center
@@ -125,7 +170,7 @@ fn sdf(pos: vec3<f32>) -> f32 {
     v = smin(v, cone, 0.2);
 
     let scale = vec3<f32>(2.3, 0.4, 2.3);
-    var spike_noise = simplexNoise3(pos * scale) + 0.5 * simplexNoise3(pos * scale * 2.0);
+    var spike_noise = noise(pos * scale) + 0.5 * noise(pos * scale * 2.0);
     let height_bias = smoothStep(0.0, -1.5, pos.y) + smoothStep(0.0, 0.2, pos.y);
     spike_noise = spike_noise + 1.6 * dist + height_bias * 2.0 - 1.0;
     // v = smin(v, spike_noise, u.misc1);
@@ -147,10 +192,115 @@ fn sdf(pos: vec3<f32>) -> f32 {
     return v;
 }
 
+// Negative inside the carved volume
+fn cave(pos: vec3<f32>, kind: u32, params: vec4<f32>) -> f32 {
+    let scale = params.x;
+    if (kind == 1u) {
+        let a = noise(pos * scale);
+        let b = noise(pos * scale + vec3<f32>(31.4, 17.2, 53.9));
+        return length(vec2<f32>(a, b)) - params.y;
+    } else if (kind == 2u) {
+        return params.y - noise(pos * scale);
+    } else if (kind == 3u) {
+        let crack = abs(noise(vec3<f32>(pos.x, 0.0, pos.z) * scale));
+        return max(crack - params.y, params.z - pos.y);
+    }
+
+    return 1.0;
+}
+
+// Furthest a cave's value can change per unit of distance. simplexNoise3 changes by at most
+// NOISE_SLOPE per unit, found by maximising the sum of its 4 corner gradients over a cell.
+let NOISE_SLOPE = 10.0;
+
+fn cave_slope(kind: u32, params: vec4<f32>) -> f32 {
+    let slope = NOISE_SLOPE * params.x;
+    if (kind == 1u) {
+        // Length of two noise values
+        return slope * 1.4142135;
+    } else if (kind == 3u) {
+        return max(slope, 1.0);
+    }
+
+    return slope;
+}
+
+// Island sdf with the caves carved out
+fn terrain(pos: vec3<f32>) -> f32 {
+    var v = sdf(pos);
+    if (v > 0.0) {
+        return v;
+    }
+
+    for (var i = 0u; i < u.cave_count; i = i + 1u) {
+        v = max(v, -cave(pos, u.cave_kinds[i], u.caves[i]));
+    }
+
+    return v;
+}
+
+// Empty only because a cave was carved there
+fn is_cave_air(pos: vec3<f32>) -> bool {
+    return sdf(pos) < 0.0 && terrain(pos) > 0.0;
+}
+
+fn biome_at(pos: vec3<f32>) -> vec4<u32> {
+    let count = max(u.biome_count, 1u);
+    let value = noise(vec3<f32>(pos.x, 0.0, pos.z) * u.biome_scale) * 0.5 + 0.5;
+    let index = min(u32(clamp(value, 0.0, 1.0) * f32(count)), count - 1u);
+    return u.biomes[index];
+}
+
+// Number of solid voxels directly above pos, stopping at limit
+fn depth_below_surface(pos: vec3<f32>, voxel_size: f32, limit: u32) -> u32 {
+    var depth = 0u;
+    loop {
+        if (depth >= limit) {
+            break;
+        }
+
+        let above = pos + vec3<f32>(0.0, f32(depth + 1u) * voxel_size, 0.0);
+        if (terrain(above) > 0.0) {
+            break;
+        }
+
+        depth = depth + 1u;
+    }
+
+    return depth;
+}
+
+fn layer_block(pos: vec3<f32>, v: f32, voxel_size: f32) -> u32 {
+    // The sdf is only a rough distance so it's good enough for the deep layer
+    if (-v > u.deep_depth * voxel_size) {
+        return u.deep_block;
+    }
+
+    let biome = biome_at(pos);
+    let depth = depth_below_surface(pos, voxel_size, biome.z + 1u);
+    if (depth == 0u) {
+        let above = pos + vec3<f32>(0.0, voxel_size, 0.0);
+        if (u.cave_count > 0u && is_cave_air(above)) {
+            return u.cave_floor_block;
+        }
+
+        return biome.x;
+    }
+
+    let below = pos - vec3<f32>(0.0, voxel_size, 0.0);
+    if (u.cave_count > 0u && is_cave_air(below)) {
+        return u.cave_ceiling_block;
+    } else if (depth <= biome.z) {
+        return biome.y;
+    }
+
+    return biome.w;
+}
+
 [[stage(compute), workgroup_size(32)]]
 fn main([[builtin(global_invocation_id)]] global_id: vec3<u32>) {
     let uurrgghh = u.misc1;
-    let uurrgghh = n.data[0];
+    let uurrgghh = atomicLoad(&n.data[0]);
 
     if (atomicLoad(&n.panic) == 1u) {
         // Somthing has gone **terribly** wrong! Just return to reduce damage.
@@ -186,18 +336,10 @@ fn main([[builtin(global_invocation_id)]] global_id: vec3<u32>) {
     //     }
     // }
 
-    let v = sdf(world_pos);
+    let v = terrain(world_pos);
     if (v < 0.0) {
         let voxel_size = 2.0 / f32(1u << full_depth);
-
-        let above = world_pos + vec3<f32>(0.0, voxel_size, 0.0);
-        let above_sdf = sdf(above);
-        
-        if (above_sdf > 0.0) {
-            put_in_voxel(chunk_pos, 3u, u.chunk_depth);
-        } else {
-            put_in_voxel(chunk_pos, 1u, u.chunk_depth);
-        }
+        put_in_voxel(chunk_pos, layer_block(world_pos, v, voxel_size), u.chunk_depth);
     }
 
     // if (atomicAdd(&counter, 1u) < 256u) {
@@ -207,4 +349,39 @@ fn main([[builtin(global_invocation_id)]] global_id: vec3<u32>) {
     // }
 
     // atomicStore(&n.lock, (atomicLoad(&n.lock) + 24929u) % 16777216u);
-}
\ No newline at end of file
+}
+
+// Works out whether each child of the node at u.pos is empty (0), full of the deep block (1) or
+// needs voxelizing (2) from the sdf and caves at its center
+[[stage(compute), workgroup_size(8)]]
+fn classify([[builtin(global_invocation_id)]] global_id: vec3<u32>) {
+    let i = global_id.x;
+    let size = 2.0 / f32(1u << u.base_depth);
+    let offset = vec3<f32>(f32((i >> 2u) & 1u), f32((i >> 1u) & 1u), f32(i & 1u));
+    let center = u.pos.xyz + (offset + 0.5) * size;
+    let voxel_size = 2.0 / f32(1u << (u.base_depth + u.chunk_depth));
+
+    // Distance from the center to a corner, each field can change by its slope times this
+    let reach = size * 0.8660254;
+
+    // Caves only remove solid, so the sdf alone proves a node empty
+    let v = sdf(center);
+    if (v > u.sdf_slope * reach) {
+        c.data[i] = 0u;
+        return;
+    }
+
+    // Full needs the surface and every cave to stay more than the deep layer away
+    var depth = -v - u.sdf_slope * reach;
+    for (var j = 0u; j < u.cave_count; j = j + 1u) {
+        let kind = u.cave_kinds[j];
+        let cave_depth = cave(center, kind, u.caves[j]) - cave_slope(kind, u.caves[j]) * reach;
+        depth = min(depth, cave_depth);
+    }
+
+    if (depth > u.deep_depth * voxel_size) {
+        c.data[i] = 1u;
+    } else {
+        c.data[i] = 2u;
+    }
+}
@@ -1,31 +1,216 @@
 use super::*;
 
 const WORK_GROUP_SIZE: u32 = 32;
-const CHUNK_SIZE: usize = 256000000; // little less than the worst case for 2^8 octree 19173960
-const ITERATIONS: u32 = 134217728; // (2^8)^3 16777216
+/// Size of the gpu node buffer in u32s. Chunks that need more than this are split into smaller
+/// jobs so generation works with a fixed amount of memory.
+const NODE_CAPACITY: usize = 32000000;
+/// len, panic and retry
+const HEADER_SIZE: usize = 3;
 
+const MAX_BIOMES: usize = 4;
+const MAX_CAVES: usize = 4;
+
+#[derive(Clone)]
 pub struct GenSettings {
     pub seed: u32,
+    /// The world is a grid of 2^world_depth chunks along each axis
+    pub world_depth: u32,
+    /// Each chunk is 2^chunk_depth voxels along each axis
+    pub chunk_depth: u32,
+    /// Upper bound on how fast the sdf changes with distance, used to prove regions of the
+    /// world empty or full without voxelizing them
+    pub sdf_slope: f32,
     pub scale: f32,
     pub height: f32,
+    /// Frequency of the noise that picks a biome for each column
+    pub biome_scale: f32,
+    pub biomes: Vec<Biome>,
+    /// Block used for everything deeper than `deep_depth` voxels below the surface
+    pub deep_block: u32,
+    pub deep_depth: f32,
+    /// Volumes carved out of the terrain
+    pub caves: Vec<Cave>,
+    pub cave_floor_block: u32,
+    pub cave_ceiling_block: u32,
+    /// Collapse solid regions that can't be seen into single nodes
+    pub hollow: bool,
+    pub mip_filter: MipFilter,
 }
 
 impl Default for GenSettings {
     fn default() -> Self {
         GenSettings {
             seed: 0,
+            world_depth: 1,
+            chunk_depth: 9,
+            sdf_slope: 2.0,
             scale: 0.2,
             height: 0.2,
+            biome_scale: 1.5,
+            biomes: vec![
+                Biome::new("Meadow", BLOCK_GRASS, BLOCK_DIRT, 3, BLOCK_STONE),
+                Biome::new("Rocky", BLOCK_STONE, BLOCK_STONE, 0, BLOCK_STONE),
+                Biome::new("Crystal", BLOCK_CRYSTAL, BLOCK_SLATE, 2, BLOCK_STONE),
+            ],
+            deep_block: BLOCK_SLATE,
+            deep_depth: 40.0,
+            caves: vec![
+                Cave::Worms {
+                    scale: 4.0,
+                    radius: 0.06,
+                },
+                Cave::Caverns {
+                    scale: 3.0,
+                    threshold: 0.75,
+                },
+                Cave::Ravines {
+                    scale: 2.0,
+                    width: 0.02,
+                    floor: -0.1,
+                },
+            ],
+            cave_floor_block: BLOCK_DIRT,
+            cave_ceiling_block: BLOCK_STONE,
+            hollow: false,
+            mip_filter: MipFilter::Mean,
+        }
+    }
+}
+
+impl GenSettings {
+    pub fn write_manifest(&self, out: &mut String) {
+        out.push_str(&format!("gen seed {}\n", self.seed));
+        out.push_str(&format!("gen world_depth {}\n", self.world_depth));
+        out.push_str(&format!("gen chunk_depth {}\n", self.chunk_depth));
+        out.push_str(&format!("gen sdf_slope {}\n", self.sdf_slope));
+        out.push_str(&format!("gen scale {}\n", self.scale));
+        out.push_str(&format!("gen height {}\n", self.height));
+        out.push_str(&format!("gen biome_scale {}\n", self.biome_scale));
+        out.push_str(&format!("gen deep_block {}\n", self.deep_block));
+        out.push_str(&format!("gen deep_depth {}\n", self.deep_depth));
+        out.push_str(&format!("gen cave_floor_block {}\n", self.cave_floor_block));
+        out.push_str(&format!(
+            "gen cave_ceiling_block {}\n",
+            self.cave_ceiling_block
+        ));
+        out.push_str(&format!("gen hollow {}\n", self.hollow));
+        out.push_str(&format!("gen mip_filter {}\n", self.mip_filter.to_words()));
+        for biome in &self.biomes {
+            out.push_str(&format!(
+                "gen biome {} {} {} {} {}\n",
+                biome.name.replace(' ', "_"),
+                biome.surface,
+                biome.subsurface,
+                biome.subsurface_depth,
+                biome.base
+            ));
+        }
+        for cave in &self.caves {
+            let line = match *cave {
+                Cave::Worms { scale, radius } => format!("worms {} {}", scale, radius),
+                Cave::Caverns { scale, threshold } => format!("caverns {} {}", scale, threshold),
+                Cave::Ravines {
+                    scale,
+                    width,
+                    floor,
+                } => format!("ravines {} {} {}", scale, width, floor),
+            };
+            out.push_str(&format!("gen cave {}\n", line));
+        }
+    }
+
+    /// Reads one `gen` line written by write_manifest
+    pub fn read_manifest(&mut self, words: &[&str]) -> Result<(), String> {
+        match words {
+            ["seed", v] => self.seed = parse(v)?,
+            ["world_depth", v] => self.world_depth = parse(v)?,
+            ["chunk_depth", v] => self.chunk_depth = parse(v)?,
+            ["sdf_slope", v] => self.sdf_slope = parse(v)?,
+            ["scale", v] => self.scale = parse(v)?,
+            ["height", v] => self.height = parse(v)?,
+            ["biome_scale", v] => self.biome_scale = parse(v)?,
+            ["deep_block", v] => self.deep_block = parse(v)?,
+            ["deep_depth", v] => self.deep_depth = parse(v)?,
+            ["cave_floor_block", v] => self.cave_floor_block = parse(v)?,
+            ["cave_ceiling_block", v] => self.cave_ceiling_block = parse(v)?,
+            ["hollow", v] => self.hollow = parse(v)?,
+            ["mip_filter", filter @ ..] => self.mip_filter = MipFilter::from_words(filter)?,
+            ["biome", name, surface, subsurface, subsurface_depth, base] => {
+                self.biomes.push(Biome::new(
+                    name,
+                    parse(surface)?,
+                    parse(subsurface)?,
+                    parse(subsurface_depth)?,
+                    parse(base)?,
+                ))
+            }
+            ["cave", "worms", scale, radius] => self.caves.push(Cave::Worms {
+                scale: parse(scale)?,
+                radius: parse(radius)?,
+            }),
+            ["cave", "caverns", scale, threshold] => self.caves.push(Cave::Caverns {
+                scale: parse(scale)?,
+                threshold: parse(threshold)?,
+            }),
+            ["cave", "ravines", scale, width, floor] => self.caves.push(Cave::Ravines {
+                scale: parse(scale)?,
+                width: parse(width)?,
+                floor: parse(floor)?,
+            }),
+            _ => return Err(format!("Unknown generator setting '{}'", words.join(" "))),
+        }
+
+        Ok(())
+    }
+}
+
+/// Stack of blocks placed from the surface downwards
+#[derive(Clone)]
+pub struct Biome {
+    pub name: String,
+    pub surface: u32,
+    pub subsurface: u32,
+    /// Number of subsurface voxels under the surface voxel
+    pub subsurface_depth: u32,
+    pub base: u32,
+}
+
+impl Biome {
+    pub fn new(
+        name: &str,
+        surface: u32,
+        subsurface: u32,
+        subsurface_depth: u32,
+        base: u32,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            surface,
+            subsurface,
+            subsurface_depth,
+            base,
         }
     }
 }
 
+/// What the sdf can prove about a region of the world
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Classification {
+    Empty,
+    /// Solid and deep enough that it's all `deep_block`
+    Full,
+    Mixed,
+}
+
 pub struct Procedural {
     pipeline: wgpu::ComputePipeline,
+    classify_pipeline: wgpu::ComputePipeline,
     pub uniforms: Uniforms,
     uniform_buffer: wgpu::Buffer,
     cpu_octree: wgpu::Buffer,
+    classification_buffer: wgpu::Buffer,
     compute_bind_group: wgpu::BindGroup,
+    classify_bind_group: wgpu::BindGroup,
 }
 
 impl Procedural {
@@ -57,22 +242,24 @@ impl Procedural {
                 entry_point: "main",
             });
 
-        let inital_octree = CpuOctree::new(255);
-        let mut raw = inital_octree.raw();
-        raw.insert(0, raw.len() as u32);
-        raw.insert(1, 0);
-        raw.insert(2, 0);
-        raw.extend(std::iter::repeat(0).take(CHUNK_SIZE.checked_sub(raw.len()).unwrap()));
-
-        let cpu_octree = gpu
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: None,
-                contents: bytemuck::cast_slice(&raw),
-                usage: wgpu::BufferUsages::STORAGE
-                    | wgpu::BufferUsages::MAP_READ
-                    | wgpu::BufferUsages::COPY_DST,
-            });
+        let classify_pipeline =
+            gpu.device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: None,
+                    layout: None,
+                    module: &shader,
+                    entry_point: "classify",
+                });
+
+        // Cleared by the gpu as it's used so it never has to be uploaded
+        let cpu_octree = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: ((HEADER_SIZE + NODE_CAPACITY) * 4) as u64,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
 
         let compute_bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
@@ -89,25 +276,192 @@ impl Procedural {
             ],
         });
 
+        let classification_buffer =
+            gpu.device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: None,
+                    contents: bytemuck::cast_slice(&[0u32; 8]),
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::MAP_READ,
+                });
+
+        let classify_bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &classify_pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: classification_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
         Self {
             pipeline,
+            classify_pipeline,
             uniforms,
             uniform_buffer,
             cpu_octree,
+            classification_buffer,
             compute_bind_group,
+            classify_bind_group,
         }
     }
 
-    pub fn generate_chunk(&mut self, gpu: &Gpu, pos: Vector3<f32>, base_depth: u32) -> Option<CpuOctree> {
+    /// Classifies the 8 children of the node at `pos` (its lowest corner) that are at `depth`
+    pub fn classify(
+        &mut self,
+        gpu: &Gpu,
+        gen_settings: &GenSettings,
+        pos: Vector3<f32>,
+        depth: u32,
+    ) -> [Classification; 8] {
+        self.uniforms.pos = [pos.x, pos.y, pos.z, 0.0];
+        self.uniforms.base_depth = depth;
+        // So the voxel size works out the same as it does for the chunks
+        self.uniforms.chunk_depth = gen_settings.world_depth + gen_settings.chunk_depth - depth;
+        self.uniforms.set_gen_settings(gen_settings);
+
+        gpu.queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms]),
+        );
+
         let mut encoder = gpu
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
-        let dispatch_size = (ITERATIONS as f32 / WORK_GROUP_SIZE as f32).sqrt().ceil() as u32;
+        {
+            let mut compute_pass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+
+            compute_pass.set_pipeline(&self.classify_pipeline);
+            compute_pass.set_bind_group(0, &self.classify_bind_group, &[]);
+            compute_pass.dispatch(1, 1, 1);
+        }
+
+        gpu.queue.submit(Some(encoder.finish()));
+
+        let slice = self.classification_buffer.slice(..);
+        let future = slice.map_async(wgpu::MapMode::Read);
+
+        gpu.device.poll(wgpu::Maintain::Wait);
+
+        let mut classifications = [Classification::Mixed; 8];
+        if let Ok(()) = pollster::block_on(future) {
+            let data = slice.get_mapped_range();
+            let result: &[u32] = bytemuck::cast_slice(&data);
+            for i in 0..8 {
+                classifications[i] = match result[i] {
+                    0 => Classification::Empty,
+                    1 => Classification::Full,
+                    _ => Classification::Mixed,
+                };
+            }
+
+            drop(data);
+            self.classification_buffer.unmap();
+        } else {
+            panic!("Failed to read classification buffer!")
+        }
+
+        classifications
+    }
+
+    /// Generates the chunk with its lowest corner at `pos`. Chunks that don't fit in the node
+    /// buffer are generated as 8 smaller jobs and merged, stopping between them once
+    /// `progress` is cancelled.
+    pub fn generate_chunk(
+        &mut self,
+        gpu: &Gpu,
+        gen_settings: &GenSettings,
+        pos: Vector3<f32>,
+        base_depth: u32,
+        progress: &GenProgress,
+    ) -> Result<Option<CpuOctree>, GenError> {
+        let mut chunk = self.generate_region(
+            gpu,
+            gen_settings,
+            pos,
+            base_depth,
+            gen_settings.chunk_depth,
+            progress,
+        )?;
+        if let Some(chunk) = &mut chunk {
+            // The node order depends on which thread got to each node first
+            chunk.canonicalize();
+        }
+
+        Ok(chunk)
+    }
+
+    fn generate_region(
+        &mut self,
+        gpu: &Gpu,
+        gen_settings: &GenSettings,
+        pos: Vector3<f32>,
+        base_depth: u32,
+        depth: u32,
+        progress: &GenProgress,
+    ) -> Result<Option<CpuOctree>, GenError> {
+        if progress.is_cancelled() {
+            return Err(GenError::Cancelled);
+        }
+
+        match self.dispatch(gpu, gen_settings, pos, base_depth, depth) {
+            Err(GenError::Overflow) if depth > 1 => {
+                let mut octree = CpuOctree::new(0);
+                let size = 2.0 / (1u64 << (base_depth + 1)) as f32;
+                let mut empty = true;
+                for child_index in 0..8 {
+                    let offset = Vector3::new(
+                        (child_index >> 2) & 1,
+                        (child_index >> 1) & 1,
+                        child_index & 1,
+                    );
+                    let child_pos = pos + offset.cast::<f32>().unwrap() * size;
+
+                    let region = self.generate_region(
+                        gpu,
+                        gen_settings,
+                        child_pos,
+                        base_depth + 1,
+                        depth - 1,
+                        progress,
+                    )?;
+                    if let Some(region) = region {
+                        octree.graft(child_index, &region);
+                        empty = false;
+                    }
+                }
+
+                Ok(if empty { None } else { Some(octree) })
+            }
+            result => result,
+        }
+    }
+
+    /// Voxelizes `depth` levels below the node at `pos` and `base_depth` in one go
+    fn dispatch(
+        &mut self,
+        gpu: &Gpu,
+        gen_settings: &GenSettings,
+        pos: Vector3<f32>,
+        base_depth: u32,
+        depth: u32,
+    ) -> Result<Option<CpuOctree>, GenError> {
+        let iterations = 1u64 << (3 * depth);
+        let dispatch_size = (iterations as f64 / WORK_GROUP_SIZE as f64).sqrt().ceil() as u32;
         self.uniforms.dispatch_size = dispatch_size;
         self.uniforms.pos = [pos.x, pos.y, pos.z, 0.0];
         self.uniforms.base_depth = base_depth;
-        self.uniforms.chunk_depth = 9;
+        self.uniforms.chunk_depth = depth;
+        self.uniforms.capacity = NODE_CAPACITY as u32;
+        self.uniforms.set_gen_settings(gen_settings);
 
         gpu.queue.write_buffer(
             &self.uniform_buffer,
@@ -115,87 +469,121 @@ impl Procedural {
             bytemuck::cast_slice(&[self.uniforms]),
         );
 
-        let inital_octree = CpuOctree::new(0);
-        let mut raw = inital_octree.raw();
-        raw.insert(0, raw.len() as u32);
-        raw.insert(1, 0);
-        raw.extend(std::iter::repeat(0).take(CHUNK_SIZE.checked_sub(raw.len()).unwrap()));
+        // Header followed by the 8 empty root nodes, everything after is cleared by the gpu
+        let mut raw = vec![8, 0, 0];
+        raw.extend([0; 8]);
 
         gpu.queue
             .write_buffer(&self.cpu_octree, 0, bytemuck::cast_slice(&raw));
 
-        {
-            let mut compute_pass =
-                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+        // Voxels that ran into a node locked by another thread ask for another pass. Voxels
+        // that are already in the octree just get written again so the result is the same
+        // no matter how the threads were scheduled.
+        let len = loop {
+            let mut encoder = gpu
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+            {
+                let mut compute_pass =
+                    encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+
+                compute_pass.set_pipeline(&self.pipeline);
+                compute_pass.set_bind_group(0, &self.compute_bind_group, &[]);
+                compute_pass.dispatch(dispatch_size, dispatch_size, 1);
+            }
 
-            compute_pass.set_pipeline(&self.pipeline);
-            compute_pass.set_bind_group(0, &self.compute_bind_group, &[]);
-            compute_pass.dispatch(dispatch_size, dispatch_size, 1);
+            gpu.queue.submit(Some(encoder.finish()));
 
-            // println!(
-            //     "Dispatch size on x and y: {} (total threads: {})",
-            //     dispatch_size,
-            //     dispatch_size * dispatch_size * WORK_GROUP_SIZE
-            // );
-        }
+            let header = self.read_nodes(gpu, 0, HEADER_SIZE);
+            let (len, panic, retry) = (header[0] as usize, header[1], header[2]);
+            if panic != 0 {
+                return Err(GenError::Overflow);
+            } else if retry == 0 {
+                break len;
+            }
 
-        gpu.queue.submit(Some(encoder.finish()));
+            gpu.queue
+                .write_buffer(&self.cpu_octree, 8, bytemuck::cast_slice(&[0u32]));
+        };
+
+        // println!("Recevied {:.1} million nodes from gpu", len as f32 / 1000000.0);
+
+        if len <= 8 {
+            return Ok(None);
+        }
 
-        // Process output
         let mut cpu_octree = CpuOctree {
-            nodes: Vec::new(),
+            nodes: Vec::with_capacity(len),
             top_mip: Voxel::new(0, 0, 0),
         };
 
-        let slice = self.cpu_octree.slice(..);
+        for pointer in self.read_nodes(gpu, HEADER_SIZE, len) {
+            if pointer == 0 {
+                cpu_octree
+                    .nodes
+                    .push(Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0)));
+            } else {
+                cpu_octree
+                    .nodes
+                    .push(Node::new(pointer, Voxel::new(0, 0, 0)));
+            }
+        }
+
+        Ok(Some(cpu_octree))
+    }
+
+    /// Copies `len` u32s starting at `start` out of the node buffer
+    fn read_nodes(&self, gpu: &Gpu, start: usize, len: usize) -> Vec<u32> {
+        let staging = gpu.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (len * 4) as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(
+            &self.cpu_octree,
+            (start * 4) as u64,
+            &staging,
+            0,
+            (len * 4) as u64,
+        );
+        gpu.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
         let future = slice.map_async(wgpu::MapMode::Read);
 
         gpu.device.poll(wgpu::Maintain::Wait);
 
         if let Ok(()) = pollster::block_on(future) {
-            let mut data = slice.get_mapped_range_mut();
-            let result: &mut [u32] = unsafe { reinterpret::reinterpret_mut_slice(&mut data) };
-
-            // Reset atomic counter
-            let len = result[0] as usize;
-            result[0] = 0;
-            let panic = result[1] as usize;
-            result[1] = 0;
-
-            // println!("Recevied {:.1} million nodes from gpu", len as f32 / 1000000.0);
-
-            if len <= 8 || panic != 0 {
-                drop(data);
-                self.cpu_octree.unmap();
-                return None;
-            } else if len >= CHUNK_SIZE {
-                panic!("Too many nodes recived from gpu ({} nodes)", len);
-            }
-
-            // Offset for len and lock
-            for i in 3..(len + 3) {
-                let pointer = result[i];
-                if pointer == 0 {
-                    cpu_octree
-                        .nodes
-                        .push(Node::new(CHUNK_OFFSET, Voxel::new(0, 0, 0)));
-                } else {
-                    cpu_octree
-                        .nodes
-                        .push(Node::new(pointer, Voxel::new(0, 0, 0)));
-                }
-            }
-
+            let data = slice.get_mapped_range();
+            let result = bytemuck::cast_slice(&data).to_vec();
             drop(data);
-            self.cpu_octree.unmap();
+            staging.unmap();
+            result
         } else {
-            panic!("Failed to run get subdivision buffer!")
+            panic!("Failed to read node buffer!")
         }
+    }
+}
 
-        // println!("{:?}", cpu_octree);
-        // panic!();
+#[derive(Debug)]
+pub enum GenError {
+    /// The job needed more nodes than fit in the node buffer
+    Overflow,
+    Cancelled,
+}
 
-        Some(cpu_octree)
+impl std::fmt::Display for GenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GenError::Overflow => write!(f, "Ran out of space in the node buffer"),
+            GenError::Cancelled => write!(f, "Generation cancelled"),
+        }
     }
 }
 
@@ -318,6 +706,57 @@ impl Procedural {
 //     Ok(octree)
 // }
 
+/// Subtractive volume carved out of the terrain sdf
+#[derive(Clone, Copy)]
+pub enum Cave {
+    /// Tunnels where two noise fields are both close to zero
+    Worms { scale: f32, radius: f32 },
+    /// Open caverns wherever the noise is above the threshold
+    Caverns { scale: f32, threshold: f32 },
+    /// Narrow cracks cut down from the surface to the floor height
+    Ravines { scale: f32, width: f32, floor: f32 },
+}
+
+impl Cave {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cave::Worms { .. } => "Worm caves",
+            Cave::Caverns { .. } => "Caverns",
+            Cave::Ravines { .. } => "Ravines",
+        }
+    }
+
+    /// Returns (kind, params) in the layout used by procedual.wgsl
+    fn to_uniform(self) -> (u32, [f32; 4]) {
+        match self {
+            Cave::Worms { scale, radius } => (1, [scale, radius, 0.0, 0.0]),
+            Cave::Caverns { scale, threshold } => (2, [scale, threshold, 0.0, 0.0]),
+            Cave::Ravines {
+                scale,
+                width,
+                floor,
+            } => (3, [scale, width, floor, 0.0]),
+        }
+    }
+}
+
+/// Moves every noise lookup to a different part of the noise field
+fn seed_offset(seed: u32) -> [f32; 4] {
+    // splitmix64
+    let mut state = seed as u64;
+    let mut next = || {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    };
+
+    // Small enough to keep precision in the noise
+    let mut offset = || (next() % 25600) as f32 / 100.0;
+    [offset(), offset(), offset(), 0.0]
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
 pub struct Uniforms {
@@ -325,10 +764,24 @@ pub struct Uniforms {
     pub dispatch_size: u32,
     pub base_depth: u32,
     pub chunk_depth: u32,
+    pub biome_count: u32,
+    pub biome_scale: f32,
+    pub deep_block: u32,
+    pub deep_depth: f32,
     pub misc1: f32,
     pub misc2: f32,
     pub misc3: f32,
-    pub padding: [u32; 6],
+    pub cave_count: u32,
+    pub cave_floor_block: u32,
+    /// (surface, subsurface, subsurface_depth, base) for each biome
+    pub biomes: [[u32; 4]; MAX_BIOMES],
+    pub cave_ceiling_block: u32,
+    pub sdf_slope: f32,
+    pub capacity: u32,
+    pub padding: [u32; 1],
+    pub cave_kinds: [u32; MAX_CAVES],
+    pub caves: [[f32; 4]; MAX_CAVES],
+    pub seed_offset: [f32; 4],
 }
 
 impl Uniforms {
@@ -338,10 +791,137 @@ impl Uniforms {
             dispatch_size,
             base_depth,
             chunk_depth,
+            biome_count: 0,
+            biome_scale: 0.0,
+            deep_block: 0,
+            deep_depth: 0.0,
             misc1: 0.0,
             misc2: 0.0,
             misc3: 0.0,
-            padding: [0; 6],
+            cave_count: 0,
+            cave_floor_block: 0,
+            biomes: [[0; 4]; MAX_BIOMES],
+            cave_ceiling_block: 0,
+            sdf_slope: 0.0,
+            capacity: 0,
+            padding: [0; 1],
+            cave_kinds: [0; MAX_CAVES],
+            caves: [[0.0; 4]; MAX_CAVES],
+            seed_offset: [0.0; 4],
+        }
+    }
+
+    fn set_gen_settings(&mut self, gen_settings: &GenSettings) {
+        self.seed_offset = seed_offset(gen_settings.seed);
+
+        let biomes = &gen_settings.biomes[..gen_settings.biomes.len().min(MAX_BIOMES)];
+        self.biome_count = biomes.len() as u32;
+        self.biome_scale = gen_settings.biome_scale;
+        self.deep_block = gen_settings.deep_block;
+        self.deep_depth = gen_settings.deep_depth;
+        for (i, biome) in biomes.iter().enumerate() {
+            self.biomes[i] = [
+                biome.surface,
+                biome.subsurface,
+                biome.subsurface_depth,
+                biome.base,
+            ];
+        }
+
+        let caves = &gen_settings.caves[..gen_settings.caves.len().min(MAX_CAVES)];
+        self.cave_count = caves.len() as u32;
+        self.cave_floor_block = gen_settings.cave_floor_block;
+        self.cave_ceiling_block = gen_settings.cave_ceiling_block;
+        self.sdf_slope = gen_settings.sdf_slope;
+        for (i, cave) in caves.iter().enumerate() {
+            let (kind, params) = cave.to_uniform();
+            self.cave_kinds[i] = kind;
+            self.caves[i] = params;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Needs a gpu, there isn't always one where the tests run
+    fn headless() -> Option<Gpu> {
+        match pollster::block_on(Gpu::headless()) {
+            Ok(gpu) => Some(gpu),
+            Err(e) => {
+                println!("Skipped: {}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn cancelling_stops_chunks() {
+        let Some(gpu) = headless() else { return };
+        let mut procedural = Procedural::new(&gpu);
+        let progress = GenProgress::default();
+        progress.cancel();
+        let result = procedural.generate_chunk(
+            &gpu,
+            &GenSettings::default(),
+            Vector3::new(-1.0, -1.0, -1.0),
+            1,
+            &progress,
+        );
+        assert!(matches!(result, Err(GenError::Cancelled)));
+    }
+
+    #[test]
+    fn classify_agrees_with_voxelizing() {
+        let Some(gpu) = headless() else { return };
+        let mut procedural = Procedural::new(&gpu);
+
+        // A thin deep layer so some nodes can be full even with the default caves
+        let gen_settings = GenSettings {
+            world_depth: 2,
+            chunk_depth: 5,
+            deep_depth: 2.0,
+            ..Default::default()
+        };
+        let voxel_depth = gen_settings.world_depth + gen_settings.chunk_depth;
+        let mut empty = 0;
+        for i in 0..64u32 {
+            let pos = Vector3::new(i >> 4, (i >> 2) & 3, i & 3);
+            let corner = pos.cast::<f32>().unwrap() * 0.5 - Vector3::new(1.0, 1.0, 1.0);
+            let classifications = procedural.classify(&gpu, &gen_settings, corner, 3);
+
+            for (child_index, classification) in classifications.iter().enumerate() {
+                let offset =
+                    Vector3::new(child_index >> 2 & 1, child_index >> 1 & 1, child_index & 1);
+                let child_corner = corner + offset.cast::<f32>().unwrap() * 0.25;
+                let voxels = procedural
+                    .dispatch(&gpu, &gen_settings, child_corner, 3, voxel_depth - 3)
+                    .unwrap();
+
+                match classification {
+                    Classification::Empty => {
+                        assert!(voxels.is_none(), "{:?} isn't empty", child_corner);
+                        empty += 1;
+                    }
+                    Classification::Full => {
+                        let deep = CHUNK_OFFSET + gen_settings.deep_block;
+                        let voxels = voxels.unwrap();
+                        let deep_voxels = voxels
+                            .walk(WalkSettings::default())
+                            .filter(|visit| visit.leaf && visit.node.pointer == deep)
+                            .count();
+                        assert_eq!(
+                            deep_voxels,
+                            1 << (3 * (voxel_depth - 3)),
+                            "{:?} isn't full",
+                            child_corner
+                        );
+                    }
+                    Classification::Mixed => {}
+                }
+            }
         }
+        assert!(empty > 0);
     }
 }
@@ -0,0 +1,251 @@
+use super::*;
+
+/// Volume to search, in the -1 to 1 space used by `find_voxel`
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug)]
+pub enum Region {
+    Aabb {
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+    },
+    Sphere {
+        centre: Vector3<f32>,
+        radius: f32,
+    },
+    /// Planes with normals pointing inwards, a point is inside when
+    /// `dot(plane.xyz, point) + plane.w >= 0` for all of them
+    Frustum([Vector4<f32>; 6]),
+}
+
+#[allow(dead_code)]
+impl Region {
+    /// Frustum of a camera at `pos` looking along `look`, oriented the same way as the
+    /// character. `fov` is vertical in degrees and `aspect` is width / height.
+    pub fn frustum(pos: Vector3<f32>, look: Vector3<f32>, fov: f32, aspect: f32, far: f32) -> Self {
+        let forward = look.normalize();
+        // Looking straight up or down there's no yaw to take right from
+        let reference = if forward.x.abs() < 1e-6 && forward.z.abs() < 1e-6 {
+            Vector3::unit_z()
+        } else {
+            Vector3::unit_y()
+        };
+        let right = forward.cross(reference).normalize();
+        let up = right.cross(forward);
+
+        let tan_v = (fov.to_radians() / 2.0).tan();
+        let tan_h = tan_v * aspect;
+
+        let plane = |normal: Vector3<f32>, offset: f32| {
+            let normal = normal.normalize();
+            normal.extend(-normal.dot(pos) + offset)
+        };
+
+        Region::Frustum([
+            plane(forward, 0.0),
+            plane(-forward, far),
+            plane(forward * tan_h - right, 0.0),
+            plane(forward * tan_h + right, 0.0),
+            plane(forward * tan_v - up, 0.0),
+            plane(forward * tan_v + up, 0.0),
+        ])
+    }
+
+    /// Whether any of the box from `min` with edges `size` long is in the region. Can be
+    /// true for boxes just outside the corners of a frustum.
+    pub fn overlaps(&self, min: Vector3<f32>, size: f32) -> bool {
+        let max = min + Vector3::new(size, size, size);
+        match self {
+            Region::Aabb {
+                min: region_min,
+                max: region_max,
+            } => (0..3).all(|axis| min[axis] <= region_max[axis] && max[axis] > region_min[axis]),
+            Region::Sphere { centre, radius } => {
+                let closest = Vector3::new(
+                    centre.x.clamp(min.x, max.x),
+                    centre.y.clamp(min.y, max.y),
+                    centre.z.clamp(min.z, max.z),
+                );
+                (closest - centre).magnitude2() <= radius * radius
+            }
+            Region::Frustum(planes) => planes.iter().all(|plane| {
+                // Corner furthest along the normal
+                let corner = Vector3::new(
+                    if plane.x > 0.0 { max.x } else { min.x },
+                    if plane.y > 0.0 { max.y } else { min.y },
+                    if plane.z > 0.0 { max.z } else { min.z },
+                );
+                plane.truncate().dot(corner) + plane.w >= 0.0
+            }),
+        }
+    }
+}
+
+/// Calls `f` on the solid leaves that overlap `region`, skipping the subtrees that don't
+fn query(walk: Walk, region: &Region, f: &mut dyn FnMut(&Visit)) {
+    walk.visit(&mut |visit| {
+        let (min, size) = visit.bounds();
+        if !region.overlaps(min, size) {
+            return false;
+        }
+
+        let empty = visit.node.pointer == CHUNK_OFFSET && visit.node.value == Voxel::new(0, 0, 0);
+        if visit.leaf && !empty {
+            f(visit);
+        }
+        true
+    });
+}
+
+fn settings(max_depth: Option<u32>, follow_refs: bool) -> WalkSettings {
+    WalkSettings {
+        max_depth,
+        follow_refs,
+        ..Default::default()
+    }
+}
+
+#[allow(dead_code)]
+impl CpuOctree {
+    /// Solid leaves and references that overlap the region, nodes at `max_depth` count as
+    /// leaves
+    pub fn query(&self, region: &Region, max_depth: Option<u32>) -> Vec<Visit> {
+        let mut leaves = Vec::new();
+        query(
+            self.walk(settings(max_depth, false)),
+            region,
+            &mut |visit| leaves.push(*visit),
+        );
+        leaves
+    }
+
+    pub fn count(&self, region: &Region, max_depth: Option<u32>) -> usize {
+        let mut count = 0;
+        query(self.walk(settings(max_depth, false)), region, &mut |_| {
+            count += 1
+        });
+        count
+    }
+}
+
+#[allow(dead_code)]
+impl World {
+    /// Solid leaves that overlap the region going through the loaded chunks, nodes at
+    /// `max_depth` count as leaves
+    pub fn query(&self, region: &Region, max_depth: Option<u32>) -> Vec<Visit> {
+        let mut leaves = Vec::new();
+        query(self.walk(settings(max_depth, true)), region, &mut |visit| {
+            leaves.push(*visit)
+        });
+        leaves
+    }
+
+    pub fn count(&self, region: &Region, max_depth: Option<u32>) -> usize {
+        let mut count = 0;
+        query(self.walk(settings(max_depth, true)), region, &mut |_| {
+            count += 1
+        });
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Red voxels along the x axis at the bottom corner, at depth 2
+    fn row() -> CpuOctree {
+        let mut leaves: Vec<_> = (0..4)
+            .map(|x| {
+                let node = Node::new(CHUNK_OFFSET, Voxel::new(255, 0, 0));
+                (morton(Vector3::new(x, 0, 0), 2), node)
+            })
+            .collect();
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, 2)
+    }
+
+    #[test]
+    fn query_and_count_agree() {
+        let octree = row();
+        let regions = [
+            Region::Aabb {
+                min: Vector3::new(-1.0, -1.0, -1.0),
+                max: Vector3::new(1.0, 1.0, 1.0),
+            },
+            Region::Aabb {
+                min: Vector3::new(-0.4, -1.0, -1.0),
+                max: Vector3::new(0.4, -0.9, -0.9),
+            },
+            Region::Sphere {
+                centre: Vector3::new(1.0, -1.0, -1.0),
+                radius: 0.3,
+            },
+            Region::Aabb {
+                min: Vector3::new(-1.0, 0.5, -1.0),
+                max: Vector3::new(1.0, 1.0, 1.0),
+            },
+        ];
+        let expected = [4, 2, 1, 0];
+
+        for (region, expected) in regions.iter().zip(expected) {
+            let leaves = octree.query(region, None);
+            assert_eq!(leaves.len(), expected, "{:?}", region);
+            assert_eq!(octree.count(region, None), expected);
+            assert!(leaves
+                .iter()
+                .all(|visit| visit.pos.y == 0 && visit.pos.z == 0));
+        }
+
+        // The mips of the coarser nodes are what's counted
+        let mut octree = octree;
+        octree.generate_mips(&|_| Voxel::new(0, 0, 0), MipFilter::default());
+        assert_eq!(octree.count(&regions[0], Some(1)), 2);
+    }
+
+    #[test]
+    fn frustum_culls_behind() {
+        let octree = row();
+        let looking_at = Region::frustum(
+            Vector3::new(0.0, -0.75, 3.0),
+            Vector3::new(0.0, 0.0, -1.0),
+            60.0,
+            1.0,
+            10.0,
+        );
+        assert_eq!(octree.count(&looking_at, None), 4);
+
+        let looking_away = Region::frustum(
+            Vector3::new(0.0, -0.75, 3.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            60.0,
+            1.0,
+            10.0,
+        );
+        assert_eq!(octree.count(&looking_away, None), 0);
+
+        // Straight down onto the row and straight up away from it
+        let looking_down = Region::frustum(
+            Vector3::new(0.0, 3.0, -0.75),
+            Vector3::new(0.0, -1.0, 0.0),
+            60.0,
+            1.0,
+            10.0,
+        );
+        if let Region::Frustum(planes) = looking_down {
+            assert!(planes.iter().all(|plane| plane.x.is_finite()
+                && plane.y.is_finite()
+                && plane.z.is_finite()
+                && plane.w.is_finite()));
+        }
+        assert_eq!(octree.count(&looking_down, None), 4);
+
+        let looking_up = Region::frustum(
+            Vector3::new(0.0, 3.0, -0.75),
+            Vector3::new(0.0, 1.0, 0.0),
+            60.0,
+            1.0,
+            10.0,
+        );
+        assert_eq!(octree.count(&looking_up, None), 0);
+    }
+}
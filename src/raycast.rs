@@ -0,0 +1,277 @@
+use super::*;
+
+/// Gives up on rays that take more steps than this. The shader stops after 100 steps to keep
+/// frames fast and draws what's left red, picking has to find the actual leaf so it goes much
+/// further.
+pub const MAX_RAY_STEPS: u32 = 100000;
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug)]
+pub struct RayHit {
+    /// Where the ray entered the leaf, in the -1 to 1 space used by `find_voxel`
+    pub pos: Vector3<f32>,
+    /// Face of the leaf that was hit
+    pub normal: Vector3<f32>,
+    /// From the origin along the normalized direction
+    pub dist: f32,
+    pub depth: u32,
+    /// The leaf, or the node at the requested depth
+    pub node: Node,
+    pub chunk: u32,
+    pub index: usize,
+    pub steps: u32,
+}
+
+fn raycast(
+    groups: &dyn Groups,
+    origin: Vector3<f32>,
+    dir: Vector3<f32>,
+    max_dist: f32,
+    max_depth: Option<u32>,
+    filter: MipFilter,
+    max_steps: u32,
+) -> Result<Option<RayHit>, String> {
+    if dir == Vector3::zero() || (0..3).any(|axis| !dir[axis].is_finite()) {
+        return Err(format!("Can't cast a ray along {:?}", dir));
+    }
+
+    Ok(march(
+        groups,
+        origin,
+        dir.normalize(),
+        max_dist,
+        max_depth,
+        filter,
+        max_steps,
+    ))
+}
+
+/// Follows `octree_ray` in shader.wgsl: find the leaf containing the current position, stop
+/// if it isn't empty, otherwise step to where the ray leaves it.
+fn march(
+    groups: &dyn Groups,
+    origin: Vector3<f32>,
+    dir: Vector3<f32>,
+    max_dist: f32,
+    max_depth: Option<u32>,
+    filter: MipFilter,
+    max_steps: u32,
+) -> Option<RayHit> {
+    let dir = dir.map(|d| if d == 0.0 { 0.000001 } else { d });
+
+    let in_bounds = |v: Vector3<f32>| (0..3).all(|axis| v[axis] >= -1.0 && v[axis] < 1.0);
+
+    let mut dist = 0.0;
+    if !in_bounds(origin) {
+        // Get position on surface of the octree
+        dist = ray_box_dist(
+            origin,
+            dir,
+            Vector3::new(-1.0, -1.0, -1.0),
+            Vector3::new(1.0, 1.0, 1.0),
+        )?;
+    }
+    let pos = origin + dir * dist;
+
+    let r_sign = dir.map(f32::signum);
+    let mut voxel_pos = pos;
+    let mut normal = (pos * 1.000001).map(f32::trunc);
+    let mut t_current = 0.0;
+    for steps in 0..max_steps {
+        if dist + t_current > max_dist {
+            return None;
+        }
+
+        let leaf = descend(groups, voxel_pos, max_depth, true, &mut |_| {})?;
+
+        // Ignores SEE_THROUGH
+        let value = (leaf.node.to_value(filter) >> 4) - VOXEL_OFFSET;
+        if value & 0xFFFFFF > 0 {
+            return Some(RayHit {
+                pos: voxel_pos,
+                normal,
+                dist: dist + t_current,
+                depth: leaf.depth,
+                node: leaf.node,
+                chunk: leaf.chunk,
+                index: leaf.index,
+                steps,
+            });
+        }
+
+        let voxel_size = 2.0 / (1u64 << leaf.depth) as f32;
+        let t_max = (leaf.centre() - pos + r_sign * voxel_size / 2.0).div_element_wise(dir);
+
+        let mask = Vector3::new(
+            (t_max.x <= t_max.y.min(t_max.z)) as u32 as f32,
+            (t_max.y <= t_max.z.min(t_max.x)) as u32 as f32,
+            (t_max.z <= t_max.x.min(t_max.y)) as u32 as f32,
+        );
+        normal = -mask.mul_element_wise(r_sign);
+
+        t_current = t_max.x.min(t_max.y).min(t_max.z);
+        voxel_pos = pos + dir * t_current - normal * 0.000002;
+
+        if !in_bounds(voxel_pos) {
+            return None;
+        }
+    }
+
+    None
+}
+
+/// Distance along the ray to the box, None if it misses or the box is behind it
+fn ray_box_dist(
+    origin: Vector3<f32>,
+    dir: Vector3<f32>,
+    vmin: Vector3<f32>,
+    vmax: Vector3<f32>,
+) -> Option<f32> {
+    let v1 = (vmin - origin).div_element_wise(dir);
+    let v2 = (vmax - origin).div_element_wise(dir);
+    let near = v1.x.min(v2.x).max(v1.y.min(v2.y)).max(v1.z.min(v2.z));
+    let far = v1.x.max(v2.x).min(v1.y.max(v2.y)).min(v1.z.max(v2.z));
+    if far < 0.0 || near > far {
+        return None;
+    }
+
+    Some(near.max(0.0))
+}
+
+impl CpuOctree {
+    /// First leaf the ray hits within `max_dist`, nodes at `max_depth` count as leaves.
+    /// References are hit using their mip colour.
+    #[allow(dead_code)]
+    pub fn raycast(
+        &self,
+        origin: Vector3<f32>,
+        dir: Vector3<f32>,
+        max_dist: f32,
+        max_depth: Option<u32>,
+    ) -> Result<Option<RayHit>, String> {
+        raycast(
+            self,
+            origin,
+            dir,
+            max_dist,
+            max_depth,
+            MipFilter::default(),
+            MAX_RAY_STEPS,
+        )
+    }
+}
+
+impl World {
+    /// First leaf the ray hits within `max_dist` going through the loaded chunks, nodes at
+    /// `max_depth` count as leaves
+    #[allow(dead_code)]
+    pub fn raycast(
+        &self,
+        origin: Vector3<f32>,
+        dir: Vector3<f32>,
+        max_dist: f32,
+        max_depth: Option<u32>,
+    ) -> Result<Option<RayHit>, String> {
+        self.raycast_steps(origin, dir, max_dist, max_depth, MAX_RAY_STEPS)
+    }
+
+    /// Like `raycast` but gives up and returns None after `max_steps` leaves
+    pub fn raycast_steps(
+        &self,
+        origin: Vector3<f32>,
+        dir: Vector3<f32>,
+        max_dist: f32,
+        max_depth: Option<u32>,
+        max_steps: u32,
+    ) -> Result<Option<RayHit>, String> {
+        raycast(
+            self,
+            origin,
+            dir,
+            max_dist,
+            max_depth,
+            self.mip_filter,
+            max_steps,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One red voxel in the corner at depth 2
+    fn corner_voxel() -> CpuOctree {
+        let leaves = [(0, Node::new(CHUNK_OFFSET, Voxel::new(255, 0, 0)))];
+        CpuOctree::from_sorted_leaves(&leaves, 2)
+    }
+
+    #[test]
+    fn hits_from_outside() {
+        let octree = corner_voxel();
+        let origin = Vector3::new(-0.9, -0.9, -3.0);
+        let hit = octree
+            .raycast(origin, Vector3::new(0.0, 0.0, 1.0), 10.0, None)
+            .unwrap()
+            .unwrap();
+        assert!((hit.pos - Vector3::new(-0.9, -0.9, -1.0)).magnitude() < 0.0001);
+        assert_eq!(hit.normal, Vector3::new(0.0, 0.0, -1.0));
+        assert!((hit.dist - 2.0).abs() < 0.0001);
+        assert_eq!((hit.depth, hit.node.value), (2, Voxel::new(255, 0, 0)));
+
+        // Stops short of it
+        let hit = octree.raycast(origin, Vector3::new(0.0, 0.0, 1.0), 1.5, None);
+        assert!(hit.unwrap().is_none());
+    }
+
+    #[test]
+    fn steps_through_empty_leaves() {
+        let octree = corner_voxel();
+        let origin = Vector3::new(0.5, -0.9, -0.9);
+        let hit = octree
+            .raycast(origin, Vector3::new(-1.0, 0.0, 0.0), 10.0, None)
+            .unwrap()
+            .unwrap();
+        assert!((hit.pos.x + 0.5).abs() < 0.0001);
+        assert_eq!(hit.normal, Vector3::new(1.0, 0.0, 0.0));
+        assert!((hit.dist - 1.0).abs() < 0.0001);
+        // Through the empty top level node and the empty voxel next to the red one
+        assert_eq!(hit.steps, 2);
+
+        // Gives up when it runs out of steps before getting there
+        let dir = Vector3::new(-1.0, 0.0, 0.0);
+        let limited = |max_steps| {
+            raycast(
+                &octree,
+                origin,
+                dir,
+                10.0,
+                None,
+                MipFilter::default(),
+                max_steps,
+            )
+            .unwrap()
+        };
+        assert!(limited(2).is_none());
+        assert_eq!(limited(3).unwrap().steps, 2);
+
+        let miss = octree.raycast(origin, Vector3::new(1.0, 0.0, 0.0), 10.0, None);
+        assert!(miss.unwrap().is_none());
+    }
+
+    #[test]
+    fn zero_direction_is_an_error() {
+        let octree = corner_voxel();
+        assert!(octree
+            .raycast(Vector3::zero(), Vector3::zero(), 10.0, None)
+            .is_err());
+        assert!(octree
+            .raycast(
+                Vector3::zero(),
+                Vector3::new(f32::NAN, 0.0, 1.0),
+                10.0,
+                None
+            )
+            .is_err());
+    }
+}
@@ -22,12 +22,12 @@ impl Render {
         let size = window.inner_size();
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: gpu.surface.get_preferred_format(&gpu.adapter).unwrap(),
+            format: gpu.surface().get_preferred_format(&gpu.adapter).unwrap(),
             width: size.width,
             height: size.height,
             present_mode: wgpu::PresentMode::Fifo,
         };
-        gpu.surface.configure(&gpu.device, &config);
+        gpu.surface().configure(&gpu.device, &config);
 
         let shader = gpu
             .device
@@ -184,7 +184,7 @@ impl Render {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            gpu.surface.configure(&gpu.device, &self.config);
+            gpu.surface().configure(&gpu.device, &self.config);
         }
     }
 
@@ -215,7 +215,7 @@ impl Render {
     }
 
     pub fn render(&mut self, gpu: &Gpu, window: &Window) -> Result<(), wgpu::SurfaceError> {
-        let output = gpu.surface.get_current_texture()?;
+        let output = gpu.surface().get_current_texture()?;
         let size = window.inner_size();
 
         let view = output
@@ -0,0 +1,371 @@
+use super::*;
+
+/// A node of the source octree, or a leaf of it that covers a bigger area
+#[derive(Copy, Clone)]
+enum Part {
+    Group(usize),
+    Leaf(Node),
+}
+
+fn part(octree: &CpuOctree, index: usize) -> Part {
+    let node = octree.nodes[index];
+    if node.pointer < CHUNK_OFFSET {
+        Part::Group(node.pointer as usize)
+    } else {
+        Part::Leaf(node)
+    }
+}
+
+/// Copies the group and its subtree down to `depth`, internal nodes there become leaves with
+/// their mip
+fn bake(
+    mipped: &CpuOctree,
+    group: usize,
+    level: u32,
+    depth: u32,
+    filter: MipFilter,
+    nodes: &mut Vec<Node>,
+) -> Node {
+    let start = nodes.len();
+    nodes.extend_from_slice(&mipped.nodes[group..group + 8]);
+    for i in start..start + 8 {
+        let node = nodes[i];
+        if node.pointer >= CHUNK_OFFSET {
+            continue;
+        }
+
+        nodes[i] = if level < depth {
+            bake(
+                mipped,
+                node.pointer as usize,
+                level + 1,
+                depth,
+                filter,
+                nodes,
+            )
+        } else {
+            match filter {
+                MipFilter::Threshold(threshold) if node.coverage < threshold => EMPTY,
+                _ => Node {
+                    pointer: CHUNK_OFFSET,
+                    value: node.value,
+                    coverage: node.coverage,
+                },
+            }
+        };
+    }
+
+    collapse(nodes, start)
+}
+
+struct Upsample<'a> {
+    source: &'a CpuOctree,
+    /// Deepest level of the source
+    depth: u32,
+    levels: u32,
+    smooth: bool,
+    nodes: Vec<Node>,
+}
+
+impl<'a> Upsample<'a> {
+    /// Builds the node at integer `pos` and `level` of the result, the top level is 1
+    fn build(&mut self, part: Part, pos: Vector3<i64>, level: u32, leaf_level: u32) -> Node {
+        let node = match part {
+            Part::Group(_) => return self.split(part, pos, level, leaf_level),
+            Part::Leaf(node) => node,
+        };
+
+        // Smaller copies of a block would look different, so references are left alone
+        if node.pointer > CHUNK_OFFSET {
+            return node;
+        }
+
+        if !self.smooth {
+            return if is_solid(node) && level < leaf_level + self.levels {
+                self.split(part, pos, level, leaf_level)
+            } else {
+                node
+            };
+        }
+
+        if level == self.depth + self.levels {
+            return self.smoothed(pos);
+        }
+
+        // Smoothing only moves surfaces by less than a voxel of the source, so leaves with
+        // nothing different next to them stay as they are
+        let (coarse_pos, coarse_level) = if level > self.depth {
+            (pos.map(|c| c >> (level - self.depth)), self.depth)
+        } else {
+            (pos, level)
+        };
+        let solid = is_solid(node);
+        let uniform = (-1..=1).all(|x| {
+            (-1..=1).all(|y| {
+                (-1..=1).all(|z| {
+                    let neighbour = coarse_pos + Vector3::new(x, y, z);
+                    self.occupancy(neighbour, coarse_level) == Some(solid)
+                })
+            })
+        });
+        if uniform {
+            node
+        } else {
+            self.split(part, pos, level, leaf_level)
+        }
+    }
+
+    fn split(&mut self, parent: Part, pos: Vector3<i64>, level: u32, leaf_level: u32) -> Node {
+        let start = self.nodes.len();
+        self.nodes.extend([EMPTY; 8]);
+        for i in 0..8 {
+            let (child, child_leaf_level) = match parent {
+                Part::Group(group) => (part(self.source, group + i), level + 1),
+                Part::Leaf(node) => (Part::Leaf(node), leaf_level),
+            };
+            let child_pos = pos * 2 + child_offset(i).cast::<i64>().unwrap();
+            self.nodes[start + i] = self.build(child, child_pos, level + 1, child_leaf_level);
+        }
+
+        if self.smooth {
+            collapse(&mut self.nodes, start)
+        } else {
+            Node::new(start as u32, Voxel::new(0, 0, 0))
+        }
+    }
+
+    /// Whether the source is solid all over the cube at `pos` and `level`, None if it's mixed.
+    /// Outside is empty.
+    fn occupancy(&self, pos: Vector3<i64>, level: u32) -> Option<bool> {
+        self.leaf_at(pos, level).map(is_solid)
+    }
+
+    fn leaf_at(&self, pos: Vector3<i64>, level: u32) -> Option<Node> {
+        if (0..3).any(|axis| pos[axis] < 0 || pos[axis] >= 1 << level) {
+            return Some(EMPTY);
+        }
+
+        let (index, depth) = self.source.lookup(pos.cast::<u32>().unwrap(), level);
+        let node = self.source.nodes[index];
+        if depth == level && node.pointer < CHUNK_OFFSET {
+            None
+        } else {
+            Some(node)
+        }
+    }
+
+    /// Voxel at the finest level where the occupancy of the source, blended between the
+    /// centres of its voxels, is more than half
+    fn smoothed(&self, pos: Vector3<i64>) -> Node {
+        let scale = (1i64 << self.levels) as f32;
+        let centre = (pos.cast::<f32>().unwrap() + Vector3::new(0.5, 0.5, 0.5)) / scale;
+        let base = (centre - Vector3::new(0.5, 0.5, 0.5)).map(f32::floor);
+        let t = centre - Vector3::new(0.5, 0.5, 0.5) - base;
+
+        let mut occupancy = 0.0;
+        let mut heaviest: Option<(f32, Node)> = None;
+        for i in 0..8 {
+            let offset = child_offset(i);
+            let weight = (0..3)
+                .map(|axis| {
+                    if offset[axis] == 1 {
+                        t[axis]
+                    } else {
+                        1.0 - t[axis]
+                    }
+                })
+                .product::<f32>();
+            let corner = base.cast::<i64>().unwrap() + offset.cast::<i64>().unwrap();
+            let node = self.leaf_at(corner, self.depth).unwrap_or(EMPTY);
+            if is_solid(node) {
+                occupancy += weight;
+                if heaviest.is_none_or(|(w, _)| weight > w) {
+                    heaviest = Some((weight, node));
+                }
+            }
+        }
+
+        let containing = self
+            .leaf_at(pos.map(|c| c >> self.levels), self.depth)
+            .unwrap_or(EMPTY);
+        let solid = if occupancy == 0.5 {
+            is_solid(containing)
+        } else {
+            occupancy > 0.5
+        };
+
+        match (solid, is_solid(containing), heaviest) {
+            (false, _, _) => EMPTY,
+            (true, true, _) => Node::new(CHUNK_OFFSET, containing.value),
+            (true, false, Some((_, node))) => Node::new(CHUNK_OFFSET, node.value),
+            (true, false, None) => EMPTY,
+        }
+    }
+}
+
+impl CpuOctree {
+    /// A copy cut off at `depth`, with the nodes there turned into leaves of their mip colour.
+    /// Under the threshold filter, nodes that are less full than the threshold become empty.
+    pub fn downsample(&self, depth: u32, filter: MipFilter) -> CpuOctree {
+        let ref_mips = self.ref_mips();
+        let ref_mip = |id: u32| ref_mips.get(&id).copied().unwrap_or(Voxel::new(0, 0, 0));
+
+        let mut mipped = CpuOctree {
+            nodes: self.nodes.clone(),
+            top_mip: self.top_mip,
+        };
+        mipped.generate_mips(&ref_mip, filter);
+
+        let mut nodes = Vec::new();
+        let root = bake(&mipped, 0, 1, depth.max(1), filter, &mut nodes);
+        if root.pointer >= CHUNK_OFFSET {
+            // Everything collapsed into one leaf, the top level has to stay a group
+            nodes = vec![root; 8];
+        }
+
+        let mut octree = CpuOctree {
+            nodes,
+            top_mip: self.top_mip,
+        };
+        octree.generate_mips(&ref_mip, filter);
+        octree
+    }
+
+    /// A copy with solid leaves split `levels` deeper. With `smooth`, the voxels at the new
+    /// deepest level are filled where more than half of the blended occupancy of the
+    /// neighbouring voxels is solid, which rounds off the steps between them.
+    pub fn upsample(&self, levels: u32, smooth: bool) -> CpuOctree {
+        let mut upsample = Upsample {
+            source: self,
+            depth: self.depth(),
+            levels,
+            smooth,
+            nodes: Vec::new(),
+        };
+
+        let root = upsample.split(Part::Group(0), Vector3::zero(), 0, 0);
+        let mut octree = CpuOctree {
+            nodes: upsample.nodes,
+            top_mip: self.top_mip,
+        };
+        if root.pointer >= CHUNK_OFFSET {
+            octree.nodes = vec![root; 8];
+        }
+
+        let ref_mips = self.ref_mips();
+        octree.generate_mips(
+            &|id| ref_mips.get(&id).copied().unwrap_or(Voxel::new(0, 0, 0)),
+            MipFilter::Mean,
+        );
+        octree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORANGE: Voxel = Voxel {
+        r: 200,
+        g: 100,
+        b: 50,
+    };
+    const BLUE: Voxel = Voxel {
+        r: 50,
+        g: 100,
+        b: 200,
+    };
+
+    fn octree(voxels: &[(Vector3<u32>, Voxel)], depth: u32) -> CpuOctree {
+        let mut leaves: Vec<_> = voxels
+            .iter()
+            .map(|&(pos, colour)| (morton(pos, depth), Node::new(CHUNK_OFFSET, colour)))
+            .collect();
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, depth)
+    }
+
+    fn colour_at(octree: &CpuOctree, pos: Vector3<u32>, depth: u32) -> Option<Voxel> {
+        let (index, _) = octree.lookup(pos, depth);
+        let node = octree.nodes[index];
+        is_solid(node).then_some(node.value)
+    }
+
+    fn grid(size: u32) -> impl Iterator<Item = Vector3<u32>> {
+        (0..size).flat_map(move |x| {
+            (0..size).flat_map(move |y| (0..size).map(move |z| Vector3::new(x, y, z)))
+        })
+    }
+
+    #[test]
+    fn upsample_then_downsample_roundtrip() {
+        let source = octree(
+            &[
+                (Vector3::new(0, 0, 0), ORANGE),
+                (Vector3::new(1, 0, 0), BLUE),
+                (Vector3::new(3, 2, 1), ORANGE),
+            ],
+            2,
+        );
+
+        let up = source.upsample(1, false);
+        assert_eq!(up.depth(), 3);
+        for pos in grid(8) {
+            assert_eq!(colour_at(&up, pos, 3), colour_at(&source, pos / 2, 2));
+        }
+
+        let down = up.downsample(2, MipFilter::Mean);
+        for pos in grid(4) {
+            assert_eq!(colour_at(&down, pos, 2), colour_at(&source, pos, 2));
+        }
+    }
+
+    #[test]
+    fn downsample_bakes_mips() {
+        // Seven of the eight voxels in the first node
+        let voxels: Vec<_> = grid(2)
+            .skip(1)
+            .map(|pos| (pos, if pos.x == 0 { BLUE } else { ORANGE }))
+            .collect();
+        let source = octree(&voxels, 2);
+
+        let majority = source.downsample(1, MipFilter::Majority);
+        assert_eq!(majority.depth(), 1);
+        assert_eq!(colour_at(&majority, Vector3::zero(), 1), Some(ORANGE));
+        let threshold = source.downsample(1, MipFilter::Threshold(255));
+        assert_eq!(colour_at(&threshold, Vector3::zero(), 1), None);
+
+        // Comes back as a full cube
+        let up = majority.upsample(1, false);
+        for pos in grid(2) {
+            assert_eq!(colour_at(&up, pos, 2), Some(ORANGE));
+        }
+    }
+
+    #[test]
+    fn smooth_upsample_rounds_corners() {
+        let voxels: Vec<_> = grid(2)
+            .map(|pos| (pos + Vector3::new(1, 1, 1), ORANGE))
+            .collect();
+        let source = octree(&voxels, 2);
+
+        let smooth = source.upsample(1, true);
+        assert_eq!(colour_at(&smooth, Vector3::new(2, 2, 2), 3), None);
+        assert_eq!(colour_at(&smooth, Vector3::new(5, 5, 5), 3), None);
+        assert_eq!(colour_at(&smooth, Vector3::new(3, 3, 3), 3), Some(ORANGE));
+        assert_eq!(colour_at(&smooth, Vector3::new(2, 3, 3), 3), Some(ORANGE));
+        assert_eq!(colour_at(&smooth, Vector3::new(1, 3, 3), 3), None);
+    }
+
+    #[test]
+    fn references_are_left_alone() {
+        let mut source = CpuOctree::new(0);
+        source.put_in_block(Vector3::new(-1.0, -1.0, -1.0), BLOCK_STONE, 1);
+        let up = source.upsample(2, false);
+        let (index, depth) = up.lookup(Vector3::zero(), 3);
+        assert_eq!(
+            (up.nodes[index].pointer, depth),
+            (CHUNK_OFFSET + BLOCK_STONE, 1)
+        );
+    }
+}
@@ -213,8 +213,9 @@ fn octree_ray(r: Ray, primary: bool) -> HitInfo {
     loop {
         voxel = find_voxel(voxel_pos, primary);
         if (!u.pause_adaptive || !u.show_hits) {
+            // Ignores SEE_THROUGH
             let tnipt = node(voxel.value) - VOXEL_OFFSET;
-            if (tnipt > 0u) {
+            if ((tnipt & 0xFFFFFFu) > 0u) {
                 break;
             }
         } else {
@@ -0,0 +1,287 @@
+use super::*;
+use std::path::{Path, PathBuf};
+
+/// Slices are 2^depth pixels wide so deeper ones would be too big an image
+const MAX_SLICE_DEPTH: u32 = 13;
+
+/// Axes of the octree along (the image, down the image) for a slice along `axis`. Y is up in
+/// the slices it shows up in.
+fn image_axes(axis: usize) -> (usize, usize) {
+    match axis {
+        0 => (2, 1),
+        1 => (0, 2),
+        _ => (0, 1),
+    }
+}
+
+/// Layer at `depth` that the plane at `coordinate` in the -1 to 1 space goes through
+pub fn slice_layer(coordinate: f32, depth: u32) -> u32 {
+    let size = 1u32 << depth;
+    (((coordinate + 1.0) / 2.0 * size as f32).floor() as i64).clamp(0, size as i64 - 1) as u32
+}
+
+/// Path next to `output` for one layer of a stack, numbered with as many digits as the last
+/// layer has
+pub fn stack_path(output: &Path, layer: u32, layers: &std::ops::Range<u32>) -> PathBuf {
+    let digits = (layers.end - 1).to_string().len();
+    let stem = output.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let extension = output.extension().and_then(|e| e.to_str()).unwrap_or("");
+    output.with_file_name(format!("{}_{:0digits$}.{}", stem, layer, extension))
+}
+
+fn slice<'a>(
+    walk: &dyn Fn(WalkSettings) -> Walk<'a>,
+    axis: usize,
+    layer: u32,
+    depth: u32,
+    filter: MipFilter,
+) -> Result<Image, String> {
+    if axis > 2 {
+        return Err("Axis has to be 0, 1 or 2".to_string());
+    } else if depth == 0 || depth > MAX_SLICE_DEPTH {
+        return Err(format!("Depth has to be from 1 to {}", MAX_SLICE_DEPTH));
+    }
+
+    let size = 1u32 << depth;
+    let layer = layer.min(size - 1);
+    // Through the middle of the layer so nodes on either side of it are left out
+    let mut min = Vector3::new(-1.0, -1.0, -1.0);
+    min[axis] = (layer as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+    let mut max = Vector3::new(1.0, 1.0, 1.0);
+    max[axis] = min[axis];
+    let settings = WalkSettings {
+        max_depth: Some(depth),
+        region: Some((min, max)),
+        follow_refs: true,
+    };
+
+    let (across, down) = image_axes(axis);
+    let mut image = Image {
+        width: size as usize,
+        height: size as usize,
+        channels: 3,
+        max: 255,
+        samples: vec![0; (size * size * 3) as usize],
+    };
+    for visit in walk(settings).filter(|visit| visit.leaf) {
+        let colour = match leaf_colour(visit.node, filter) {
+            Some(colour) => colour,
+            None => continue,
+        };
+
+        let scale = 1u32 << (depth - visit.depth);
+        let lo = visit.pos * scale;
+        for u in lo[across]..lo[across] + scale {
+            for v in lo[down]..lo[down] + scale {
+                let row = if down == 1 { size - 1 - v } else { v };
+                let i = ((row * size + u) * 3) as usize;
+                image.samples[i..i + 3].copy_from_slice(&[
+                    colour.r as u16,
+                    colour.g as u16,
+                    colour.b as u16,
+                ]);
+            }
+        }
+    }
+    Ok(image)
+}
+
+impl CpuOctree {
+    /// Colours of the voxels at `depth` in one layer along `axis`, with empty voxels black.
+    /// Deeper nodes are drawn with their mip colour so the mips have to be generated.
+    #[allow(dead_code)]
+    pub fn slice(&self, axis: usize, layer: u32, depth: u32) -> Result<Image, String> {
+        slice(
+            &|settings| self.walk(settings),
+            axis,
+            layer,
+            depth,
+            MipFilter::default(),
+        )
+    }
+}
+
+impl World {
+    /// Colours of the voxels at `depth` in one layer along `axis` going through the loaded
+    /// chunks
+    pub fn slice(&self, axis: usize, layer: u32, depth: u32) -> Result<Image, String> {
+        slice(
+            &|settings| self.walk(settings),
+            axis,
+            layer,
+            depth,
+            self.mip_filter,
+        )
+    }
+}
+
+impl ChunkFiles<'_> {
+    /// Colours of the voxels at `depth` in one layer along `axis`, reading the chunk files the
+    /// layer goes through
+    pub fn slice(&self, axis: usize, layer: u32, depth: u32) -> Result<Image, String> {
+        let image = slice(
+            &|settings| self.walk(settings),
+            axis,
+            layer,
+            depth,
+            self.world.mip_filter,
+        )?;
+        self.error()?;
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Voxel = Voxel { r: 255, g: 0, b: 0 };
+    const GREEN: Voxel = Voxel { r: 0, g: 255, b: 0 };
+    const BLUE: Voxel = Voxel { r: 0, g: 0, b: 255 };
+    const YELLOW: Voxel = Voxel {
+        r: 255,
+        g: 255,
+        b: 0,
+    };
+    const CYAN: Voxel = Voxel {
+        r: 0,
+        g: 255,
+        b: 255,
+    };
+    const BLACK: Voxel = Voxel { r: 0, g: 0, b: 0 };
+
+    /// Depth 2 octree with a voxel in four corners and the top corner octant solid
+    fn corners() -> CpuOctree {
+        let mut leaves = vec![
+            (Vector3::new(0, 0, 0), RED),
+            (Vector3::new(3, 0, 0), GREEN),
+            (Vector3::new(0, 3, 0), BLUE),
+            (Vector3::new(0, 0, 3), YELLOW),
+        ];
+        for i in 0..8 {
+            let pos = Vector3::new(2 + (i & 1), 2 + (i >> 1 & 1), 2 + (i >> 2));
+            leaves.push((pos, CYAN));
+        }
+        let mut leaves: Vec<_> = leaves
+            .into_iter()
+            .map(|(pos, colour)| (morton(pos, 2), Node::new(CHUNK_OFFSET, colour)))
+            .collect();
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, 2)
+    }
+
+    /// Pixels that aren't black, by (column, row)
+    fn coloured(image: &Image) -> Vec<((usize, usize), Voxel)> {
+        let mut pixels = Vec::new();
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let colour = image.colour(x, y);
+                if colour != BLACK {
+                    pixels.push(((x, y), colour));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn slices_along_each_axis() {
+        let octree = corners();
+
+        // Across Z, X goes across and Y goes up
+        let image = octree.slice(2, 0, 2).unwrap();
+        assert_eq!((image.width, image.height, image.channels), (4, 4, 3));
+        assert_eq!(
+            coloured(&image),
+            [((0, 0), BLUE), ((0, 3), RED), ((3, 3), GREEN)]
+        );
+        assert_eq!(coloured(&octree.slice(2, 3, 2).unwrap()).len(), 5);
+
+        // The solid octant was collapsed into one leaf and covers 2x2 pixels
+        assert_eq!(
+            coloured(&octree.slice(2, 2, 2).unwrap()),
+            [
+                ((2, 0), CYAN),
+                ((3, 0), CYAN),
+                ((2, 1), CYAN),
+                ((3, 1), CYAN)
+            ]
+        );
+
+        // Across X, Z goes across and Y goes up
+        assert_eq!(
+            coloured(&octree.slice(0, 0, 2).unwrap()),
+            [((0, 0), BLUE), ((0, 3), RED), ((3, 3), YELLOW)]
+        );
+        assert_eq!(
+            coloured(&octree.slice(0, 3, 2).unwrap())[4],
+            ((0, 3), GREEN)
+        );
+
+        // Across Y, X goes across and Z goes down
+        assert_eq!(
+            coloured(&octree.slice(1, 0, 2).unwrap()),
+            [((0, 0), RED), ((3, 0), GREEN), ((0, 3), YELLOW)]
+        );
+        assert_eq!(coloured(&octree.slice(1, 3, 2).unwrap())[0], ((0, 0), BLUE));
+    }
+
+    #[test]
+    fn coarser_slices_use_mips() {
+        let mut octree = corners();
+        octree.generate_mips(&|_| BLACK, MipFilter::default());
+
+        let pixels = |layer| -> Vec<_> {
+            let image = octree.slice(2, layer, 1).unwrap();
+            assert_eq!(image.width, 2);
+            coloured(&image)
+                .into_iter()
+                .map(|(pixel, _)| pixel)
+                .collect()
+        };
+        // Each octant with a voxel in it shows up, the empty one doesn't
+        assert_eq!(pixels(0), [(0, 0), (0, 1), (1, 1)]);
+        assert_eq!(pixels(1), [(1, 0), (0, 1)]);
+        // The solid octant keeps its own colour
+        assert_eq!(octree.slice(2, 1, 1).unwrap().colour(1, 0), CYAN);
+    }
+
+    #[test]
+    fn layers_are_clamped() {
+        let octree = corners();
+        assert_eq!(
+            octree.slice(2, 100, 2).unwrap().samples,
+            octree.slice(2, 3, 2).unwrap().samples
+        );
+
+        assert_eq!(slice_layer(-1.0, 2), 0);
+        assert_eq!(slice_layer(-0.01, 2), 1);
+        assert_eq!(slice_layer(0.0, 2), 2);
+        assert_eq!(slice_layer(1.0, 2), 3);
+        assert_eq!(slice_layer(-5.0, 2), 0);
+        assert_eq!(slice_layer(5.0, 2), 3);
+        assert_eq!(slice_layer(0.3, 4), 10);
+    }
+
+    #[test]
+    fn bad_axes_and_depths_are_errors() {
+        let octree = corners();
+        assert!(octree.slice(3, 0, 2).is_err());
+        assert!(octree.slice(0, 0, 0).is_err());
+        assert!(octree.slice(0, 0, MAX_SLICE_DEPTH + 1).is_err());
+    }
+
+    #[test]
+    fn stacks_are_numbered() {
+        let output = Path::new("out/slice.png");
+        assert_eq!(stack_path(output, 3, &(0..8)), Path::new("out/slice_3.png"));
+        assert_eq!(
+            stack_path(output, 3, &(0..11)),
+            Path::new("out/slice_03.png")
+        );
+        assert_eq!(
+            stack_path(output, 42, &(40..1000)),
+            Path::new("out/slice_042.png")
+        );
+    }
+}
@@ -0,0 +1,388 @@
+use super::*;
+use std::collections::{BTreeSet, HashSet};
+
+/// Nodes and leaves at one depth
+#[derive(Copy, Clone, Default, Debug)]
+pub struct LevelStats {
+    pub nodes: usize,
+    pub empty: usize,
+    pub solid: usize,
+    /// Leaves that reference a block or chunk
+    pub refs: usize,
+}
+
+impl LevelStats {
+    pub fn leaves(&self) -> usize {
+        self.empty + self.solid + self.refs
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct OctreeStats {
+    /// Index 0 is the top level
+    pub levels: Vec<LevelStats>,
+    /// Colours of the solid leaves
+    pub colours: HashSet<u32>,
+    pub chunk_refs: BTreeSet<u32>,
+    /// Nodes in memory that can't be reached from the top level, like holes in the gpu octree
+    pub unused: usize,
+    pub bytes: usize,
+    /// Octrees the stats were gathered from
+    pub chunks: usize,
+}
+
+impl OctreeStats {
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.levels.iter().map(|level| level.nodes).sum()
+    }
+
+    /// Totals of every level
+    pub fn total(&self) -> LevelStats {
+        let mut total = LevelStats::default();
+        for level in &self.levels {
+            total.nodes += level.nodes;
+            total.empty += level.empty;
+            total.solid += level.solid;
+            total.refs += level.refs;
+        }
+        total
+    }
+
+    /// Adds the stats of another octree, levels are matched by depth within each octree
+    pub fn merge(&mut self, other: &OctreeStats) {
+        for (depth, level) in other.levels.iter().enumerate() {
+            let this = self.level(depth);
+            this.nodes += level.nodes;
+            this.empty += level.empty;
+            this.solid += level.solid;
+            this.refs += level.refs;
+        }
+        self.colours.extend(&other.colours);
+        self.chunk_refs.extend(&other.chunk_refs);
+        self.unused += other.unused;
+        self.bytes += other.bytes;
+        self.chunks += other.chunks;
+    }
+
+    fn level(&mut self, depth: usize) -> &mut LevelStats {
+        if self.levels.len() <= depth {
+            self.levels.resize(depth + 1, LevelStats::default());
+        }
+        &mut self.levels[depth]
+    }
+}
+
+impl CpuOctree {
+    pub fn stats(&self) -> OctreeStats {
+        let mut stats = OctreeStats {
+            bytes: self.nodes.len() * std::mem::size_of::<Node>(),
+            chunks: 1,
+            ..Default::default()
+        };
+
+        for visit in self.walk(WalkSettings::default()) {
+            let node = visit.node;
+            let level = stats.level(visit.depth as usize - 1);
+            level.nodes += 1;
+            if node.pointer > CHUNK_OFFSET {
+                level.refs += 1;
+                stats.chunk_refs.insert(node.pointer - CHUNK_OFFSET);
+            } else if node.pointer < CHUNK_OFFSET {
+                // Internal, or a broken pointer that's left to validate
+            } else if node.value == Voxel::new(0, 0, 0) {
+                level.empty += 1;
+            } else {
+                level.solid += 1;
+                stats.colours.insert(node.value.to_cpu_value());
+            }
+        }
+
+        stats.unused = self.nodes.len().saturating_sub(stats.nodes());
+        stats
+    }
+}
+
+impl Octree {
+    /// Leaves that are see through count as empty. Bytes include the positions kept on the
+    /// cpu.
+    pub fn stats(&self) -> OctreeStats {
+        let mut stats = OctreeStats {
+            bytes: self.nodes.len()
+                * (std::mem::size_of::<u32>() + std::mem::size_of::<Vector3<f32>>())
+                + self.hole_stack.len() * std::mem::size_of::<usize>(),
+            chunks: 1,
+            ..Default::default()
+        };
+
+        let mut level = vec![0];
+        let mut depth = 0;
+        while !level.is_empty() {
+            let mut next = Vec::new();
+            for group in level {
+                stats.level(depth).nodes += 8;
+                for i in group..group + 8 {
+                    let value = self.get_node(i);
+                    if value < VOXEL_OFFSET {
+                        next.push(value as usize);
+                        continue;
+                    }
+
+                    let colour = value - VOXEL_OFFSET;
+                    if colour & SEE_THROUGH != 0 || colour & 0xFFFFFF == 0 {
+                        stats.level(depth).empty += 1;
+                    } else {
+                        stats.level(depth).solid += 1;
+                        stats.colours.insert(colour & 0xFFFFFF);
+                    }
+                }
+            }
+
+            level = next;
+            depth += 1;
+        }
+
+        stats.unused = self.nodes.len().saturating_sub(stats.nodes());
+        stats
+    }
+}
+
+impl World {
+    /// Stats of every loaded chunk and block added together. Levels are counted from the top
+    /// of each chunk.
+    pub fn stats(&self) -> OctreeStats {
+        let mut stats = OctreeStats::default();
+        for chunk in self.chunks.iter() {
+            stats.merge(&chunk.stats());
+        }
+        stats
+    }
+
+    /// Stats of every chunk file in the world folder and the loaded blocks, reading one chunk
+    /// at a time
+    pub fn folder_stats(&self) -> Result<OctreeStats, String> {
+        let files = self.chunk_files()?;
+        let mut stats = OctreeStats::default();
+        for chunk in self.chunks.iter() {
+            if !files.contains_key(chunk.key()) {
+                stats.merge(&chunk.stats());
+            }
+        }
+
+        for (id, path) in files {
+            let chunk = CpuOctree::load_file(path.to_string_lossy().to_string(), 0)
+                .map_err(|e| format!("Chunk {}: {}", id, e))?;
+            stats.merge(&chunk.stats());
+        }
+        Ok(stats)
+    }
+}
+
+impl std::fmt::Display for OctreeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let total = self.total();
+        if self.chunks > 1 {
+            writeln!(f, "Chunks: {}", self.chunks)?;
+        }
+        writeln!(f, "Depth: {}", self.depth())?;
+        writeln!(
+            f,
+            "Nodes: {} ({} unused), {:.2} MB",
+            total.nodes,
+            self.unused,
+            self.bytes as f64 / 1000000.0
+        )?;
+        writeln!(
+            f,
+            "Leaves: {} ({} empty, {} solid, {} references)",
+            total.leaves(),
+            total.empty,
+            total.solid,
+            total.refs
+        )?;
+        writeln!(f, "Distinct colours: {}", self.colours.len())?;
+
+        let refs: Vec<String> = self.chunk_refs.iter().map(|id| id.to_string()).collect();
+        match refs.len() {
+            0 => writeln!(f, "Chunk references: none")?,
+            1..=16 => writeln!(f, "Chunk references: {}", refs.join(", "))?,
+            len => writeln!(
+                f,
+                "Chunk references: {} ({}, ...)",
+                len,
+                refs[..16].join(", ")
+            )?,
+        }
+
+        // Leaves per depth drawn as a bar
+        writeln!(
+            f,
+            "{:>5} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "Level", "Nodes", "Empty", "Solid", "Refs", "Leaves"
+        )?;
+        let max_leaves = self
+            .levels
+            .iter()
+            .map(LevelStats::leaves)
+            .max()
+            .unwrap_or(0);
+        for (depth, level) in self.levels.iter().enumerate() {
+            let bar = (level.leaves() * 40).div_ceil(max_leaves.max(1));
+            writeln!(
+                f,
+                "{:>5} {:>10} {:>10} {:>10} {:>10} {}",
+                depth + 1,
+                level.nodes,
+                level.empty,
+                level.solid,
+                level.refs,
+                "#".repeat(bar)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Voxel = Voxel { r: 255, g: 0, b: 0 };
+    const BLUE: Voxel = Voxel { r: 0, g: 0, b: 255 };
+
+    /// A red voxel, a blue octant that collapses into one leaf and a reference to chunk 5
+    fn sample() -> CpuOctree {
+        let mut leaves = vec![
+            (Vector3::new(0, 0, 0), Node::new(CHUNK_OFFSET, RED)),
+            (Vector3::new(3, 3, 3), Node::new(CHUNK_OFFSET + 5, RED)),
+        ];
+        for i in 0..8 {
+            let pos = Vector3::new(2 + (i & 1), i >> 1 & 1, i >> 2);
+            leaves.push((pos, Node::new(CHUNK_OFFSET, BLUE)));
+        }
+        let mut leaves: Vec<_> = leaves
+            .into_iter()
+            .map(|(pos, node)| (morton(pos, 2), node))
+            .collect();
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, 2)
+    }
+
+    fn counts(level: &LevelStats) -> [usize; 4] {
+        [level.nodes, level.empty, level.solid, level.refs]
+    }
+
+    #[test]
+    fn cpu_octree_levels() {
+        let mut octree = sample();
+        let stats = octree.stats();
+        assert_eq!(stats.depth(), 2);
+        // Two of the top level nodes are groups
+        assert_eq!(counts(&stats.levels[0]), [8, 5, 1, 0]);
+        assert_eq!(counts(&stats.levels[1]), [16, 14, 1, 1]);
+        assert_eq!(stats.nodes(), 24);
+        assert_eq!(stats.total().leaves(), 22);
+        assert_eq!(stats.colours.len(), 2);
+        assert_eq!(stats.chunk_refs.iter().copied().collect::<Vec<_>>(), [5]);
+        assert_eq!(stats.unused, 0);
+        assert_eq!(stats.bytes, 24 * std::mem::size_of::<Node>());
+
+        // A group nothing points to
+        octree.nodes.extend([EMPTY; 8]);
+        assert_eq!(octree.stats().unused, 8);
+    }
+
+    #[test]
+    fn gpu_octree_holes_are_unused() {
+        let (red, blue) = (RED.to_value(), BLUE.to_value());
+        let black = Voxel::new(0, 0, 0).to_value();
+        let see_through = (VOXEL_OFFSET + SEE_THROUGH + 0x123456) << 4;
+        let mut octree = Octree::new([red, black, see_through, black, black, black, black, blue]);
+        octree.subdivide(
+            0,
+            [blue, black, black, black, black, black, black, black],
+            2,
+        );
+
+        let stats = octree.stats();
+        assert_eq!(counts(&stats.levels[0]), [8, 6, 1, 0]);
+        assert_eq!(counts(&stats.levels[1]), [8, 7, 1, 0]);
+        assert_eq!(stats.colours.len(), 1);
+        assert_eq!(stats.unused, 0);
+
+        // The group is left as a hole in the nodes
+        octree.unsubdivide(0);
+        let stats = octree.stats();
+        assert_eq!(stats.depth(), 1);
+        assert_eq!(counts(&stats.levels[0]), [8, 6, 2, 0]);
+        assert_eq!(stats.unused, 8);
+        assert_eq!(
+            stats.bytes,
+            16 * (std::mem::size_of::<u32>() + std::mem::size_of::<Vector3<f32>>())
+                + std::mem::size_of::<usize>()
+        );
+
+        // Until it's filled again
+        octree.subdivide(1, [red; 8], 2);
+        assert_eq!(octree.stats().unused, 0);
+    }
+
+    #[test]
+    fn merging_adds_levels() {
+        let mut stats = sample().stats();
+        let mut flat = CpuOctree::new(0b1).stats();
+        flat.unused = 3;
+        stats.merge(&flat);
+
+        assert_eq!(stats.chunks, 2);
+        assert_eq!(counts(&stats.levels[0]), [16, 12, 1, 1]);
+        assert_eq!(counts(&stats.levels[1]), [16, 14, 1, 1]);
+        assert_eq!(stats.unused, 3);
+        assert_eq!(stats.bytes, 32 * std::mem::size_of::<Node>());
+        // The reference of CpuOctree::new is to block 1
+        assert_eq!(stats.chunk_refs.iter().copied().collect::<Vec<_>>(), [1, 5]);
+
+        // Merging a deeper tree into a shallower one adds the levels
+        let mut flat = CpuOctree::new(0b1).stats();
+        flat.merge(&sample().stats());
+        assert_eq!(flat.depth(), 2);
+        assert_eq!(flat.nodes(), 32);
+    }
+
+    #[test]
+    fn folder_stats_reads_chunk_files() {
+        let path = std::env::temp_dir().join(format!("folder_stats_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+
+        let chunk = sample();
+        let id = CHUNK_OFFSET / 2;
+        let mut root = CpuOctree::new(0);
+        root.put_in_block(Vector3::new(-0.5, -0.5, -0.5), id, 1);
+        root.save_file(&path.join("0.bin")).unwrap();
+        chunk.save_file(&path.join(format!("{}.bin", id))).unwrap();
+
+        let world = World::new(path.to_str().unwrap().to_string());
+        let stats = world.folder_stats().unwrap();
+        let mut expected = world.stats();
+        expected.merge(&root.stats());
+        expected.merge(&chunk.stats());
+
+        assert_eq!(stats.chunks, world.chunks.len() + 2);
+        assert_eq!(stats.chunks, expected.chunks);
+        assert_eq!(counts(&stats.total()), counts(&expected.total()));
+        assert_eq!(stats.bytes, expected.bytes);
+        assert_eq!(stats.colours, expected.colours);
+        assert!(stats.chunk_refs.contains(&id) && stats.chunk_refs.contains(&5));
+
+        std::fs::write(path.join("7.bin"), [0; 3]).unwrap();
+        let error = world.folder_stats().unwrap_err();
+        assert!(error.starts_with("Chunk 7"), "{}", error);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
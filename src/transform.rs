@@ -0,0 +1,233 @@
+use super::*;
+
+/// A rotation or mirroring of the cube. Output axis `k` is input axis `axes[k]`, negated if
+/// `flip[k]` is set.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Orientation {
+    pub axes: [usize; 3],
+    pub flip: [bool; 3],
+}
+
+#[allow(dead_code)]
+impl Orientation {
+    pub const IDENTITY: Orientation = Orientation {
+        axes: [0, 1, 2],
+        flip: [false, false, false],
+    };
+
+    /// How `load_vox` maps MagicaVoxel coordinates, which are z up, to ours: x is mirrored
+    /// and y and z are swapped
+    pub const VOX: Orientation = Orientation {
+        axes: [0, 2, 1],
+        flip: [true, false, false],
+    };
+
+    /// Quarter turns about the axis, counterclockwise looking down it
+    pub fn rotate(axis: usize, quarter_turns: i32) -> Self {
+        let u = (axis + 1) % 3;
+        let v = (axis + 2) % 3;
+        let mut quarter = Orientation::IDENTITY;
+        quarter.axes[u] = v;
+        quarter.flip[u] = true;
+        quarter.axes[v] = u;
+
+        (0..quarter_turns.rem_euclid(4)).fold(Orientation::IDENTITY, |t, _| t.then(quarter))
+    }
+
+    pub fn mirror(axis: usize) -> Self {
+        let mut t = Orientation::IDENTITY;
+        t.flip[axis] = true;
+        t
+    }
+
+    /// `self` followed by `other`
+    pub fn then(self, other: Orientation) -> Self {
+        let mut t = Orientation::IDENTITY;
+        for k in 0..3 {
+            t.axes[k] = self.axes[other.axes[k]];
+            t.flip[k] = other.flip[k] ^ self.flip[other.axes[k]];
+        }
+        t
+    }
+
+    pub fn inverse(self) -> Self {
+        let mut t = Orientation::IDENTITY;
+        for k in 0..3 {
+            t.axes[self.axes[k]] = k;
+            t.flip[self.axes[k]] = self.flip[k];
+        }
+        t
+    }
+
+    /// Where child `i` of a node ends up
+    pub fn child_index(&self, i: usize) -> usize {
+        let bits = child_offset(i);
+        let bit = |k: usize| (bits[self.axes[k]] ^ self.flip[k] as u32) as usize;
+        bit(0) * 4 + bit(1) * 2 + bit(2)
+    }
+
+    /// Position in a grid `size` voxels along each axis
+    pub fn apply_grid(&self, pos: Vector3<u32>, size: u32) -> Vector3<u32> {
+        let axis = |k: usize| {
+            let p = pos[self.axes[k]];
+            if self.flip[k] {
+                size - 1 - p
+            } else {
+                p
+            }
+        };
+        Vector3::new(axis(0), axis(1), axis(2))
+    }
+
+    /// Position relative to a centre, like the voxels of structures
+    pub fn apply_signed(&self, pos: Vector3<i32>) -> Vector3<i32> {
+        let axis = |k: usize| {
+            let p = pos[self.axes[k]];
+            if self.flip[k] {
+                -p
+            } else {
+                p
+            }
+        };
+        Vector3::new(axis(0), axis(1), axis(2))
+    }
+}
+
+#[allow(dead_code)]
+impl CpuOctree {
+    /// Reorders the children of every node, so nothing is lost and the mips stay valid.
+    /// Referenced blocks and chunks aren't transformed.
+    pub fn transform(&mut self, t: Orientation) {
+        for group in self.nodes.chunks_exact_mut(8) {
+            let old: Vec<Node> = group.to_vec();
+            for (i, node) in old.into_iter().enumerate() {
+                group[t.child_index(i)] = node;
+            }
+        }
+    }
+
+    pub fn rotate(&mut self, axis: usize, quarter_turns: i32) {
+        self.transform(Orientation::rotate(axis, quarter_turns));
+    }
+
+    pub fn mirror(&mut self, axis: usize) {
+        self.transform(Orientation::mirror(axis));
+    }
+
+    /// Moves everything by `offset` nodes at `depth`, whatever ends up outside is cut off.
+    /// References that would end up split between nodes are an error.
+    pub fn translate(&self, offset: Vector3<i64>, depth: u32) -> Result<CpuOctree, String> {
+        CpuOctree::new(0).union(self, offset, depth, Priority::Placed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// All 48 rotations and mirrorings
+    fn all() -> Vec<Orientation> {
+        let mut all = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    for mirrored in [false, true] {
+                        let mut t = Orientation::rotate(0, x)
+                            .then(Orientation::rotate(1, y))
+                            .then(Orientation::rotate(2, z));
+                        if mirrored {
+                            t = t.then(Orientation::mirror(0));
+                        }
+                        if !all.contains(&t) {
+                            all.push(t);
+                        }
+                    }
+                }
+            }
+        }
+        all
+    }
+
+    #[test]
+    fn then_and_inverse() {
+        let all = all();
+        assert_eq!(all.len(), 48);
+
+        let p = Vector3::new(1, -2, 3);
+        for &a in &all {
+            assert_eq!(a.then(a.inverse()), Orientation::IDENTITY);
+            assert_eq!(a.inverse().then(a), Orientation::IDENTITY);
+            for &b in &all {
+                assert_eq!(a.then(b).apply_signed(p), b.apply_signed(a.apply_signed(p)));
+            }
+        }
+
+        for axis in 0..3 {
+            let quarter = Orientation::rotate(axis, 1);
+            assert_eq!(quarter.then(quarter), Orientation::rotate(axis, 2));
+            assert_eq!(Orientation::rotate(axis, 4), Orientation::IDENTITY);
+            assert_eq!(Orientation::rotate(axis, -1), quarter.inverse());
+            // Counterclockwise looking down the axis
+            let mut u = Vector3::zero();
+            u[(axis + 1) % 3] = 1;
+            let mut v = Vector3::zero();
+            v[(axis + 2) % 3] = 1;
+            assert_eq!(quarter.apply_signed(u), v);
+        }
+    }
+
+    #[test]
+    fn child_index_matches_grid() {
+        for t in all() {
+            for i in 0..8 {
+                let moved = t.apply_grid(child_offset(i), 2);
+                assert_eq!(
+                    t.child_index(i),
+                    (moved.x * 4 + moved.y * 2 + moved.z) as usize
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn vox_matches_old_axis_swap() {
+        // What load_vox and load_structure used to do by hand
+        let size = 8;
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    let pos = Orientation::VOX.apply_grid(Vector3::new(x, y, z), size);
+                    assert_eq!(pos, Vector3::new(size - x - 1, z, y));
+
+                    let (x, y, z) = (x as i32, y as i32, z as i32);
+                    let half = size as i32 / 2;
+                    let pos = Orientation::VOX.apply_signed(Vector3::new(x - half, y - half, z));
+                    assert_eq!(pos, Vector3::new(half - x, z, y - half));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transform_moves_voxels() {
+        let leaves = [(
+            morton(Vector3::new(0, 1, 3), 2),
+            Node::new(CHUNK_OFFSET, Voxel::new(255, 0, 0)),
+        )];
+        let octree = CpuOctree::from_sorted_leaves(&leaves, 2);
+
+        for t in all() {
+            let mut moved = CpuOctree::from_sorted_leaves(&leaves, 2);
+            moved.transform(t);
+            let pos = t.apply_grid(Vector3::new(0, 1, 3), 4);
+            let (index, depth) = moved.lookup(pos, 2);
+            assert_eq!(
+                (depth, moved.nodes[index].value),
+                (2, Voxel::new(255, 0, 0))
+            );
+
+            moved.transform(t.inverse());
+            assert_eq!(format!("{:?}", moved), format!("{:?}", octree));
+        }
+    }
+}
@@ -0,0 +1,532 @@
+use super::*;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Volumes are read this many levels at a time so only one brick is in memory
+const BRICK_DEPTH: u32 = 6;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SampleType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl SampleType {
+    /// Names used by NRRD and the command line
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "u8" | "uchar" | "unsigned char" | "uint8" | "uint8_t" => SampleType::U8,
+            "i8" | "char" | "signed char" | "int8" | "int8_t" => SampleType::I8,
+            "u16" | "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => {
+                SampleType::U16
+            }
+            "i16" | "short" | "short int" | "signed short" | "signed short int" | "int16"
+            | "int16_t" => SampleType::I16,
+            "u32" | "uint" | "unsigned int" | "uint32" | "uint32_t" => SampleType::U32,
+            "i32" | "int" | "signed int" | "int32" | "int32_t" => SampleType::I32,
+            "f32" | "float" => SampleType::F32,
+            _ => return Err(format!("Unsupported sample type '{}'", name)),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            SampleType::U8 | SampleType::I8 => 1,
+            SampleType::U16 | SampleType::I16 => 2,
+            SampleType::U32 | SampleType::I32 | SampleType::F32 => 4,
+        }
+    }
+
+    /// Smallest and biggest value, floats are taken to be from 0 to 1
+    fn range(self) -> (f32, f32) {
+        match self {
+            SampleType::U8 => (0.0, u8::MAX as f32),
+            SampleType::I8 => (i8::MIN as f32, i8::MAX as f32),
+            SampleType::U16 => (0.0, u16::MAX as f32),
+            SampleType::I16 => (i16::MIN as f32, i16::MAX as f32),
+            SampleType::U32 => (0.0, u32::MAX as f32),
+            SampleType::I32 => (i32::MIN as f32, i32::MAX as f32),
+            SampleType::F32 => (0.0, 1.0),
+        }
+    }
+
+    fn read(self, bytes: &[u8], big_endian: bool) -> f32 {
+        let mut four = [0; 4];
+        four[..bytes.len()].copy_from_slice(bytes);
+        if big_endian {
+            four[..bytes.len()].reverse();
+        }
+        let two = [four[0], four[1]];
+        match self {
+            SampleType::U8 => four[0] as f32,
+            SampleType::I8 => four[0] as i8 as f32,
+            SampleType::U16 => u16::from_le_bytes(two) as f32,
+            SampleType::I16 => i16::from_le_bytes(two) as f32,
+            SampleType::U32 => u32::from_le_bytes(four) as f32,
+            SampleType::I32 => i32::from_le_bytes(four) as f32,
+            SampleType::F32 => f32::from_le_bytes(four),
+        }
+    }
+}
+
+/// Where the samples of a volume are in a file, x changes fastest then y then z
+pub struct Volume {
+    pub path: PathBuf,
+    /// Bytes before the first sample
+    pub offset: u64,
+    pub size: Vector3<u32>,
+    pub sample_type: SampleType,
+    pub big_endian: bool,
+}
+
+impl Volume {
+    /// Reads the header of a .nrrd file, or of a detached .nhdr header. Only raw encoding can
+    /// be read a brick at a time so compressed files aren't supported.
+    pub fn load_nrrd(path: &Path) -> Result<Self, String> {
+        let mut file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        let mut header = Vec::new();
+        // The header is text up to the first empty line
+        let mut byte = [0];
+        while !header.ends_with(b"\n\n") && !header.ends_with(b"\r\n\r\n") {
+            if file.read(&mut byte).map_err(|e| e.to_string())? == 0 {
+                break;
+            }
+            header.push(byte[0]);
+        }
+        let header = String::from_utf8_lossy(&header).to_string();
+
+        let mut lines = header.lines();
+        if !lines.next().is_some_and(|magic| magic.starts_with("NRRD")) {
+            return Err("Not a NRRD file".to_string());
+        }
+
+        let mut volume = Volume {
+            path: path.to_path_buf(),
+            offset: header.len() as u64,
+            size: Vector3::zero(),
+            sample_type: SampleType::U8,
+            big_endian: false,
+        };
+        let mut dimension = 0;
+        let mut byte_skip = 0i64;
+        for line in lines {
+            if line.starts_with('#') {
+                continue;
+            }
+            // Key/value pairs use ":=" and are ignored
+            let (field, value) = match line.split_once(": ") {
+                Some((field, value)) if !field.ends_with(':') => (field, value.trim()),
+                _ => continue,
+            };
+
+            match field {
+                "type" => volume.sample_type = SampleType::from_name(value)?,
+                "dimension" => dimension = parse(value)?,
+                "sizes" => {
+                    let sizes = value
+                        .split_whitespace()
+                        .map(parse)
+                        .collect::<Result<Vec<u32>, String>>()?;
+                    if let [x, y, z] = sizes[..] {
+                        volume.size = Vector3::new(x, y, z);
+                    }
+                }
+                "endian" => volume.big_endian = value == "big",
+                "encoding" if value != "raw" => {
+                    return Err(format!("Only raw NRRD files are supported, not {}", value))
+                }
+                "byte skip" => byte_skip = parse(value)?,
+                "data file" | "datafile" => {
+                    volume.path = path.with_file_name(value);
+                    volume.offset = 0;
+                }
+                _ => {}
+            }
+        }
+
+        if dimension != 3 || volume.size.x == 0 || volume.size.y == 0 || volume.size.z == 0 {
+            return Err("Only 3 dimensional NRRD volumes are supported".to_string());
+        }
+
+        if byte_skip == -1 {
+            // The samples are at the end of the file
+            let length = std::fs::metadata(&volume.path)
+                .map_err(|e| e.to_string())?
+                .len();
+            volume.offset = length
+                .checked_sub(volume.sample_count() * volume.sample_type.size() as u64)
+                .ok_or("NRRD data file is truncated")?;
+        } else {
+            volume.offset += byte_skip as u64;
+        }
+
+        Ok(volume)
+    }
+
+    fn sample_count(&self) -> u64 {
+        self.size.x as u64 * self.size.y as u64 * self.size.z as u64
+    }
+
+    /// Reads the samples in the box from `min` with `size` samples along each side, parts
+    /// outside the volume are None
+    fn read_brick(
+        &self,
+        file: &mut std::fs::File,
+        min: Vector3<u32>,
+        size: u32,
+    ) -> Result<Vec<Option<f32>>, String> {
+        let sample_size = self.sample_type.size();
+        let mut samples = vec![None; (size * size * size) as usize];
+        let width = size.min(self.size.x.saturating_sub(min.x)) as usize;
+        let mut row = vec![0; width * sample_size];
+        for z in min.z..(min.z + size).min(self.size.z) {
+            for y in min.y..(min.y + size).min(self.size.y) {
+                let index =
+                    (z as u64 * self.size.y as u64 + y as u64) * self.size.x as u64 + min.x as u64;
+                file.seek(SeekFrom::Start(self.offset + index * sample_size as u64))
+                    .map_err(|e| e.to_string())?;
+                file.read_exact(&mut row)
+                    .map_err(|_| "Volume file is truncated".to_string())?;
+
+                let start = (((z - min.z) * size + y - min.y) * size) as usize;
+                for (x, bytes) in row.chunks_exact(sample_size).enumerate() {
+                    samples[start + x] = Some(self.sample_type.read(bytes, self.big_endian));
+                }
+            }
+        }
+        Ok(samples)
+    }
+}
+
+/// Colour and opacity at each density, blended between the stops
+pub struct TransferFunction {
+    /// (density, colour, opacity) sorted by density
+    stops: Vec<(f32, Vector3<f32>, f32)>,
+}
+
+impl TransferFunction {
+    /// Lines of "density r g b opacity" with densities in the units of the samples and
+    /// opacities from 0 to 1
+    pub fn new(text: &str) -> Result<Self, String> {
+        let mut stops = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let stop = match words.as_slice() {
+                [density, r, g, b, opacity] => (|| {
+                    Ok((
+                        parse(density)?,
+                        Vector3::new(parse(r)?, parse(g)?, parse(b)?),
+                        parse(opacity)?,
+                    ))
+                })(),
+                _ => Err("Expected density r g b opacity".to_string()),
+            };
+            stops.push(stop.map_err(|e: String| format!("Line {}: {}", i + 1, e))?);
+        }
+
+        if stops.is_empty() {
+            return Err("Transfer function has no stops".to_string());
+        }
+        stops.sort_by(|a: &(f32, _, _), b| a.0.total_cmp(&b.0));
+        Ok(Self { stops })
+    }
+
+    /// A grey ramp that's solid above a quarter of the range of the sample type
+    pub fn default_for(sample_type: SampleType) -> Self {
+        let (min, max) = sample_type.range();
+        let at = |t: f32| min + (max - min) * t;
+        Self {
+            stops: vec![
+                (at(0.2), Vector3::new(0.0, 0.0, 0.0), 0.0),
+                (at(0.3), Vector3::new(80.0, 80.0, 80.0), 1.0),
+                (at(1.0), Vector3::new(255.0, 255.0, 255.0), 1.0),
+            ],
+        }
+    }
+
+    /// Densities that are at least half opaque are solid
+    pub fn voxel(&self, density: f32) -> Option<Voxel> {
+        let next = self.stops.partition_point(|stop| stop.0 <= density);
+        let (colour, opacity) = if next == 0 {
+            (self.stops[0].1, self.stops[0].2)
+        } else if next == self.stops.len() {
+            let last = self.stops[next - 1];
+            (last.1, last.2)
+        } else {
+            let (a, b) = (self.stops[next - 1], self.stops[next]);
+            let t = (density - a.0) / (b.0 - a.0);
+            (a.1 + (b.1 - a.1) * t, a.2 + (b.2 - a.2) * t)
+        };
+
+        (opacity >= 0.5).then(|| {
+            let channel = |c: f32| c.clamp(0.0, 255.0).round() as u8;
+            non_black(Voxel::new(
+                channel(colour.x),
+                channel(colour.y),
+                channel(colour.z),
+            ))
+        })
+    }
+}
+
+struct VolumeImport<'a> {
+    volume: &'a Volume,
+    transfer: &'a TransferFunction,
+    file: std::fs::File,
+    depth: u32,
+    brick_depth: u32,
+    nodes: Vec<Node>,
+}
+
+impl<'a> VolumeImport<'a> {
+    /// Builds the group at grid position `pos` and `level`, reading a brick when it gets down
+    /// to one
+    fn build(&mut self, pos: Vector3<u32>, level: u32) -> Result<Node, String> {
+        let span = 1u32 << (self.depth - level);
+        if (0..3).any(|axis| pos[axis] * span >= self.volume.size[axis]) {
+            return Ok(EMPTY);
+        }
+
+        if self.depth - level == self.brick_depth {
+            let samples = self.volume.read_brick(&mut self.file, pos * span, span)?;
+            let voxels: Vec<Option<Voxel>> = samples
+                .iter()
+                .map(|sample| sample.and_then(|density| self.transfer.voxel(density)))
+                .collect();
+            return Ok(self.build_brick(&voxels, Vector3::zero(), level));
+        }
+
+        let start = self.nodes.len();
+        self.nodes.extend([EMPTY; 8]);
+        for i in 0..8 {
+            self.nodes[start + i] = self.build(pos * 2 + child_offset(i), level + 1)?;
+        }
+        Ok(collapse(&mut self.nodes, start))
+    }
+
+    fn build_brick(&mut self, voxels: &[Option<Voxel>], pos: Vector3<u32>, level: u32) -> Node {
+        if level == self.depth {
+            let size = 1 << self.brick_depth;
+            let voxel = voxels[((pos.z * size + pos.y) * size + pos.x) as usize];
+            return voxel.map_or(EMPTY, |voxel| Node::new(CHUNK_OFFSET, voxel));
+        }
+
+        let start = self.nodes.len();
+        self.nodes.extend([EMPTY; 8]);
+        for i in 0..8 {
+            self.nodes[start + i] = self.build_brick(voxels, pos * 2 + child_offset(i), level + 1);
+        }
+        collapse(&mut self.nodes, start)
+    }
+}
+
+impl CpuOctree {
+    /// Voxels where the transfer function is at least half opaque, one per sample. The longest
+    /// side of the volume fills the octree. The volume is read a brick at a time so it never
+    /// has to fit in memory.
+    pub fn load_volume(volume: &Volume, transfer: &TransferFunction) -> Result<CpuOctree, String> {
+        let longest = volume.size.x.max(volume.size.y).max(volume.size.z).max(2);
+        let depth = 32 - (longest - 1).leading_zeros();
+
+        let mut import = VolumeImport {
+            volume,
+            transfer,
+            file: std::fs::File::open(&volume.path)
+                .map_err(|e| format!("{}: {}", volume.path.display(), e))?,
+            depth,
+            brick_depth: BRICK_DEPTH.min(depth - 1),
+            nodes: Vec::new(),
+        };
+
+        let root = import.build(Vector3::zero(), 0)?;
+        let mut nodes = import.nodes;
+        if root.pointer >= CHUNK_OFFSET {
+            // Everything collapsed into one leaf, the top level has to stay a group
+            nodes = vec![root; 8];
+        }
+        Ok(CpuOctree {
+            nodes,
+            top_mip: Voxel::new(0, 0, 0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(fields: &str) -> Vec<u8> {
+        format!("NRRD0004\n# comment\ndimension: 3\n{}\n", fields).into_bytes()
+    }
+
+    #[test]
+    fn nrrd_headers() {
+        let path = std::env::temp_dir().join(format!("volume_nrrd_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+
+        // Samples at the end of the file after some padding, most significant byte first
+        let mut data = header(
+            "type: uint16\nsizes: 3 2 2\nendian: big\nencoding: raw\n\
+            spacing:=1 1 1\nbyte skip: -1\n",
+        );
+        let start = data.len() + 5;
+        data.extend([0xff; 5]);
+        for i in 0..12u16 {
+            data.extend((i * 300).to_be_bytes());
+        }
+        std::fs::write(path.join("skip.nrrd"), &data).unwrap();
+        let volume = Volume::load_nrrd(&path.join("skip.nrrd")).unwrap();
+        assert_eq!(volume.path, path.join("skip.nrrd"));
+        assert_eq!(volume.offset, start as u64);
+        assert_eq!(volume.size, Vector3::new(3, 2, 2));
+        assert_eq!(volume.sample_type, SampleType::U16);
+        assert!(volume.big_endian);
+
+        let mut file = std::fs::File::open(&volume.path).unwrap();
+        let samples = volume.read_brick(&mut file, Vector3::zero(), 2).unwrap();
+        // x = 1, y = 1, z = 1 is sample 10
+        assert_eq!(samples[7], Some(3000.0));
+        assert_eq!(samples[1], Some(300.0));
+
+        // Detached header pointing at a data file next to it
+        std::fs::write(
+            path.join("detached.nhdr"),
+            header(
+                "type: float\nsizes: 2 2 2\nencoding: raw\ndata file: samples.raw\nbyte skip: 4\n",
+            ),
+        )
+        .unwrap();
+        let volume = Volume::load_nrrd(&path.join("detached.nhdr")).unwrap();
+        assert_eq!(volume.path, path.join("samples.raw"));
+        assert_eq!(volume.offset, 4);
+        assert_eq!(volume.sample_type, SampleType::F32);
+        assert!(!volume.big_endian);
+
+        std::fs::write(
+            path.join("gzip.nrrd"),
+            header("type: uchar\nsizes: 2 2 2\nencoding: gzip\n"),
+        )
+        .unwrap();
+        assert!(Volume::load_nrrd(&path.join("gzip.nrrd")).is_err());
+        std::fs::write(
+            path.join("flat.nrrd"),
+            b"NRRD0004\ndimension: 2\nsizes: 2 2\n\n",
+        )
+        .unwrap();
+        assert!(Volume::load_nrrd(&path.join("flat.nrrd")).is_err());
+        std::fs::write(path.join("text.nrrd"), b"P2\n2 2\n255\n\n").unwrap();
+        assert!(Volume::load_nrrd(&path.join("text.nrrd")).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    /// A 5x3x6 volume of bytes where each sample is its own index
+    fn counting_volume(path: &Path) -> Volume {
+        std::fs::write(path, (0..90).collect::<Vec<u8>>()).unwrap();
+        Volume {
+            path: path.to_path_buf(),
+            offset: 0,
+            size: Vector3::new(5, 3, 6),
+            sample_type: SampleType::U8,
+            big_endian: false,
+        }
+    }
+
+    #[test]
+    fn bricks_at_the_edges() {
+        let path = std::env::temp_dir().join(format!("volume_brick_{}.raw", std::process::id()));
+        let volume = counting_volume(&path);
+        let mut file = std::fs::File::open(&path).unwrap();
+
+        let min = Vector3::new(4, 0, 4);
+        let samples = volume.read_brick(&mut file, min, 4).unwrap();
+        for z in 0..4 {
+            for y in 0..4 {
+                for x in 0..4 {
+                    let sample = samples[((z * 4 + y) * 4 + x) as usize];
+                    let (vx, vy, vz) = (min.x + x, min.y + y, min.z + z);
+                    if vx < 5 && vy < 3 && vz < 6 {
+                        assert_eq!(sample, Some(((vz * 3 + vy) * 5 + vx) as f32));
+                    } else {
+                        assert_eq!(sample, None, "{} {} {}", x, y, z);
+                    }
+                }
+            }
+        }
+
+        // Entirely outside
+        let samples = volume
+            .read_brick(&mut file, Vector3::new(8, 0, 0), 2)
+            .unwrap();
+        assert!(samples.iter().all(Option::is_none));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn transfer_function_blends_stops() {
+        let transfer = TransferFunction::new("# ramp\n100 200 100 0 1\n\n0 0 0 0 0\n").unwrap();
+        assert_eq!(transfer.voxel(-10.0), None);
+        assert_eq!(transfer.voxel(49.0), None);
+        assert_eq!(transfer.voxel(50.0), Some(Voxel::new(100, 50, 0)));
+        assert_eq!(transfer.voxel(75.0), Some(Voxel::new(150, 75, 0)));
+        assert_eq!(transfer.voxel(100.0), Some(Voxel::new(200, 100, 0)));
+        assert_eq!(transfer.voxel(1000.0), Some(Voxel::new(200, 100, 0)));
+
+        // Solid black is still solid
+        let black = TransferFunction::new("0 0 0 0 1").unwrap();
+        assert_eq!(black.voxel(0.0), Some(Voxel::new(1, 1, 1)));
+
+        assert!(TransferFunction::new("").is_err());
+        assert!(TransferFunction::new("0 0 0 0").is_err());
+        assert!(TransferFunction::new("0 0 zero 0 1").is_err());
+    }
+
+    #[test]
+    fn imports_a_raw_volume() {
+        let path = std::env::temp_dir().join(format!("volume_import_{}.raw", std::process::id()));
+        let solid = [(0, 0, 0), (2, 1, 0), (1, 2, 2), (2, 2, 2)];
+        let mut data = vec![0u8; 27];
+        for &(x, y, z) in &solid {
+            data[(z * 3 + y) * 3 + x] = 255;
+        }
+        std::fs::write(&path, &data).unwrap();
+        let volume = Volume {
+            path: path.clone(),
+            offset: 0,
+            size: Vector3::new(3, 3, 3),
+            sample_type: SampleType::U8,
+            big_endian: false,
+        };
+
+        let octree =
+            CpuOctree::load_volume(&volume, &TransferFunction::default_for(SampleType::U8))
+                .unwrap();
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    let (index, _) = octree.lookup(Vector3::new(x, y, z), 2);
+                    let node = octree.nodes[index];
+                    if solid.contains(&(x as usize, y as usize, z as usize)) {
+                        assert_eq!(node.value, Voxel::new(255, 255, 255));
+                    } else {
+                        assert!(!is_solid(node), "{} {} {}", x, y, z);
+                    }
+                }
+            }
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,419 @@
+use super::*;
+
+/// Positions are u32 so nodes can't be any deeper
+const MAX_WALK_DEPTH: u32 = 32;
+
+/// A node reached while walking an octree
+#[derive(Copy, Clone, Debug)]
+pub struct Visit {
+    /// Chunk the node is stored in, 0 when walking a single octree
+    pub chunk: u32,
+    #[allow(dead_code)]
+    pub index: usize,
+    /// Integer position at `depth`, each axis goes from 0 to 2^depth - 1
+    pub pos: Vector3<u32>,
+    /// The top level is depth 1
+    pub depth: u32,
+    pub node: Node,
+    /// Whether the walk stops at this node, either because it has no children or because of
+    /// the settings
+    pub leaf: bool,
+}
+
+impl Visit {
+    /// Minimum corner and size of the node in the -1 to 1 space used by `find_voxel`
+    pub fn bounds(&self) -> (Vector3<f32>, f32) {
+        let size = 2.0 / (1u64 << self.depth) as f32;
+        let min = self.pos.cast::<f32>().unwrap() * size - Vector3::new(1.0, 1.0, 1.0);
+        (min, size)
+    }
+
+    pub fn centre(&self) -> Vector3<f32> {
+        let (min, size) = self.bounds();
+        min + Vector3::new(size, size, size) / 2.0
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct WalkSettings {
+    /// Nodes at this depth are treated as leaves with their mip value
+    pub max_depth: Option<u32>,
+    /// Only nodes that overlap the box from the first corner to the second, in the -1 to 1
+    /// space used by `find_voxel`
+    pub region: Option<(Vector3<f32>, Vector3<f32>)>,
+    /// Walk into the blocks and chunks that leaves reference. Only works when walking a
+    /// world, references to chunks that aren't loaded are still leaves.
+    pub follow_refs: bool,
+}
+
+/// Anything that stores nodes in groups of 8
+pub trait Groups {
+    /// The 8 nodes starting at `group` in `chunk`
+    fn group(&self, chunk: u32, group: usize) -> Option<[Node; 8]>;
+
+    /// Whether a reference to `chunk` can be walked into
+    fn has_chunk(&self, chunk: u32) -> bool {
+        self.group(chunk, 0).is_some()
+    }
+}
+
+impl Groups for CpuOctree {
+    fn group(&self, chunk: u32, group: usize) -> Option<[Node; 8]> {
+        if chunk != 0 {
+            return None;
+        }
+
+        self.nodes
+            .get(group..group + 8)
+            .map(|nodes| nodes.try_into().unwrap())
+    }
+}
+
+impl Groups for Octree {
+    fn group(&self, chunk: u32, group: usize) -> Option<[Node; 8]> {
+        if chunk != 0 || group + 8 > self.nodes.len() {
+            return None;
+        }
+
+        Some(std::array::from_fn(|i| {
+            let value = self.get_node(group + i);
+            if value >= VOXEL_OFFSET {
+                Node::new(CHUNK_OFFSET, Voxel::from_value(value - VOXEL_OFFSET))
+            } else {
+                Node::new(value, Voxel::new(0, 0, 0))
+            }
+        }))
+    }
+}
+
+impl Groups for ChunkFiles<'_> {
+    fn group(&self, chunk: u32, group: usize) -> Option<[Node; 8]> {
+        if self.world.chunks.contains_key(&chunk) {
+            return self.world.group(chunk, group);
+        }
+
+        self.chunk(chunk)?
+            .nodes
+            .get(group..group + 8)
+            .map(|nodes| nodes.try_into().unwrap())
+    }
+
+    /// Doesn't read the file so the chunks next to the one being walked aren't all loaded
+    fn has_chunk(&self, chunk: u32) -> bool {
+        self.world.chunks.contains_key(&chunk) || self.has_file(chunk)
+    }
+}
+
+impl Groups for World {
+    fn group(&self, chunk: u32, group: usize) -> Option<[Node; 8]> {
+        let chunk = self.chunks.get(&chunk)?;
+        chunk
+            .nodes
+            .get(group..group + 8)
+            .map(|nodes| nodes.try_into().unwrap())
+    }
+}
+
+/// Depth first iterator over every node, parents come before their children
+pub struct Walk<'a> {
+    groups: &'a dyn Groups,
+    settings: WalkSettings,
+    stack: Vec<Visit>,
+    /// Chunks the current node is inside of and the depth they were walked into at, so a
+    /// chunk that ends up referencing itself isn't walked into again
+    chunks: Vec<(u32, u32)>,
+}
+
+impl<'a> Walk<'a> {
+    fn new(groups: &'a dyn Groups, settings: WalkSettings) -> Self {
+        let mut walk = Self {
+            groups,
+            settings,
+            stack: Vec::new(),
+            chunks: vec![(0, 0)],
+        };
+        walk.push_group(0, 0, Vector3::zero(), 0);
+        walk
+    }
+
+    /// Calls `f` on every node, parents before children. Children are skipped when `f`
+    /// returns false.
+    pub fn visit(mut self, f: &mut dyn FnMut(&Visit) -> bool) {
+        while let Some(visit) = self.pop() {
+            if f(&visit) && !visit.leaf {
+                self.push_children(&visit);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Visit> {
+        let visit = self.stack.pop()?;
+        self.chunks.retain(|&(_, depth)| depth < visit.depth);
+        Some(visit)
+    }
+
+    /// Pushes the children of a node that isn't a leaf
+    fn push_children(&mut self, parent: &Visit) {
+        let pointer = parent.node.pointer;
+        if pointer < CHUNK_OFFSET {
+            self.push_group(parent.chunk, pointer as usize, parent.pos, parent.depth);
+        } else {
+            self.chunks.push((pointer - CHUNK_OFFSET, parent.depth));
+            self.push_group(pointer - CHUNK_OFFSET, 0, parent.pos, parent.depth);
+        }
+    }
+
+    fn push_group(&mut self, chunk: u32, group: usize, pos: Vector3<u32>, depth: u32) {
+        let nodes = match self.groups.group(chunk, group) {
+            Some(nodes) => nodes,
+            None => return,
+        };
+
+        // Reversed so child 0 comes out first
+        for i in (0..8).rev() {
+            let node = nodes[i];
+            let mut visit = Visit {
+                chunk,
+                index: group + i,
+                pos: pos * 2 + child_offset(i),
+                depth: depth + 1,
+                node,
+                leaf: false,
+            };
+
+            if let Some((min, max)) = self.settings.region {
+                let (node_min, size) = visit.bounds();
+                let node_max = node_min + Vector3::new(size, size, size);
+                if (0..3).any(|axis| node_min[axis] > max[axis] || node_max[axis] <= min[axis]) {
+                    continue;
+                }
+            }
+
+            visit.leaf = Some(visit.depth) == self.settings.max_depth
+                || visit.depth == MAX_WALK_DEPTH
+                || match node.pointer {
+                    pointer if pointer < CHUNK_OFFSET => {
+                        self.groups.group(chunk, pointer as usize).is_none()
+                    }
+                    CHUNK_OFFSET => true,
+                    pointer => {
+                        let target = pointer - CHUNK_OFFSET;
+                        !self.settings.follow_refs
+                            || self.chunks.iter().any(|&(chunk, _)| chunk == target)
+                            || !self.groups.has_chunk(target)
+                    }
+                };
+
+            self.stack.push(visit);
+        }
+    }
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Visit;
+
+    fn next(&mut self) -> Option<Visit> {
+        let visit = self.pop()?;
+        if !visit.leaf {
+            self.push_children(&visit);
+        }
+        Some(visit)
+    }
+}
+
+/// Follows `pos` from the top level down to the leaf containing it, or the node at
+/// `max_depth`, calling `f` on every node on the way. Walks into references when `follow_refs`
+/// and the chunk can be found, but never into a chunk it's already inside of. Returns the last
+/// node, None if there is no top level.
+pub fn descend(
+    groups: &dyn Groups,
+    pos: Vector3<f32>,
+    max_depth: Option<u32>,
+    follow_refs: bool,
+    f: &mut dyn FnMut(&Visit),
+) -> Option<Visit> {
+    let mut chunks = vec![0];
+    let mut group = 0;
+    let mut nodes = groups.group(0, 0)?;
+    let mut parent = Visit {
+        chunk: 0,
+        index: 0,
+        pos: Vector3::zero(),
+        depth: 0,
+        node: Node::new(0, Voxel::new(0, 0, 0)),
+        leaf: false,
+    };
+    loop {
+        let centre = parent.centre();
+        let child_index = (pos.x >= centre.x) as usize * 4
+            + (pos.y >= centre.y) as usize * 2
+            + (pos.z >= centre.z) as usize;
+        let mut visit = Visit {
+            chunk: parent.chunk,
+            index: group + child_index,
+            pos: parent.pos * 2 + child_offset(child_index),
+            depth: parent.depth + 1,
+            node: nodes[child_index],
+            leaf: true,
+        };
+
+        let next = match visit.node.pointer {
+            _ if Some(visit.depth) == max_depth || visit.depth == MAX_WALK_DEPTH => None,
+            pointer if pointer < CHUNK_OFFSET => Some((visit.chunk, pointer as usize)),
+            CHUNK_OFFSET => None,
+            pointer if follow_refs && !chunks.contains(&(pointer - CHUNK_OFFSET)) => {
+                Some((pointer - CHUNK_OFFSET, 0))
+            }
+            _ => None,
+        };
+        let next =
+            next.and_then(|(chunk, group)| Some((chunk, group, groups.group(chunk, group)?)));
+        visit.leaf = next.is_none();
+        f(&visit);
+
+        match next {
+            Some((chunk, next_group, next_nodes)) => {
+                if chunk != visit.chunk {
+                    chunks.push(chunk);
+                }
+                visit.chunk = chunk;
+                group = next_group;
+                nodes = next_nodes;
+                parent = visit;
+            }
+            None => return Some(visit),
+        }
+    }
+}
+
+impl CpuOctree {
+    /// Every node reachable from the top level. References aren't followed.
+    pub fn walk(&self, settings: WalkSettings) -> Walk<'_> {
+        Walk::new(self, settings)
+    }
+
+    pub fn leaves(&self, settings: WalkSettings) -> impl Iterator<Item = Visit> + '_ {
+        self.walk(settings).filter(|visit| visit.leaf)
+    }
+
+    #[allow(dead_code)]
+    pub fn visit(&self, settings: WalkSettings, f: &mut dyn FnMut(&Visit) -> bool) {
+        self.walk(settings).visit(f)
+    }
+}
+
+impl ChunkFiles<'_> {
+    /// Every node reachable from the root chunk, reading chunk files as they're reached
+    pub fn walk(&self, settings: WalkSettings) -> Walk<'_> {
+        Walk::new(self, settings)
+    }
+}
+
+impl World {
+    /// Every node reachable from the root chunk, only the loaded chunks can be walked into
+    pub fn walk(&self, settings: WalkSettings) -> Walk<'_> {
+        Walk::new(self, settings)
+    }
+
+    #[allow(dead_code)]
+    pub fn leaves(&self, settings: WalkSettings) -> impl Iterator<Item = Visit> + '_ {
+        self.walk(settings).filter(|visit| visit.leaf)
+    }
+
+    #[allow(dead_code)]
+    pub fn visit(&self, settings: WalkSettings, f: &mut dyn FnMut(&Visit) -> bool) {
+        self.walk(settings).visit(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One red voxel in the corner at depth 2, everything else collapses into empty leaves
+    fn corner_voxel() -> CpuOctree {
+        let leaves = [(0, Node::new(CHUNK_OFFSET, Voxel::new(255, 0, 0)))];
+        CpuOctree::from_sorted_leaves(&leaves, 2)
+    }
+
+    fn refs_to(chunk: u32) -> CpuOctree {
+        CpuOctree {
+            nodes: vec![Node::new(CHUNK_OFFSET + chunk, Voxel::new(0, 0, 0)); 8],
+            top_mip: Voxel::new(0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn walk_is_depth_first() {
+        let octree = corner_voxel();
+        let visits: Vec<_> = octree
+            .walk(WalkSettings::default())
+            .map(|visit| (visit.depth, visit.pos, visit.leaf))
+            .collect();
+
+        let mut expected = vec![(1, Vector3::zero(), false)];
+        expected.extend((0..8).map(|i| (2, child_offset(i), true)));
+        expected.extend((1..8).map(|i| (1, child_offset(i), true)));
+        assert_eq!(visits, expected);
+    }
+
+    #[test]
+    fn walk_prunes_region_and_depth() {
+        let octree = corner_voxel();
+        let corner = Vector3::new(-0.9, -0.9, -0.9);
+        let settings = WalkSettings {
+            region: Some((corner, corner)),
+            ..Default::default()
+        };
+        let visits: Vec<_> = octree
+            .walk(settings)
+            .map(|visit| (visit.depth, visit.pos))
+            .collect();
+        assert_eq!(visits, vec![(1, Vector3::zero()), (2, Vector3::zero())]);
+
+        let settings = WalkSettings {
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(octree.leaves(settings).count(), 8);
+    }
+
+    #[test]
+    fn descend_finds_the_leaf() {
+        let octree = corner_voxel();
+        let leaf = descend(
+            &octree,
+            Vector3::new(-0.9, -0.9, -0.4),
+            None,
+            false,
+            &mut |_| {},
+        );
+        let leaf = leaf.unwrap();
+        assert_eq!((leaf.depth, leaf.pos), (2, Vector3::new(0, 0, 1)));
+        assert_eq!(leaf.centre(), Vector3::new(-0.75, -0.75, -0.25));
+        assert_eq!(octree.lookup(Vector3::new(0, 0, 1), 2), (leaf.index, 2));
+        assert_eq!(
+            octree.path_to(Vector3::new(-0.9, -0.9, -0.4), None).len(),
+            2
+        );
+
+        let (_, depth, _) = octree.find_voxel(Vector3::new(0.5, 0.5, 0.5), None);
+        assert_eq!(depth, 1);
+    }
+
+    #[test]
+    fn chunks_referencing_themselves_end() {
+        let world = World::new(String::new());
+        world.chunks.insert(0, refs_to(100));
+        world.chunks.insert(100, refs_to(100));
+        let settings = WalkSettings {
+            follow_refs: true,
+            ..Default::default()
+        };
+        assert_eq!(world.walk(settings).count(), 8 + 64);
+        assert_eq!(world.leaves(settings).count(), 64);
+
+        let (chunk, _, depth, _) = world.find_voxel(Vector3::new(0.5, 0.5, 0.5), None);
+        assert_eq!((chunk, depth), (100, 2));
+    }
+}
@@ -1,11 +1,36 @@
 use super::*;
 use dashmap::{DashMap, DashSet};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 
+// Block ids loaded by World::new
+pub const BLOCK_STONE: u32 = 1;
+pub const BLOCK_DIRT: u32 = 2;
+pub const BLOCK_GRASS: u32 = 3;
+#[allow(dead_code)]
+pub const BLOCK_WOOD: u32 = 4;
+#[allow(dead_code)]
+pub const BLOCK_LEAF: u32 = 5;
+pub const BLOCK_SLATE: u32 = 6;
+pub const BLOCK_CRYSTAL: u32 = 7;
+#[allow(dead_code)]
+pub const BLOCK_GLASS: u32 = 8;
+
+/// Chunk files `ChunkFiles` keeps in memory at once
+const CACHED_CHUNKS: usize = 8;
+
+/// Chunk ids start at CHUNK_OFFSET / 2, with 2^30 chunks or more they run into the
+/// references to them
+pub const MAX_WORLD_DEPTH: u32 = 9;
+
 pub struct World {
     pub path: String,
     pub chunks: Arc<DashMap<u32, CpuOctree>>,
     pub loading: Arc<DashSet<u32>>,
+    /// Used to build mips and to send nodes to the gpu, saved in the manifest
+    pub mip_filter: MipFilter,
 }
 
 impl World {
@@ -14,128 +39,207 @@ impl World {
             path,
             chunks: Arc::new(DashMap::new()),
             loading: Arc::new(DashSet::new()),
+            mip_filter: MipFilter::default(),
         };
 
         world.chunks.insert(
             1,
             CpuOctree::load_file("blocks/stone.vox".to_string(), 0).unwrap(),
         );
-        world.generate_mip_tree(1);
+        world.generate_mip_tree(1).unwrap();
         world.chunks.insert(
             2,
             CpuOctree::load_file("blocks/dirt.vox".to_string(), 0).unwrap(),
         );
-        world.generate_mip_tree(2);
+        world.generate_mip_tree(2).unwrap();
         world.chunks.insert(
             3,
             CpuOctree::load_file("blocks/grass.vox".to_string(), 0).unwrap(),
         );
-        world.generate_mip_tree(3);
+        world.generate_mip_tree(3).unwrap();
         world.chunks.insert(
             4,
             CpuOctree::load_file("blocks/wood.vox".to_string(), 0).unwrap(),
         );
-        world.generate_mip_tree(4);
+        world.generate_mip_tree(4).unwrap();
         world.chunks.insert(
             5,
             CpuOctree::load_file("blocks/leaf.vox".to_string(), 0).unwrap(),
         );
-        world.generate_mip_tree(5);
+        world.generate_mip_tree(5).unwrap();
         world.chunks.insert(
             6,
             CpuOctree::load_file("blocks/slate.vox".to_string(), 0).unwrap(),
         );
-        world.generate_mip_tree(6);
+        world.generate_mip_tree(6).unwrap();
         world.chunks.insert(
             7,
             CpuOctree::load_file("blocks/crystal.vox".to_string(), 0).unwrap(),
         );
-        world.generate_mip_tree(7);
+        world.generate_mip_tree(7).unwrap();
         world.chunks.insert(
             8,
             CpuOctree::load_file("blocks/glass.vox".to_string(), 0).unwrap(),
         );
-        world.generate_mip_tree(8);
+        world.generate_mip_tree(8).unwrap();
 
         world
     }
 
+    /// Generates the world into the folder at `path`. If the folder holds a world that was
+    /// interrupted with the same settings the chunks already on disk are reused.
     pub fn generate_world<S: AsRef<std::ffi::OsStr> + Sized>(
         path: S,
         procedual: &mut Procedural,
+        gen_settings: &GenSettings,
         gpu: &Gpu,
+        progress: &GenProgress,
     ) -> Result<(), String> {
-        // Write chunk to file
+        if gen_settings.world_depth == 0 || gen_settings.world_depth > MAX_WORLD_DEPTH {
+            return Err(format!(
+                "World depth has to be from 1 to {}",
+                MAX_WORLD_DEPTH
+            ));
+        }
+
         let path = std::path::Path::new(&path);
+        let mut manifest = Manifest::new(gen_settings.clone());
         if path.exists() {
-            if path.file_stem().unwrap() == "tmp" {
-                std::fs::remove_dir_all(path).unwrap();
-                std::fs::create_dir(path).unwrap();
-            } else {
-                return Err("File already exists".to_string());
+            let unfinished = Manifest::load(path)
+                .ok()
+                .filter(|existing| existing.root_hash.is_none());
+            let same_settings = |existing: &Manifest| {
+                let (mut a, mut b) = (String::new(), String::new());
+                existing.gen_settings.write_manifest(&mut a);
+                gen_settings.write_manifest(&mut b);
+                a == b
+            };
+
+            match unfinished {
+                Some(existing) if same_settings(&existing) => manifest = existing,
+                _ if path.file_stem() == Some(std::ffi::OsStr::new("tmp")) => {
+                    std::fs::remove_dir_all(path).map_err(|e| e.to_string())?;
+                    std::fs::create_dir(path).map_err(|e| e.to_string())?;
+                }
+                Some(_) => {
+                    return Err("World was started with different settings".to_string());
+                }
+                None => return Err("File already exists".to_string()),
             }
         } else {
-            std::fs::create_dir(path).unwrap();
+            std::fs::create_dir(path).map_err(|e| e.to_string())?;
         }
 
-        // let root = procedual.generate_chunk(gpu, Vector3::new(-1.0, -1.0, -1.0), 0);
-        let mut root = CpuOctree::new(0);
-        let world_depth = 1;
+        // Chunks are added to the manifest as they finish so an interrupted run can resume
+        manifest.save(path)?;
+
+        let world_size = 1u64 << gen_settings.world_depth;
+        progress.set_total(world_size * world_size * world_size);
+
+        let mut world = World::new(path.to_str().unwrap().to_string());
+        world.mip_filter = gen_settings.mip_filter;
+        let opaque_blocks = world.opaque_blocks();
+        let mut generator = WorldGenerator {
+            procedual,
+            gen_settings,
+            gpu,
+            world,
+            root: CpuOctree::new(0),
+            finished: manifest.chunks.iter().map(|chunk| chunk.id).collect(),
+            empty: manifest.empty_chunks.iter().copied().collect(),
+            manifest,
+            opaque_blocks,
+            progress,
+        };
+        generator.generate_node(Vector3::zero(), 0)?;
 
-        let world_size = 1 << world_depth;
-        let voxel_size = 2.0 / world_size as f32;
-        let total_iterations = world_size * world_size * world_size;
-
-        use indicatif::{ProgressBar, ProgressStyle};
-        let pb = ProgressBar::new(total_iterations);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{wide_bar:.green/blue}] {pos}/{len} chunks generated ({eta_precise})")
-                .progress_chars("=> "),
-        );
-        pb.set_position(0);
-
-        let mut tmp_world = World::new(path.to_str().unwrap().to_string());
-
-        let mut i = 0;
-        // let (x, y, z) = (2, 0, 1);
-        for x in 0..world_size {
-            for y in 0..world_size {
-                for z in 0..world_size {
-                    let pos = (Vector3::new(x as f32, y as f32, z as f32)) * voxel_size
-                        - Vector3::new(1.0, 1.0, 1.0);
-
-                    let index = CHUNK_OFFSET / 2 + i as u32;
-                    let chunk = procedual.generate_chunk(gpu, pos, world_depth);
-                    if let Some(chunk) = chunk {
-                        println!(
-                            "({}, {}, {}): {} million",
-                            x,
-                            y,
-                            z,
-                            chunk.nodes.len() as f32 / 1000000.0
-                        );
-
-                        tmp_world.chunks.insert(index, chunk);
-                        tmp_world.generate_mip_tree(index);
-                        tmp_world.save_chunk(index);
-                        tmp_world.chunks.get_mut(&index).unwrap().nodes = Vec::new(); // To free the ram while keeping the top_mip
-                        root.put_in_block(pos, index, world_depth);
-                    }
+        let WorldGenerator {
+            mut world,
+            root,
+            mut manifest,
+            ..
+        } = generator;
 
-                    i += 1;
-                    pb.set_position(i);
-                }
-            }
+        manifest.root_hash = Some(root.content_hash());
+        manifest.save(path)?;
+
+        world.chunks.insert(0, root);
+        world.generate_mip_tree(0)?;
+        world.save_chunk(0);
+
+        Ok(())
+    }
+
+    /// Regenerates `samples` chunks spread through the world and checks them against the
+    /// manifest and the chunk files. Returns (chunk id, matches) for each sample.
+    pub fn verify_world<S: AsRef<std::ffi::OsStr> + Sized>(
+        path: S,
+        procedual: &mut Procedural,
+        gpu: &Gpu,
+        samples: usize,
+    ) -> Result<Vec<(u32, bool)>, String> {
+        World::verify_chunks(path, samples, &mut |gen_settings, pos| {
+            procedual
+                .generate_chunk(
+                    gpu,
+                    gen_settings,
+                    pos,
+                    gen_settings.world_depth,
+                    &GenProgress::default(),
+                )
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    /// `verify_world` with the chunks regenerated by `generate`
+    fn verify_chunks<S: AsRef<std::ffi::OsStr> + Sized>(
+        path: S,
+        samples: usize,
+        generate: &mut RegenerateChunk,
+    ) -> Result<Vec<(u32, bool)>, String> {
+        let path = std::path::Path::new(&path);
+        let manifest = Manifest::load(path)?;
+        if manifest.imported {
+            return Err("World was imported, its chunks can't be regenerated".to_string());
         }
 
-        println!();
+        let root = std::fs::read(path.join("0.bin")).map_err(|e| e.to_string())?;
+        let root = unsafe { CpuOctree::from_bin(root) };
+        if manifest.root_hash.is_none() {
+            return Err("World hasn't finished generating".to_string());
+        } else if Some(root.content_hash()) != manifest.root_hash {
+            return Err("Root chunk doesn't match the manifest".to_string());
+        }
 
-        tmp_world.chunks.insert(0, root);
-        tmp_world.generate_mip_tree(0);
-        tmp_world.save_chunk(0);
+        let world_depth = manifest.gen_settings.world_depth;
+        let voxel_size = 2.0 / (1u64 << world_depth) as f32;
 
-        Ok(())
+        let opaque_blocks = World::new(String::new()).opaque_blocks();
+        let samples = samples.min(manifest.chunks.len());
+        if samples == 0 {
+            return Err("There are no chunks to verify".to_string());
+        }
+        let mut results = Vec::new();
+        for i in 0..samples {
+            let entry = &manifest.chunks[i * manifest.chunks.len() / samples];
+
+            let file = std::fs::read(path.join(entry.id.to_string() + ".bin"))
+                .map_err(|e| format!("Chunk {}: {}", entry.id, e))?;
+            let saved = unsafe { CpuOctree::from_bin(file) };
+
+            let pos = entry.pos.cast::<f32>().unwrap() * voxel_size - Vector3::new(1.0, 1.0, 1.0);
+            let hash = generate(&manifest.gen_settings, pos)
+                .map_err(|e| format!("Chunk {}: {}", entry.id, e))?
+                .map(|mut chunk| {
+                    finish_chunk(&mut chunk, &manifest.gen_settings, &opaque_blocks);
+                    chunk.content_hash()
+                });
+
+            let matches = hash == Some(entry.hash) && saved.content_hash() == entry.hash;
+            results.push((entry.id, matches));
+        }
+
+        Ok(results)
     }
 
     // pub fn save_world<S: AsRef<std::ffi::OsStr> + Sized>(&mut self, path: S) -> Result<(), String> {
@@ -156,20 +260,73 @@ impl World {
     //     Ok(())
     // }
 
+    /// Ids of the loaded blocks that have no gaps on their faces
+    pub fn opaque_blocks(&self) -> std::collections::HashSet<u32> {
+        self.chunks
+            .iter()
+            .filter(|chunk| *chunk.key() != 0 && *chunk.key() < CHUNK_OFFSET / 2)
+            .filter(|chunk| chunk.faces_opaque())
+            .map(|chunk| *chunk.key())
+            .collect()
+    }
+
     pub fn load_world<S: AsRef<std::ffi::OsStr> + Sized>(path: S) -> Result<Self, String> {
         let path = std::path::Path::new(&path);
-        let world = World::new(path.to_str().unwrap().to_string());
+        let mut world = World::new(path.to_str().unwrap().to_string());
         if !path.exists() {
             return Err("File doesn't exist!".to_string());
         }
 
-        let file = std::fs::read(path.join("0.bin")).unwrap();
+        // Worlds from before manifests were added use the defaults
+        if let Ok(manifest) = Manifest::load(path) {
+            world.mip_filter = manifest.gen_settings.mip_filter;
+        }
+
+        let file = std::fs::read(path.join("0.bin")).map_err(|e| e.to_string())?;
         let root = unsafe { CpuOctree::from_bin(file) };
         world.chunks.insert(0, root);
 
         Ok(world)
     }
 
+    /// Opens a world folder, or a single file as the root chunk of a world with no folder
+    pub fn open(path: &std::path::Path, octree_depth: u32) -> Result<Self, String> {
+        if path.is_dir() {
+            return World::load_world(path);
+        }
+
+        // Chunk files can reference the chunks next to them
+        let folder = match path.extension().and_then(std::ffi::OsStr::to_str) {
+            Some("bin") => path
+                .parent()
+                .map(|parent| parent.to_string_lossy().to_string()),
+            _ => None,
+        };
+        let mut world = World::new(folder.unwrap_or_default());
+        let chunk = CpuOctree::load_file(path.to_string_lossy().to_string(), octree_depth)?;
+        world.chunks.insert(0, chunk);
+        world.generate_mip_tree(0)?;
+        Ok(world)
+    }
+
+    /// Ids and paths of the chunk files in the world folder
+    pub fn chunk_files(&self) -> Result<HashMap<u32, PathBuf>, String> {
+        let mut files = HashMap::new();
+        for entry in std::fs::read_dir(&self.path).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".bin"))
+                .and_then(|id| id.parse::<u32>().ok());
+            if let Some(id) = id {
+                files.insert(id, path);
+            }
+        }
+
+        Ok(files)
+    }
+
     pub fn save_chunk(&self, index: u32) {
         let path = self.path.clone() + "/" + &index.to_string() + ".bin";
         let mut file = std::fs::File::create(path).unwrap();
@@ -203,135 +360,456 @@ impl World {
         pos: Vector3<f32>,
         max_depth: Option<u32>,
     ) -> (u32, usize, u32, Vector3<f32>) {
-        let mut node_index = 0;
-        let mut chunk = 0;
-        let mut node_pos = Vector3::zero();
-        let mut depth = 0;
-        loop {
-            depth += 1;
-
-            let p = Vector3::new(
-                (pos.x >= node_pos.x) as usize,
-                (pos.y >= node_pos.y) as usize,
-                (pos.z >= node_pos.z) as usize,
-            );
-            let child_index = p.x * 4 + p.y * 2 + p.z;
-
-            node_pos += Octree::pos_offset(child_index, depth);
-
-            let tnipt = self.chunks.get(&chunk).unwrap().nodes[node_index + child_index].pointer;
-            if tnipt == CHUNK_OFFSET || depth == max_depth.unwrap_or(u32::MAX) {
-                return (chunk, node_index + child_index, depth, node_pos);
-            } else if tnipt > CHUNK_OFFSET {
-                chunk = tnipt - CHUNK_OFFSET;
-                node_index = 0;
-            } else {
-                node_index = tnipt as usize;
+        let leaf = descend(self, pos, max_depth, true, &mut |_| {}).unwrap();
+        (leaf.chunk, leaf.index, leaf.depth, leaf.centre())
+    }
+
+    /// Fills in the mips of a loaded chunk. Chunks it references that aren't loaded are read
+    /// from the world folder for their top mip.
+    pub fn generate_mip_tree(&mut self, id: u32) -> Result<(), String> {
+        let (_, mut chunk) = self
+            .chunks
+            .remove(&id)
+            .ok_or_else(|| format!("Chunk {} isn't loaded", id))?;
+
+        // Look up everything the chunk references once instead of for every node
+        let mut ref_mips = std::collections::HashMap::new();
+        let mut missing = None;
+        for node in &chunk.nodes {
+            if node.pointer > CHUNK_OFFSET {
+                let index = node.pointer - CHUNK_OFFSET;
+                if ref_mips.contains_key(&index) {
+                    continue;
+                }
+                match self.top_mip(index, id) {
+                    Ok(mip) => ref_mips.insert(index, mip),
+                    Err(e) => {
+                        missing = Some(e);
+                        break;
+                    }
+                };
             }
         }
+
+        if missing.is_none() {
+            chunk.generate_mips(&|index| ref_mips[&index], self.mip_filter);
+        }
+        self.chunks.insert(id, chunk);
+        missing.map_or(Ok(()), Err)
+    }
+
+    /// Switches filter and rebuilds the mips of every loaded chunk with it, the chunks they
+    /// reference first
+    pub fn set_mip_filter(&mut self, filter: MipFilter) -> Result<(), String> {
+        self.mip_filter = filter;
+
+        // Chunks only kept for their top mip can't be rebuilt
+        let mut ids: Vec<u32> = self
+            .chunks
+            .iter()
+            .filter(|chunk| !chunk.nodes.is_empty())
+            .map(|chunk| *chunk.key())
+            .collect();
+        ids.sort_unstable_by_key(|id| (*id == 0, *id));
+        for id in ids {
+            self.generate_mip_tree(id)?;
+        }
+        Ok(())
     }
 
-    pub fn generate_mip_tree(&mut self, id: u32) {
-        // let chunk = self
-        //     .chunks
-        //     .get_mut(&chunk_id)
-        //     .expect("Tried to generate mip tree for chunk that doesn't exist");
+    /// Top mip of a block or chunk referenced from the chunk `from`
+    fn top_mip(&self, id: u32, from: u32) -> Result<Voxel, String> {
+        if id == from {
+            return Err(format!("Chunk {} references itself", id));
+        } else if let Some(chunk) = self.chunks.get(&id) {
+            return Ok(chunk.top_mip);
+        }
+
+        let path = std::path::Path::new(&self.path).join(format!("{}.bin", id));
+        if self.path.is_empty() || !path.exists() {
+            return Err(format!(
+                "Chunk {} references chunk {}, which isn't loaded or in the folder",
+                from, id
+            ));
+        }
 
-        let mut voxels_in_each_level: Vec<Vec<usize>> = Vec::new();
-        voxels_in_each_level.push(vec![0]);
+        // Saved chunks already have their mips
+        let chunk = CpuOctree::load_file(path.to_string_lossy().to_string(), 0)
+            .map_err(|e| format!("Chunk {}: {}", id, e))?;
+        Ok(chunk.mip(0, self.mip_filter))
+    }
+}
 
-        use std::collections::VecDeque;
-        let mut queue = VecDeque::new();
+/// Generates the chunk with its corner at the position, for checking the saved one
+type RegenerateChunk<'a> =
+    dyn FnMut(&GenSettings, Vector3<f32>) -> Result<Option<CpuOctree>, String> + 'a;
+
+/// Hollows a generated chunk if the settings ask for it. Generating and verifying both go
+/// through this so the hashes of hollow worlds match. Returns the node counts if it hollowed.
+fn finish_chunk(
+    chunk: &mut CpuOctree,
+    gen_settings: &GenSettings,
+    opaque_blocks: &std::collections::HashSet<u32>,
+) -> Option<(usize, usize)> {
+    gen_settings
+        .hollow
+        .then(|| chunk.hollow(&|id| opaque_blocks.contains(&id)))
+}
 
-        for child_index in 0..8 {
-            let node = self.chunks.get(&id).unwrap().nodes[child_index];
-            if node.pointer < CHUNK_OFFSET {
-                queue.push_back((child_index, 1));
-            } else if node.pointer > CHUNK_OFFSET {
-                let index = node.pointer - CHUNK_OFFSET;
-                self.chunks.get_mut(&id).unwrap().nodes[child_index].value =
-                    self.chunks.get(&index).unwrap().top_mip;
+struct WorldGenerator<'a> {
+    procedual: &'a mut Procedural,
+    gen_settings: &'a GenSettings,
+    gpu: &'a Gpu,
+    world: World,
+    root: CpuOctree,
+    manifest: Manifest,
+    /// Chunks already on disk from an earlier run
+    finished: std::collections::HashSet<u32>,
+    /// Chunks an earlier run found to be empty
+    empty: std::collections::HashSet<u32>,
+    /// Blocks that hide whatever is behind them
+    opaque_blocks: std::collections::HashSet<u32>,
+    progress: &'a GenProgress,
+}
+
+impl<'a> WorldGenerator<'a> {
+    /// Generates the children of the node at grid position `pos` and `depth`, skipping any the
+    /// sdf proves to be empty or full
+    fn generate_node(&mut self, pos: Vector3<u32>, depth: u32) -> Result<(), String> {
+        let world_depth = self.gen_settings.world_depth;
+        let node_size = 2.0 / (1u64 << depth) as f32;
+        let corner = pos.cast::<f32>().unwrap() * node_size - Vector3::new(1.0, 1.0, 1.0);
+
+        let classifications =
+            self.procedual
+                .classify(self.gpu, self.gen_settings, corner, depth + 1);
+
+        for (child_index, classification) in classifications.iter().enumerate() {
+            let child_pos = pos * 2
+                + Vector3::new(
+                    (child_index as u32 >> 2) & 1,
+                    (child_index as u32 >> 1) & 1,
+                    child_index as u32 & 1,
+                );
+            let child_corner =
+                child_pos.cast::<f32>().unwrap() * node_size / 2.0 - Vector3::new(1.0, 1.0, 1.0);
+            let chunks_inside = 1u64 << (3 * (world_depth - depth - 1));
+
+            match classification {
+                Classification::Empty => self.progress.inc(chunks_inside),
+                Classification::Full => {
+                    self.root
+                        .put_in_block(child_corner, self.gen_settings.deep_block, depth + 1);
+                    self.progress.inc(chunks_inside);
+                }
+                Classification::Mixed if depth + 1 < world_depth => {
+                    self.generate_node(child_pos, depth + 1)?;
+                }
+                Classification::Mixed => {
+                    self.generate_chunk(child_pos, child_corner)?;
+                    self.progress.inc(1);
+                }
             }
         }
 
-        println!("Staring to mip...");
+        Ok(())
+    }
 
-        let mut i = 0;
-        while let Some((node_index, depth)) = queue.pop_front() {
-            loop {
-                if i % 1000000 == 0 {
-                    println!("Cataloged {} million nodes", i / 1000000);
-                }
-                i += 1;
-
-                if let Some(level) = voxels_in_each_level.get_mut(depth as usize) {
-                    level.push(node_index);
-
-                    let node = self.chunks.get(&id).unwrap().nodes[node_index as usize];
-                    for child_index in 0..8 {
-                        let child_node = self.chunks.get(&id).unwrap().nodes
-                            [node.pointer as usize + child_index];
-                        if child_node.pointer < CHUNK_OFFSET {
-                            queue.push_back((node.pointer as usize + child_index, depth + 1));
-                        } else if child_node.pointer > CHUNK_OFFSET {
-                            let index = child_node.pointer - CHUNK_OFFSET;
-                            let top_mip = self.chunks.get(&index).unwrap().top_mip;
-                            self.chunks.get_mut(&id).unwrap().nodes
-                                [node.pointer as usize + child_index]
-                                .value = top_mip;
-                        }
-                    }
+    fn generate_chunk(&mut self, pos: Vector3<u32>, corner: Vector3<f32>) -> Result<(), String> {
+        let world_depth = self.gen_settings.world_depth;
+        let world_size = 1 << world_depth;
 
-                    break;
-                } else {
-                    voxels_in_each_level.push(Vec::new());
-                }
+        let index = CHUNK_OFFSET / 2 + (pos.x * world_size + pos.y) * world_size + pos.z;
+        if self.progress.is_cancelled() {
+            return Err("Generation cancelled".to_string());
+        }
+
+        if self.empty.contains(&index) {
+            return Ok(());
+        }
+
+        if self.finished.contains(&index) {
+            let file = std::fs::read(format!("{}/{}.bin", self.world.path, index))
+                .map_err(|e| format!("Chunk {}: {}", index, e))?;
+            let chunk = unsafe { CpuOctree::from_bin(file) };
+
+            // Only the top mip is needed to build the root
+            self.world.chunks.insert(
+                index,
+                CpuOctree {
+                    nodes: Vec::new(),
+                    top_mip: chunk.mip(0, self.world.mip_filter),
+                },
+            );
+            self.root.put_in_block(corner, index, world_depth);
+            return Ok(());
+        }
+
+        let chunk = self
+            .procedual
+            .generate_chunk(
+                self.gpu,
+                self.gen_settings,
+                corner,
+                world_depth,
+                self.progress,
+            )
+            .map_err(|e| format!("Chunk ({}, {}, {}): {}", pos.x, pos.y, pos.z, e))?;
+        let path = PathBuf::from(&self.world.path);
+        if let Some(mut chunk) = chunk {
+            if let Some((before, after)) =
+                finish_chunk(&mut chunk, self.gen_settings, &self.opaque_blocks)
+            {
+                println!("Hollowed chunk from {} to {} nodes", before, after);
             }
+
+            println!(
+                "({}, {}, {}): {} million",
+                pos.x,
+                pos.y,
+                pos.z,
+                chunk.nodes.len() as f32 / 1000000.0
+            );
+
+            let hash = chunk.content_hash();
+            self.world.chunks.insert(index, chunk);
+            self.world.generate_mip_tree(index)?;
+            self.world.save_chunk(index);
+
+            self.manifest.add_chunk(
+                &path,
+                ChunkEntry {
+                    id: index,
+                    pos,
+                    hash,
+                },
+            )?;
+            self.world.chunks.get_mut(&index).unwrap().nodes = Vec::new(); // To free the ram while keeping the top_mip
+            self.root.put_in_block(corner, index, world_depth);
+        } else {
+            self.manifest.add_empty_chunk(&path, index)?;
         }
 
-        // for i in 0..voxels_in_each_level.len() {
-        //     println!("Level {}: ({})", i, voxels_in_each_level[i].len());
-        //     for j in 0..voxels_in_each_level[i].len() {
-        //         println!("  {}", voxels_in_each_level[i][j]);
-        //     }
-        // }
-
-        for i in (0..voxels_in_each_level.len()).rev() {
-            for node_index in &voxels_in_each_level[i] {
-                // Average the colours of the 8 children
-                let node = if i != 0 {
-                    self.chunks.get(&id).unwrap().nodes[*node_index as usize]
-                } else {
-                    Node::new(0, Voxel::new(0, 0, 0))
-                };
-                let mut colour = Vector3::new(0.0, 0.0, 0.0);
-                let mut divisor = 0.0;
-
-                for i in 0..8 {
-                    let child = self.chunks.get(&id).unwrap().nodes[node.pointer as usize + i];
-                    if child.value != Voxel::new(0, 0, 0) {
-                        let voxel = child.value;
-                        colour += Vector3::new(voxel.r as f32, voxel.g as f32, voxel.b as f32);
-                        divisor += 1.0;
-                    }
-                }
+        Ok(())
+    }
+}
+
+/// A world with the chunk files in its folder read as a walk reaches them, so only the last
+/// few are in memory at once. Chunks the world has loaded are used as they are.
+pub struct ChunkFiles<'a> {
+    pub world: &'a World,
+    files: HashMap<u32, PathBuf>,
+    /// Most recently used last
+    cache: RefCell<Vec<(u32, Arc<CpuOctree>)>>,
+    error: RefCell<Option<String>>,
+}
 
-                colour /= divisor;
+impl<'a> ChunkFiles<'a> {
+    pub fn new(world: &'a World) -> Result<Self, String> {
+        Ok(Self {
+            world,
+            files: world.chunk_files()?,
+            cache: RefCell::new(Vec::new()),
+            error: RefCell::new(None),
+        })
+    }
 
-                let voxel = Voxel::new(
-                    (colour.x as u8).max(1),
-                    (colour.y as u8).max(1),
-                    (colour.z as u8).max(1),
-                );
+    /// Reads the chunk file unless it's one of the last few used
+    pub fn chunk(&self, id: u32) -> Option<Arc<CpuOctree>> {
+        let mut cache = self.cache.borrow_mut();
+        if let Some(i) = cache.iter().position(|(cached, _)| *cached == id) {
+            let entry = cache.remove(i);
+            cache.push(entry);
+            return cache.last().map(|(_, chunk)| chunk.clone());
+        }
 
-                if i != 0 {
-                    self.chunks.get_mut(&id).unwrap().nodes[*node_index as usize].value = voxel;
-                } else {
-                    self.chunks.get_mut(&id).unwrap().top_mip = voxel;
+        let path = self.files.get(&id)?;
+        let chunk = match CpuOctree::load_file(path.to_string_lossy().to_string(), 0) {
+            Ok(chunk) => Arc::new(chunk),
+            Err(e) => {
+                self.error
+                    .borrow_mut()
+                    .get_or_insert(format!("Chunk {}: {}", id, e));
+                return None;
+            }
+        };
+        if cache.len() == CACHED_CHUNKS {
+            cache.remove(0);
+        }
+        cache.push((id, chunk.clone()));
+        Some(chunk)
+    }
+
+    pub fn has_file(&self, id: u32) -> bool {
+        self.files.contains_key(&id)
+    }
+
+    /// The first chunk that couldn't be read, walks treat them as missing
+    pub fn error(&self) -> Result<(), String> {
+        match self.error.borrow().clone() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cube of stone with one corner missing so some of it can be seen
+    fn stone_chunk() -> CpuOctree {
+        let depth = 3;
+        let mut leaves = Vec::new();
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    if (x, y, z) != (0, 0, 0) {
+                        let node = Node::new(CHUNK_OFFSET + BLOCK_STONE, Voxel::new(0, 0, 0));
+                        leaves.push((morton(Vector3::new(x, y, z), depth), node));
+                    }
                 }
             }
         }
+        leaves.sort_unstable_by_key(|(code, _)| *code);
+        CpuOctree::from_sorted_leaves(&leaves, depth)
+    }
+
+    #[test]
+    fn hollow_chunks_verify() {
+        let path = std::env::temp_dir().join(format!("hollow_verify_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+
+        let gen_settings = GenSettings {
+            hollow: true,
+            ..Default::default()
+        };
+        let opaque_blocks = World::new(String::new()).opaque_blocks();
+        assert!(opaque_blocks.contains(&BLOCK_STONE));
+
+        // Saved the way generate_chunk saves it
+        let mut chunk = stone_chunk();
+        assert!(finish_chunk(&mut chunk, &gen_settings, &opaque_blocks).is_some());
+        assert_ne!(chunk.content_hash(), stone_chunk().content_hash());
+        let id = CHUNK_OFFSET / 2;
+        std::fs::write(path.join(format!("{}.bin", id)), unsafe { chunk.bin() }).unwrap();
 
-        println!("Mip success!");
+        let mut root = CpuOctree::new(0);
+        root.put_in_block(Vector3::new(-1.0, -1.0, -1.0), id, 1);
+        std::fs::write(path.join("0.bin"), unsafe { root.bin() }).unwrap();
+
+        let mut manifest = Manifest::new(gen_settings);
+        manifest.root_hash = Some(root.content_hash());
+        manifest.chunks.push(ChunkEntry {
+            id,
+            pos: Vector3::zero(),
+            hash: chunk.content_hash(),
+        });
+        manifest.save(&path).unwrap();
+
+        let results = World::verify_chunks(&path, 4, &mut |_, _| Ok(Some(stone_chunk())));
+        assert_eq!(results, Ok(vec![(id, true)]));
+
+        // Nothing to check passes nothing
+        let results = World::verify_chunks(&path, 0, &mut |_, _| Ok(Some(stone_chunk())));
+        assert_eq!(results.unwrap_err(), "There are no chunks to verify");
+
+        // Imported worlds like heightmaps can't be regenerated
+        manifest.imported = true;
+        manifest.save(&path).unwrap();
+        let results = World::verify_chunks(&path, 4, &mut |_, _| Ok(Some(stone_chunk())));
+        std::fs::remove_dir_all(&path).unwrap();
+        assert!(results.unwrap_err().contains("imported"));
+    }
+
+    #[test]
+    fn missing_refs_are_errors() {
+        let mut world = World::new(String::new());
+        let mut chunk = CpuOctree::new(0);
+        chunk.put_in_block(Vector3::zero(), CHUNK_OFFSET / 2 + 5, 1);
+        world.chunks.insert(0, chunk);
+        assert!(world.generate_mip_tree(0).is_err());
+        // The chunk is still there
+        assert!(world.chunks.contains_key(&0));
+
+        let mut chunk = CpuOctree::new(0);
+        chunk.put_in_block(Vector3::zero(), BLOCK_STONE, 1);
+        world.chunks.insert(0, chunk);
+        world.generate_mip_tree(0).unwrap();
+        assert_ne!(world.chunks.get(&0).unwrap().top_mip, Voxel::new(0, 0, 0));
+    }
+
+    #[test]
+    fn set_mip_filter_rebuilds_mips() {
+        let chunk = || {
+            let mut chunk = CpuOctree::new(0);
+            chunk.put_in_block(Vector3::new(-0.9, -0.9, -0.9), BLOCK_GRASS, 3);
+            chunk.put_in_block(Vector3::new(-0.6, -0.9, -0.9), BLOCK_STONE, 3);
+            chunk.put_in_block(Vector3::new(-0.9, -0.6, -0.9), BLOCK_STONE, 3);
+            chunk
+        };
+        let mut world = World::new(String::new());
+        world.chunks.insert(0, chunk());
+        world.generate_mip_tree(0).unwrap();
+        let mean = unsafe { world.chunks.get(&0).unwrap().bin().to_vec() };
+
+        let mut fresh = World::new(String::new());
+        fresh.mip_filter = MipFilter::Majority;
+        // The blocks are referenced so their mips change too
+        for id in 1..=8 {
+            fresh.generate_mip_tree(id).unwrap();
+        }
+        fresh.chunks.insert(0, chunk());
+        fresh.generate_mip_tree(0).unwrap();
+        let majority = unsafe { fresh.chunks.get(&0).unwrap().bin().to_vec() };
+        assert_ne!(mean, majority);
+
+        world.set_mip_filter(MipFilter::Majority).unwrap();
+        assert_eq!(world.mip_filter, MipFilter::Majority);
+        let rebuilt = unsafe { world.chunks.get(&0).unwrap().bin().to_vec() };
+        assert_eq!(rebuilt, majority);
+    }
+
+    #[test]
+    fn chunk_files_match_loaded_chunks() {
+        let path = std::env::temp_dir().join(format!("chunk_files_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+
+        let id = CHUNK_OFFSET / 2;
+        let mut root = CpuOctree::new(0);
+        root.put_in_block(Vector3::new(-1.0, -1.0, -1.0), id, 1);
+        root.put_in_block(Vector3::new(0.5, 0.5, 0.5), id, 1);
+        std::fs::write(path.join("0.bin"), unsafe { root.bin() }).unwrap();
+        std::fs::write(path.join(format!("{}.bin", id)), unsafe {
+            stone_chunk().bin()
+        })
+        .unwrap();
+
+        let world = World::load_world(&path).unwrap();
+        // The blocks are slow to get the stats of and aren't needed
+        world.chunks.retain(|id, _| *id == 0);
+        let settings = WalkSettings {
+            follow_refs: true,
+            ..Default::default()
+        };
+        let files = ChunkFiles::new(&world).unwrap();
+        let streamed: Vec<_> = files
+            .walk(settings)
+            .map(|visit| (visit.chunk, visit.pos))
+            .collect();
+        files.error().unwrap();
+        let stats = world.folder_stats().unwrap();
+
+        world.chunks.insert(id, stone_chunk());
+        let loaded: Vec<_> = world
+            .walk(settings)
+            .map(|visit| (visit.chunk, visit.pos))
+            .collect();
+        std::fs::remove_dir_all(&path).unwrap();
+        assert_eq!(streamed, loaded);
+        assert!(streamed.iter().any(|(chunk, _)| *chunk == id));
+        assert_eq!(format!("{}", stats), format!("{}", world.stats()));
     }
 }